@@ -1,10 +1,16 @@
-#![warn(missing_docs)]
+//! A preprocessor for WGSL whose directives live inside `//` comments, so that unprocessed
+//! sources stay valid WGSL.
 
+#![warn(missing_docs)]
 #![cfg_attr(not(feature = "std"), no_std)]
 
 #[cfg(feature = "alloc")]
 extern crate alloc;
 
+#[cfg(feature = "alloc")]
 pub mod macros;
 pub mod parsing;
+#[cfg(feature = "alloc")]
+pub mod preprocessor;
 pub mod utils;
+pub mod wgsl;
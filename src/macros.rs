@@ -1,18 +1,1117 @@
-// pub struct Anchor {
-//     pub range: Range<u64>,
-// }
-
-// pub struct Include<'a> {
-//     pub anchor: &'a str,
-//     pub source: &'a str,
-// }
-
-// pub struct IncludeBytes<'a> {
-//     pub source_range: Range<u64>,
-//     pub source: &'a str,
-// }
-
-// pub struct IncludeStr<'a> {
-//     pub source: &'a str,
-//     pub source_range: TextRange,
-// }
+//! Directives understood by the preprocessor.
+//!
+//! [`DirectiveIter`] groups the tokens from a [`TotalMacroTokenIter`] into directives and parses
+//! each group into a typed [`Directive`].
+
+use crate::parsing::{
+    validate_wgsl_identifier, IdentifierError, MacroTokenError, MacroTokenErrorKind,
+    MacroTokenResult, StringLiteral, SyntaxSettings, TotalMacroTokenIter,
+};
+use crate::utils::{LocatedStr, TextRange};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Display;
+use enum_dispatch::enum_dispatch;
+#[cfg(feature = "std")]
+use std::error::Error;
+
+/// Behaviour shared by every kind of directive.
+#[enum_dispatch]
+pub trait DirectiveHandler {
+    /// The directive's name, as written after the start ident.
+    fn name(&self) -> &str;
+
+    /// The directive's full span, from the start ident to the end ident.
+    fn range(&self) -> TextRange;
+}
+
+/// A parsed directive.
+#[enum_dispatch(DirectiveHandler)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Directive<'a> {
+    /// `#include "path";` or `#include "path" anchor;`
+    Include(Include<'a>),
+    /// `#import module::path;`
+    Import(Import<'a>),
+    /// `#anchor name;`
+    Anchor(Anchor<'a>),
+    /// `#endanchor;`
+    EndAnchor(EndAnchor),
+    /// `#define NAME body;` or `#define NAME(params) body;`
+    Define(Define<'a>),
+    /// `#undef NAME;`
+    Undef(Undef<'a>),
+    /// `#if condition;`
+    If(If<'a>),
+    /// `#ifdef NAME;`
+    Ifdef(Ifdef<'a>),
+    /// `#ifndef NAME;`
+    Ifndef(Ifndef<'a>),
+    /// `#elif condition;`
+    Elif(Elif<'a>),
+    /// `#else;`
+    Else(Else),
+    /// `#endif;`
+    Endif(Endif),
+    /// `#error "message";`
+    Error(ErrorDirective<'a>),
+    /// `#warning "message";`
+    Warning(WarningDirective<'a>),
+    /// `#pragma tokens;`
+    Pragma(Pragma<'a>),
+    /// Any directive whose name is not built in.
+    Custom(CustomDirective<'a>),
+}
+
+// The names of the directives that are not parsed as [`Directive::Custom`].
+pub(crate) const BUILTIN_DIRECTIVES: &[&str] = &[
+    "include",
+    "import",
+    "anchor",
+    "endanchor",
+    "define",
+    "undef",
+    "if",
+    "ifdef",
+    "ifndef",
+    "elif",
+    "else",
+    "endif",
+    "error",
+    "warning",
+    "pragma",
+];
+
+impl<'a> Directive<'a> {
+    /// Parses the tokens of one directive, as yielded by [`TotalMacroTokenIter`]: the start ident,
+    /// the directive's name and arguments, then the terminator.
+    pub fn parse(tokens: &[MacroTokenResult<'a>]) -> Result<Directive<'a>, DirectiveParseError> {
+        let mut cursor = DirectiveTokens::new(tokens)?;
+        let name = cursor.expect_ident(DirectiveParseErrorKind::MissingName)?;
+        let range = cursor.range;
+
+        let directive = match name.inner_str {
+            "include" => {
+                let path = cursor.expect_string()?;
+                let anchor = cursor.maybe_ident();
+                cursor.expect_end()?;
+                Directive::Include(Include {
+                    path,
+                    anchor,
+                    range,
+                })
+            }
+            "import" => Directive::Import(parse_import(&mut cursor)?),
+            "anchor" => {
+                let name = cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?;
+                cursor.expect_end()?;
+                Directive::Anchor(Anchor { name, range })
+            }
+            "endanchor" => {
+                cursor.expect_end()?;
+                Directive::EndAnchor(EndAnchor { range })
+            }
+            "define" => Directive::Define(parse_define(&mut cursor)?),
+            "undef" => {
+                let name = cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?;
+                cursor.expect_end()?;
+                Directive::Undef(Undef { name, range })
+            }
+            "if" => Directive::If(If {
+                condition: cursor.expect_rest()?,
+                range,
+            }),
+            "ifdef" => {
+                let name = cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?;
+                cursor.expect_end()?;
+                Directive::Ifdef(Ifdef { name, range })
+            }
+            "ifndef" => {
+                let name = cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?;
+                cursor.expect_end()?;
+                Directive::Ifndef(Ifndef { name, range })
+            }
+            "elif" => Directive::Elif(Elif {
+                condition: cursor.expect_rest()?,
+                range,
+            }),
+            "else" => {
+                cursor.expect_end()?;
+                Directive::Else(Else { range })
+            }
+            "endif" => {
+                cursor.expect_end()?;
+                Directive::Endif(Endif { range })
+            }
+            "error" => {
+                let message = cursor.expect_string()?;
+                cursor.expect_end()?;
+                Directive::Error(ErrorDirective { message, range })
+            }
+            "warning" => {
+                let message = cursor.expect_string()?;
+                cursor.expect_end()?;
+                Directive::Warning(WarningDirective { message, range })
+            }
+            "pragma" => Directive::Pragma(Pragma {
+                tokens: cursor.rest(),
+                range,
+            }),
+            _ => Directive::Custom(CustomDirective {
+                name,
+                tokens: cursor.rest(),
+                range,
+            }),
+        };
+
+        Ok(directive)
+    }
+}
+
+fn parse_define<'a>(
+    cursor: &mut DirectiveTokens<'_, 'a>,
+) -> Result<Define<'a>, DirectiveParseError> {
+    let name = cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?;
+    validate_wgsl_identifier(name.inner_str).map_err(|error| DirectiveParseError {
+        kind: DirectiveParseErrorKind::InvalidMacroName(error),
+        range: name.range(),
+    })?;
+
+    // Like in C, a macro is function-like only if the parenthesis directly follows the name.
+    let mut params = None;
+    let mut variadic = false;
+    if let Some(MacroTokenResult::SymbolToken(open)) = cursor.peek() {
+        if open.inner_str == "(" && open.start_location.byte_num == name.end_location().byte_num {
+            cursor.pos += 1;
+            let (named_params, is_variadic) = parse_params(cursor, open.range())?;
+            params = Some(named_params);
+            variadic = is_variadic;
+        }
+    }
+
+    Ok(Define {
+        name,
+        params,
+        variadic,
+        body: cursor.rest(),
+        range: cursor.range,
+    })
+}
+
+fn parse_import<'a>(
+    cursor: &mut DirectiveTokens<'_, 'a>,
+) -> Result<Import<'a>, DirectiveParseError> {
+    let mut path = alloc::vec![cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?];
+    let mut items = None;
+    while cursor.maybe_symbol("::").is_some() {
+        if cursor.maybe_symbol("{").is_some() {
+            items = Some(parse_import_items(cursor)?);
+            break;
+        }
+        path.push(cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?);
+    }
+    cursor.expect_end()?;
+    Ok(Import {
+        path,
+        items,
+        range: cursor.range,
+    })
+}
+
+// Parses the rest of `{a, b}` after the opening brace. A trailing comma is allowed.
+fn parse_import_items<'a>(
+    cursor: &mut DirectiveTokens<'_, 'a>,
+) -> Result<Vec<LocatedStr<'a>>, DirectiveParseError> {
+    let mut items = alloc::vec![cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?];
+    while cursor.maybe_symbol("}").is_none() {
+        if cursor.maybe_symbol(",").is_none() {
+            return Err(DirectiveParseError {
+                kind: DirectiveParseErrorKind::UnexpectedToken,
+                range: cursor.current_range(),
+            });
+        }
+        if cursor.maybe_symbol("}").is_some() {
+            break;
+        }
+        items.push(cursor.expect_ident(DirectiveParseErrorKind::ExpectedIdentifier)?);
+    }
+    Ok(items)
+}
+
+// Parses the rest of a parameter list after the opening parenthesis. Returns the named parameters
+// and whether the list ends with `...`.
+fn parse_params<'a>(
+    cursor: &mut DirectiveTokens<'_, 'a>,
+    open_range: TextRange,
+) -> Result<(Vec<LocatedStr<'a>>, bool), DirectiveParseError> {
+    let mut params: Vec<LocatedStr<'a>> = Vec::new();
+    let unclosed = DirectiveParseError {
+        kind: DirectiveParseErrorKind::InvalidParameterList,
+        range: open_range,
+    };
+
+    if cursor.maybe_symbol(")").is_some() {
+        return Ok((params, false));
+    }
+    loop {
+        if let Some(ellipsis) = cursor.maybe_symbol("...") {
+            return match cursor.maybe_symbol(")") {
+                Some(_) => Ok((params, true)),
+                None => Err(DirectiveParseError {
+                    kind: DirectiveParseErrorKind::InvalidParameterList,
+                    range: cursor
+                        .peek()
+                        .map_or(ellipsis.range(), MacroTokenResult::range),
+                }),
+            };
+        }
+        let param = match cursor.peek() {
+            Some(MacroTokenResult::AlphanumStringToken(param)) => *param,
+            Some(token) => {
+                return Err(DirectiveParseError {
+                    kind: DirectiveParseErrorKind::InvalidParameterList,
+                    range: token.range(),
+                })
+            }
+            None => return Err(unclosed),
+        };
+        cursor.pos += 1;
+        if params.iter().any(|p| p.inner_str == param.inner_str) {
+            return Err(DirectiveParseError {
+                kind: DirectiveParseErrorKind::DuplicateParameter,
+                range: param.range(),
+            });
+        }
+        params.push(param);
+
+        if cursor.maybe_symbol(")").is_some() {
+            return Ok((params, false));
+        }
+        match cursor.maybe_symbol(",") {
+            Some(_) => {}
+            None => {
+                return Err(match cursor.peek() {
+                    Some(token) => DirectiveParseError {
+                        kind: DirectiveParseErrorKind::InvalidParameterList,
+                        range: token.range(),
+                    },
+                    None => unclosed,
+                })
+            }
+        }
+    }
+}
+
+// A cursor over the tokens between a directive's start ident and terminator.
+struct DirectiveTokens<'t, 'a> {
+    tokens: &'t [MacroTokenResult<'a>],
+    pos: usize,
+    range: TextRange,
+    terminator_range: TextRange,
+}
+
+impl<'t, 'a> DirectiveTokens<'t, 'a> {
+    fn new(tokens: &'t [MacroTokenResult<'a>]) -> Result<Self, DirectiveParseError> {
+        let (Some(first), Some(last)) = (tokens.first(), tokens.last()) else {
+            return Err(DirectiveParseError {
+                kind: DirectiveParseErrorKind::MissingName,
+                range: TextRange::default(),
+            });
+        };
+        let range = first.range().merge(last.range());
+        if let Some(error) = tokens.iter().find_map(|token| match token {
+            MacroTokenResult::Error(error) => Some(*error),
+            _ => None,
+        }) {
+            return Err(error.into());
+        }
+        if !matches!(last, MacroTokenResult::Terminator(_)) {
+            return Err(MacroTokenError {
+                kind: MacroTokenErrorKind::UnterminatedDirective,
+                range,
+            }
+            .into());
+        }
+
+        Ok(DirectiveTokens {
+            // Skip the start ident and the terminator.
+            tokens: &tokens[1..tokens.len() - 1],
+            pos: 0,
+            range,
+            terminator_range: last.range(),
+        })
+    }
+
+    fn peek(&self) -> Option<&'t MacroTokenResult<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    // The range used for errors about something missing at the current position.
+    fn current_range(&self) -> TextRange {
+        match self.peek() {
+            Some(token) => token.range(),
+            None => TextRange::empty_at(self.terminator_range.start),
+        }
+    }
+
+    fn expect_ident(
+        &mut self,
+        missing_kind: DirectiveParseErrorKind,
+    ) -> Result<LocatedStr<'a>, DirectiveParseError> {
+        match self.maybe_ident() {
+            Some(ident) => Ok(ident),
+            None => Err(DirectiveParseError {
+                kind: missing_kind,
+                range: self.current_range(),
+            }),
+        }
+    }
+
+    fn maybe_ident(&mut self) -> Option<LocatedStr<'a>> {
+        match self.peek() {
+            Some(MacroTokenResult::AlphanumStringToken(ident)) => {
+                self.pos += 1;
+                Some(*ident)
+            }
+            _ => None,
+        }
+    }
+
+    fn maybe_symbol(&mut self, symbol: &str) -> Option<LocatedStr<'a>> {
+        match self.peek() {
+            Some(MacroTokenResult::SymbolToken(s)) if s.inner_str == symbol => {
+                self.pos += 1;
+                Some(*s)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect_string(&mut self) -> Result<StringLiteral<'a>, DirectiveParseError> {
+        match self.peek() {
+            Some(MacroTokenResult::StringLiteralToken(literal)) => {
+                self.pos += 1;
+                Ok(*literal)
+            }
+            _ => Err(DirectiveParseError {
+                kind: DirectiveParseErrorKind::ExpectedStringLiteral,
+                range: self.current_range(),
+            }),
+        }
+    }
+
+    fn expect_end(&self) -> Result<(), DirectiveParseError> {
+        match self.peek() {
+            Some(token) => Err(DirectiveParseError {
+                kind: DirectiveParseErrorKind::UnexpectedToken,
+                range: token.range(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn expect_rest(&mut self) -> Result<Vec<MacroTokenResult<'a>>, DirectiveParseError> {
+        if self.peek().is_none() {
+            return Err(DirectiveParseError {
+                kind: DirectiveParseErrorKind::MissingCondition,
+                range: self.current_range(),
+            });
+        }
+        Ok(self.rest())
+    }
+
+    fn rest(&mut self) -> Vec<MacroTokenResult<'a>> {
+        let output = self.tokens[self.pos..].to_vec();
+        self.pos = self.tokens.len();
+        output
+    }
+}
+
+/// Iterates over the parsed directives of a whole WGSL source.
+#[derive(Clone, Debug)]
+pub struct DirectiveIter<'a, 'b> {
+    tokens: TotalMacroTokenIter<'a, 'b>,
+}
+
+impl<'a, 'b> DirectiveIter<'a, 'b> {
+    /// Creates an iterator over the directives in `source`.
+    pub fn new(source: LocatedStr<'a>, syntax_settings: SyntaxSettings<'b>) -> Self {
+        DirectiveIter {
+            tokens: TotalMacroTokenIter::new(source, syntax_settings),
+        }
+    }
+
+    /// Creates an iterator over the directives in `source` using the default syntax.
+    pub fn new_with_default_syntax(source: LocatedStr<'a>) -> DirectiveIter<'a, 'static> {
+        DirectiveIter::new(source, SyntaxSettings::default())
+    }
+
+    // Returns the tokens of the next directive, up to its terminator.
+    pub(crate) fn next_tokens(&mut self) -> Option<Vec<MacroTokenResult<'a>>> {
+        let mut group = Vec::new();
+        for token in &mut self.tokens {
+            let is_end = matches!(
+                token,
+                MacroTokenResult::Terminator(_)
+                    | MacroTokenResult::Error(MacroTokenError {
+                        kind: MacroTokenErrorKind::UnterminatedDirective
+                            | MacroTokenErrorKind::UnclosedBracket(_),
+                        ..
+                    })
+            );
+            group.push(token);
+            if is_end {
+                break;
+            }
+        }
+        (!group.is_empty()).then_some(group)
+    }
+}
+
+impl<'a, 'b> Iterator for DirectiveIter<'a, 'b> {
+    type Item = Result<Directive<'a>, DirectiveParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let group = self.next_tokens()?;
+        Some(Directive::parse(&group))
+    }
+}
+
+/// `#include "path";` or `#include "path" anchor;`
+///
+/// Includes another file, or only the part of it between `#anchor anchor;` and `#endanchor;`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Include<'a> {
+    /// The path of the included file.
+    pub path: StringLiteral<'a>,
+    /// The anchor to include instead of the whole file.
+    pub anchor: Option<LocatedStr<'a>>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for Include<'a> {
+    fn name(&self) -> &str {
+        "include"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#import module::path;` or `#import module::path::{item, ...};`
+///
+/// Imports a module, found from the preprocessor's module root by its path. With a list of items,
+/// only those items and the declarations they depend on are emitted.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Import<'a> {
+    /// The segments of the module's path, such as `lighting` and `pbr` in `lighting::pbr`.
+    pub path: Vec<LocatedStr<'a>>,
+    /// The names of the imported items, or `None` if the whole module is imported.
+    pub items: Option<Vec<LocatedStr<'a>>>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> Import<'a> {
+    /// Returns the module's path as written, with `::` between segments.
+    pub fn module_path(&self) -> String {
+        let segments: Vec<&str> = self.path.iter().map(|segment| segment.inner_str).collect();
+        segments.join("::")
+    }
+}
+
+impl<'a> DirectiveHandler for Import<'a> {
+    fn name(&self) -> &str {
+        "import"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#anchor name;`
+///
+/// Starts a named region that can be included on its own. The region ends at `#endanchor;`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Anchor<'a> {
+    /// The anchor's name.
+    pub name: LocatedStr<'a>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for Anchor<'a> {
+    fn name(&self) -> &str {
+        "anchor"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#endanchor;`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct EndAnchor {
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl DirectiveHandler for EndAnchor {
+    fn name(&self) -> &str {
+        "endanchor"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#define NAME body;` or `#define NAME(params) body;`
+///
+/// The parameter list may end with `...`, in which case the macro takes any number of further
+/// arguments as `__VA_ARGS__`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Define<'a> {
+    /// The macro's name. Always a valid WGSL identifier.
+    pub name: LocatedStr<'a>,
+    /// The named parameters, if the macro is function-like.
+    pub params: Option<Vec<LocatedStr<'a>>>,
+    /// Whether the parameter list ends with `...`.
+    pub variadic: bool,
+    /// The tokens the macro expands to.
+    pub body: Vec<MacroTokenResult<'a>>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for Define<'a> {
+    fn name(&self) -> &str {
+        "define"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#undef NAME;`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Undef<'a> {
+    /// The name of the macro to remove.
+    pub name: LocatedStr<'a>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for Undef<'a> {
+    fn name(&self) -> &str {
+        "undef"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#if condition;`
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct If<'a> {
+    /// The tokens of the condition. Never empty.
+    pub condition: Vec<MacroTokenResult<'a>>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for If<'a> {
+    fn name(&self) -> &str {
+        "if"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#ifdef NAME;`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Ifdef<'a> {
+    /// The name of the macro to check.
+    pub name: LocatedStr<'a>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for Ifdef<'a> {
+    fn name(&self) -> &str {
+        "ifdef"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#ifndef NAME;`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Ifndef<'a> {
+    /// The name of the macro to check.
+    pub name: LocatedStr<'a>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for Ifndef<'a> {
+    fn name(&self) -> &str {
+        "ifndef"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#elif condition;`
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Elif<'a> {
+    /// The tokens of the condition. Never empty.
+    pub condition: Vec<MacroTokenResult<'a>>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for Elif<'a> {
+    fn name(&self) -> &str {
+        "elif"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#else;`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Else {
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl DirectiveHandler for Else {
+    fn name(&self) -> &str {
+        "else"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#endif;`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Endif {
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl DirectiveHandler for Endif {
+    fn name(&self) -> &str {
+        "endif"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#error "message";`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ErrorDirective<'a> {
+    /// The message to report.
+    pub message: StringLiteral<'a>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for ErrorDirective<'a> {
+    fn name(&self) -> &str {
+        "error"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#warning "message";`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct WarningDirective<'a> {
+    /// The message to report.
+    pub message: StringLiteral<'a>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for WarningDirective<'a> {
+    fn name(&self) -> &str {
+        "warning"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// `#pragma tokens;`
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Pragma<'a> {
+    /// Everything after the directive's name.
+    pub tokens: Vec<MacroTokenResult<'a>>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for Pragma<'a> {
+    fn name(&self) -> &str {
+        "pragma"
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// A directive whose name is not built in.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CustomDirective<'a> {
+    /// The directive's name.
+    pub name: LocatedStr<'a>,
+    /// Everything after the directive's name.
+    pub tokens: Vec<MacroTokenResult<'a>>,
+    /// The directive's full span.
+    pub range: TextRange,
+}
+
+impl<'a> DirectiveHandler for CustomDirective<'a> {
+    fn name(&self) -> &str {
+        self.name.inner_str
+    }
+
+    fn range(&self) -> TextRange {
+        self.range
+    }
+}
+
+/// An error produced while parsing a directive.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DirectiveParseError {
+    /// What went wrong.
+    pub kind: DirectiveParseErrorKind,
+    /// The offending text.
+    pub range: TextRange,
+}
+
+impl From<MacroTokenError> for DirectiveParseError {
+    fn from(error: MacroTokenError) -> Self {
+        DirectiveParseError {
+            kind: DirectiveParseErrorKind::Token(error.kind),
+            range: error.range,
+        }
+    }
+}
+
+impl Display for DirectiveParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.kind,
+            self.range.start.line_num + 1,
+            self.range.start.col_num + 1
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for DirectiveParseError {}
+
+/// The kinds of [`DirectiveParseError`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DirectiveParseErrorKind {
+    /// The directive's tokens could not be tokenized.
+    Token(MacroTokenErrorKind),
+    /// There is no directive name after the start ident.
+    MissingName,
+    /// An identifier was expected.
+    ExpectedIdentifier,
+    /// A string literal was expected.
+    ExpectedStringLiteral,
+    /// `#if` or `#elif` has no condition.
+    MissingCondition,
+    /// The directive has more tokens than it takes.
+    UnexpectedToken,
+    /// A macro's name is not a valid WGSL identifier.
+    InvalidMacroName(IdentifierError),
+    /// A function-like macro's parameter list is malformed.
+    InvalidParameterList,
+    /// A function-like macro has two parameters with the same name.
+    DuplicateParameter,
+}
+
+impl Display for DirectiveParseErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DirectiveParseErrorKind::Token(kind) => write!(f, "{kind}"),
+            DirectiveParseErrorKind::MissingName => write!(f, "expected a directive name"),
+            DirectiveParseErrorKind::ExpectedIdentifier => write!(f, "expected an identifier"),
+            DirectiveParseErrorKind::ExpectedStringLiteral => {
+                write!(f, "expected a string literal")
+            }
+            DirectiveParseErrorKind::MissingCondition => write!(f, "expected a condition"),
+            DirectiveParseErrorKind::UnexpectedToken => write!(f, "unexpected token"),
+            DirectiveParseErrorKind::InvalidMacroName(error) => {
+                write!(f, "invalid macro name: {error}")
+            }
+            DirectiveParseErrorKind::InvalidParameterList => {
+                write!(f, "malformed macro parameter list")
+            }
+            DirectiveParseErrorKind::DuplicateParameter => {
+                write!(f, "duplicate macro parameter")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(source: &str) -> Vec<Result<Directive<'_>, DirectiveParseError>> {
+        DirectiveIter::new_with_default_syntax(LocatedStr::new(source)).collect()
+    }
+
+    fn parse_one(source: &str) -> Result<Directive<'_>, DirectiveParseError> {
+        let mut results = parse_all(source);
+        assert_eq!(results.len(), 1, "{source}");
+        results.pop().unwrap()
+    }
+
+    #[test]
+    fn test_include() {
+        let directive = parse_one(r#"// #include "lighting.wgsl" pbr;"#).unwrap();
+        let Directive::Include(include) = &directive else {
+            panic!("{directive:?}");
+        };
+        assert_eq!(include.path.value(), "lighting.wgsl");
+        assert_eq!(include.anchor.map(|a| a.inner_str), Some("pbr"));
+        assert_eq!(directive.name(), "include");
+        assert_eq!(directive.range().byte_range(), 3..32);
+    }
+
+    #[test]
+    fn test_import() {
+        let directive = parse_one("// #import lighting::pbr;").unwrap();
+        let Directive::Import(import) = &directive else {
+            panic!("{directive:?}");
+        };
+        assert_eq!(import.module_path(), "lighting::pbr");
+        assert_eq!(import.path[1].range().byte_range(), 21..24);
+        assert_eq!(directive.range().byte_range(), 3..25);
+
+        let test_cases = [
+            ("// #import;", 10..10),
+            ("// #import lighting::;", 21..21),
+            ("// #import \"lighting\";", 11..21),
+        ];
+        for (source, byte_range) in test_cases {
+            let error = parse_one(source).unwrap_err();
+            assert_eq!(error.kind, DirectiveParseErrorKind::ExpectedIdentifier);
+            assert_eq!(error.range.byte_range(), byte_range, "{source}");
+        }
+        let error = parse_one("// #import lighting pbr;").unwrap_err();
+        assert_eq!(error.kind, DirectiveParseErrorKind::UnexpectedToken);
+    }
+
+    #[test]
+    fn test_import_items() {
+        let directive = parse_one("// #import lighting::{pbr_direct, fresnel_schlick,};").unwrap();
+        let Directive::Import(import) = &directive else {
+            panic!("{directive:?}");
+        };
+        assert_eq!(import.module_path(), "lighting");
+        let items: Vec<_> = import.items.iter().flatten().map(|i| i.inner_str).collect();
+        assert_eq!(items, ["pbr_direct", "fresnel_schlick"]);
+        let Directive::Import(import) = parse_one("// #import a::{b};").unwrap() else {
+            panic!();
+        };
+        assert_eq!(import.items.map(|items| items.len()), Some(1));
+
+        let test_cases = [
+            (
+                "// #import lighting::{};",
+                DirectiveParseErrorKind::ExpectedIdentifier,
+            ),
+            (
+                "// #import lighting::{a b};",
+                DirectiveParseErrorKind::UnexpectedToken,
+            ),
+            (
+                "// #import lighting::{a}::b;",
+                DirectiveParseErrorKind::UnexpectedToken,
+            ),
+        ];
+        for (source, kind) in test_cases {
+            assert_eq!(parse_one(source).map_err(|e| e.kind), Err(kind), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_define() {
+        let Directive::Define(object_like) = parse_one("// #define LIGHTS 4;").unwrap() else {
+            panic!();
+        };
+        assert_eq!(object_like.name.inner_str, "LIGHTS");
+        assert_eq!(object_like.params, None);
+        assert_eq!(object_like.body.len(), 1);
+
+        let Directive::Define(function_like) =
+            parse_one("// #define MAX(a, b) select(b, a, a > b);").unwrap()
+        else {
+            panic!();
+        };
+        let params: Vec<_> = function_like
+            .params
+            .unwrap()
+            .iter()
+            .map(|p| p.inner_str)
+            .collect();
+        assert_eq!(params, ["a", "b"]);
+        assert_eq!(function_like.body.len(), 10);
+
+        // A space before the parenthesis makes it part of the body.
+        let Directive::Define(spaced) = parse_one("// #define PAREN (a);").unwrap() else {
+            panic!();
+        };
+        assert_eq!((spaced.params, spaced.body.len()), (None, 3));
+
+        let Directive::Define(variadic) =
+            parse_one("// #define LOG(fmt, ...) log(fmt, __VA_ARGS__);").unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(variadic.params.unwrap().len(), 1);
+        assert!(variadic.variadic);
+        let Directive::Define(only_variadic) = parse_one("// #define F(...) 1;").unwrap() else {
+            panic!();
+        };
+        assert_eq!(
+            (only_variadic.params, only_variadic.variadic),
+            (Some(vec![]), true)
+        );
+    }
+
+    #[test]
+    fn test_define_errors() {
+        let test_cases = [
+            (
+                "// #define fn 1;",
+                DirectiveParseErrorKind::InvalidMacroName(IdentifierError::Keyword),
+                11..13,
+            ),
+            (
+                "// #define __X 1;",
+                DirectiveParseErrorKind::InvalidMacroName(IdentifierError::DoubleUnderscorePrefix),
+                11..14,
+            ),
+            (
+                "// #define F(a, a) a;",
+                DirectiveParseErrorKind::DuplicateParameter,
+                16..17,
+            ),
+            (
+                "// #define F(a b) a;",
+                DirectiveParseErrorKind::InvalidParameterList,
+                15..16,
+            ),
+            (
+                "// #define F(..., a) a;",
+                DirectiveParseErrorKind::InvalidParameterList,
+                16..17,
+            ),
+            (
+                "// #define F(a, ;) a;",
+                DirectiveParseErrorKind::InvalidParameterList,
+                16..17,
+            ),
+            // The end ident does not end a directive inside of brackets.
+            (
+                "// #define F(a;",
+                DirectiveParseErrorKind::Token(MacroTokenErrorKind::UnclosedBracket('(')),
+                12..13,
+            ),
+            (
+                "// #define;",
+                DirectiveParseErrorKind::ExpectedIdentifier,
+                10..10,
+            ),
+        ];
+        for (source, kind, byte_range) in test_cases {
+            let error = parse_one(source).unwrap_err();
+            assert_eq!(error.kind, kind, "{source}");
+            assert_eq!(error.range.byte_range(), byte_range, "{source}");
+        }
+    }
+
+    #[test]
+    fn test_conditionals_and_misc() {
+        let source = "// #ifdef A;\n\
+                      // #elif B && C;\n\
+                      // #else;\n\
+                      // #endif;\n\
+                      // #error \"unsupported\";\n\
+                      // #warning \"slow path\";\n\
+                      // #pragma once;\n\
+                      // #bindings material;";
+        let names: Vec<_> = parse_all(source)
+            .into_iter()
+            .map(|result| result.unwrap().name().to_owned())
+            .collect();
+        assert_eq!(
+            names,
+            ["ifdef", "elif", "else", "endif", "error", "warning", "pragma", "bindings"]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let test_cases = [
+            ("// #;", DirectiveParseErrorKind::MissingName),
+            ("// #endif now;", DirectiveParseErrorKind::UnexpectedToken),
+            ("// #if;", DirectiveParseErrorKind::MissingCondition),
+            (
+                "// #include lighting;",
+                DirectiveParseErrorKind::ExpectedStringLiteral,
+            ),
+            (
+                "// #error \"oops;",
+                DirectiveParseErrorKind::Token(MacroTokenErrorKind::UnterminatedStringLiteral {
+                    opening_quote: (0, 10, 10).into(),
+                }),
+            ),
+            (
+                "// #define A\nfn main() {}",
+                DirectiveParseErrorKind::Token(MacroTokenErrorKind::UnterminatedDirective),
+            ),
+            (
+                "// #define A 0x;",
+                DirectiveParseErrorKind::Token(MacroTokenErrorKind::MalformedNumber),
+            ),
+        ];
+        for (source, kind) in test_cases {
+            assert_eq!(parse_one(source).unwrap_err().kind, kind, "{source}");
+        }
+    }
+}
@@ -1,3 +1,705 @@
-fn main() {
-    todo!();
+//! `yawgsl`, the command line interface to the preprocessor.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+use yet_another_wgsl_preprocessor::preprocessor::{
+    content_hash, depfile, BacktraceFrame, FsResolver, OutputMode, Permutation, PermutationMatrix,
+    PreprocessCache, PreprocessError, PreprocessOutput, Preprocessor,
+};
+
+const USAGE: &str = "\
+Usage: yawgsl [watch] [OPTIONS] <INPUT>
+
+Preprocesses the WGSL file INPUT. With `watch`, keeps running and preprocesses INPUT again
+whenever it or a file it read changes, printing errors instead of exiting.
+
+Options:
+  -o <FILE>          Write the output to FILE instead of stdout
+  -I <DIR>           Search DIR for included files
+  -D <NAME[=VALUE]>  Define the macro NAME as VALUE, or as nothing
+  --module-root <DIR>
+                     Find modules imported with #import in DIR
+  --mangle-names     Prefix the names declared by modules with the modules' paths
+  --eliminate-dead-code
+                     Remove declarations that entry points and kept symbols do not use
+  --keep <NAME>      Keep the declaration NAME when eliminating dead code
+  --entry-point <NAME>
+                     Only emit the entry point NAME and what it uses. When given more than
+                     once, each entry point is written to <NAME>.wgsl in the -o directory
+  --split-entry-points
+                     Write each entry point to <NAME>.wgsl in the -o directory
+  --permute <NAME[=VALUE,...]>
+                     Preprocess once per VALUE of NAME, or with NAME defined and not, for every
+                     combination with the other --permute options. Writes the distinct outputs
+                     to the -o directory, with a manifest.json that lists the permutations
+  --exclude <NAME=VALUE,...>
+                     Skip the permutations that have all of the given values
+  --deny-warnings    Fail if preprocessing produces warnings
+  --preserve-lines   Keep every line of INPUT on the same line of the output
+  -MD                Also write a Makefile-style depfile that lists every file that was read, to
+                     the -o path with its extension replaced by .d
+  -MF <FILE>         Write the depfile to FILE instead, implies -MD
+  -h, --help         Print this message";
+
+// How often `watch` checks whether files changed.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Args {
+    watch: bool,
+    input: String,
+    output: Option<String>,
+    include_dirs: Vec<String>,
+    defines: Vec<(String, String)>,
+    module_root: Option<String>,
+    mangle_names: bool,
+    eliminate_dead_code: bool,
+    kept_symbols: Vec<String>,
+    entry_points: Vec<String>,
+    split_entry_points: bool,
+    // `None` for a define that is toggled on and off.
+    permute: Vec<(String, Option<Vec<String>>)>,
+    exclude: Vec<Vec<(String, String)>>,
+    deny_warnings: bool,
+    preserve_lines: bool,
+    write_depfile: bool,
+    depfile: Option<String>,
+}
+
+// Returns `Ok(None)` if help was requested.
+fn parse_args(raw_args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut args = Args::default();
+    let mut maybe_input = None;
+    let mut raw_args = raw_args.peekable();
+    if raw_args.peek().is_some_and(|arg| arg == "watch") {
+        raw_args.next();
+        args.watch = true;
+    }
+    while let Some(arg) = raw_args.next() {
+        // Options taking a value accept it attached (`-Idir`) or as the next argument.
+        let mut value_of = |option: &str| -> Result<String, String> {
+            match arg[option.len()..].to_owned() {
+                attached if !attached.is_empty() => Ok(attached),
+                _ => raw_args
+                    .next()
+                    .ok_or_else(|| format!("{option} requires a value")),
+            }
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--deny-warnings" => args.deny_warnings = true,
+            "--mangle-names" => args.mangle_names = true,
+            "--eliminate-dead-code" => args.eliminate_dead_code = true,
+            "--split-entry-points" => args.split_entry_points = true,
+            "--preserve-lines" => args.preserve_lines = true,
+            "-MD" => args.write_depfile = true,
+            "--module-root" => {
+                let dir = raw_args.next().ok_or("--module-root requires a value")?;
+                args.module_root = Some(dir);
+            }
+            "--keep" => {
+                let name = raw_args.next().ok_or("--keep requires a value")?;
+                args.kept_symbols.push(name);
+            }
+            "--permute" => {
+                let define = raw_args.next().ok_or("--permute requires a value")?;
+                args.permute.push(match define.split_once('=') {
+                    Some((name, values)) => {
+                        let values = values.split(',').map(str::to_owned).collect();
+                        (name.to_owned(), Some(values))
+                    }
+                    None => (define, None),
+                });
+            }
+            "--exclude" => {
+                let choices = raw_args.next().ok_or("--exclude requires a value")?;
+                let choices = choices
+                    .split(',')
+                    .map(|choice| match choice.split_once('=') {
+                        Some((name, value)) => Ok((name.to_owned(), value.to_owned())),
+                        None => Err(format!("--exclude expects NAME=VALUE, not `{choice}`")),
+                    })
+                    .collect::<Result<_, _>>()?;
+                args.exclude.push(choices);
+            }
+            "--entry-point" => {
+                let name = raw_args.next().ok_or("--entry-point requires a value")?;
+                args.entry_points.push(name);
+            }
+            _ if arg.starts_with("-MF") => {
+                args.depfile = Some(value_of("-MF")?);
+                args.write_depfile = true;
+            }
+            _ if arg.starts_with("-o") => args.output = Some(value_of("-o")?),
+            _ if arg.starts_with("-I") => args.include_dirs.push(value_of("-I")?),
+            _ if arg.starts_with("-D") => {
+                let define = value_of("-D")?;
+                let (name, value) = define.split_once('=').unwrap_or((&define, ""));
+                args.defines.push((name.to_owned(), value.to_owned()));
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown option `{arg}`"));
+            }
+            _ if maybe_input.is_some() => return Err(format!("unexpected argument `{arg}`")),
+            _ => maybe_input = Some(arg),
+        }
+    }
+    args.input = maybe_input.ok_or("no input file given")?;
+    Ok(Some(args))
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let preprocessor = preprocessor(args)?;
+    match args.watch {
+        true => watch(&preprocessor, args),
+        false => build(
+            &preprocessor,
+            args,
+            &mut PreprocessCache::new(),
+            &mut Files::default(),
+        ),
+    }
+}
+
+fn preprocessor(args: &Args) -> Result<Preprocessor<'static>, String> {
+    let mut resolver = FsResolver::new();
+    for dir in &args.include_dirs {
+        resolver.add_include_dir(dir);
+    }
+    let mut preprocessor = Preprocessor::new(resolver);
+    if args.preserve_lines {
+        preprocessor.set_output_mode(OutputMode::LinePreserving);
+    }
+    if let Some(module_root) = &args.module_root {
+        preprocessor.set_module_root(module_root);
+    }
+    preprocessor.set_mangle_names(args.mangle_names);
+    preprocessor.set_eliminate_dead_code(args.eliminate_dead_code);
+    for name in &args.kept_symbols {
+        preprocessor.keep_symbol(name);
+    }
+    for (name, value) in &args.defines {
+        preprocessor
+            .define(name, value)
+            .map_err(|error| format!("-D {name}: {error}"))?;
+    }
+    Ok(preprocessor)
+}
+
+// The files that a build read and wrote.
+#[derive(Clone, Debug, Default)]
+struct Files {
+    // Includes the file that an error is in.
+    read: BTreeSet<String>,
+    written: Vec<String>,
+}
+
+impl Files {
+    fn write(&mut self, path: &str, contents: &str) -> Result<(), String> {
+        write(path, contents)?;
+        self.written.push(path.to_owned());
+        Ok(())
+    }
+}
+
+// Preprocesses the input, then writes the outputs and the depfile.
+fn build(
+    preprocessor: &Preprocessor,
+    args: &Args,
+    cache: &mut PreprocessCache,
+    files: &mut Files,
+) -> Result<(), String> {
+    if !args.write_depfile {
+        return write_outputs(preprocessor, args, cache, files);
+    }
+    // The depfile's target is the output.
+    let output = args.output.as_ref().ok_or("-MD requires -o <PATH>")?;
+    let path = args.depfile.clone().unwrap_or_else(|| {
+        let path = std::path::Path::new(output).with_extension("d");
+        path.to_string_lossy().into_owned()
+    });
+    write_outputs(preprocessor, args, cache, files)?;
+    let targets = files.written.iter().map(String::as_str);
+    write(
+        &path,
+        &depfile(targets, files.read.iter().map(String::as_str)),
+    )
+}
+
+fn write_outputs(
+    preprocessor: &Preprocessor,
+    args: &Args,
+    cache: &mut PreprocessCache,
+    files: &mut Files,
+) -> Result<(), String> {
+    if !args.permute.is_empty() {
+        return run_permutations(preprocessor, args, files);
+    }
+    let output = preprocessor
+        .preprocess_cached(&args.input, cache)
+        .map_err(|error| error_message(error, files))?;
+    files
+        .read
+        .extend(output.files.iter().map(|(_, file)| file.path.clone()));
+    report_warnings(args, &output)?;
+
+    if !args.split_entry_points && args.entry_points.len() <= 1 {
+        let output = match args.entry_points.first() {
+            Some(name) => entry_point(&output, name)?,
+            None => (*output).clone(),
+        };
+        return match &args.output {
+            Some(path) => files.write(path, &output.code),
+            None => {
+                print!("{}", output.code);
+                Ok(())
+            }
+        };
+    }
+
+    let dir = args
+        .output
+        .as_ref()
+        .ok_or("writing several entry points requires -o <DIR>")?;
+    std::fs::create_dir_all(dir).map_err(|error| format!("could not create {dir}: {error}"))?;
+    let names = match args.entry_points.is_empty() {
+        true => output.entry_points(),
+        false => args.entry_points.clone(),
+    };
+    for name in names {
+        let path = std::path::Path::new(dir).join(format!("{name}.wgsl"));
+        files.write(&path.to_string_lossy(), &entry_point(&output, &name)?.code)?;
+    }
+    Ok(())
+}
+
+// Preprocesses the input again whenever it or a file it read changes, until interrupted. Changes
+// are found by polling the files' modification times.
+fn watch(preprocessor: &Preprocessor, args: &Args) -> Result<(), String> {
+    let output = args.output.as_ref().ok_or("watch requires -o <PATH>")?;
+    let mut cache = PreprocessCache::new();
+    let mut watched = BTreeMap::from([(args.input.clone(), modified(&args.input))]);
+    loop {
+        let mut files = Files::default();
+        let result = build(preprocessor, args, &mut cache, &mut files);
+        match &result {
+            Ok(()) => eprintln!("wrote {output}"),
+            Err(message) => eprintln!("error: {message}"),
+        }
+        update_watched(&mut watched, args, &files, result.is_ok(), modified);
+        while !poll_changes(&mut watched, modified) {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+// Watches the files read by a build. Files that are no longer read stop being watched, unless the
+// build failed: then the files read before the error are unknown, so the ones watched so far stay
+// watched.
+fn update_watched(
+    watched: &mut BTreeMap<String, Option<SystemTime>>,
+    args: &Args,
+    files: &Files,
+    succeeded: bool,
+    modified: impl Fn(&str) -> Option<SystemTime>,
+) {
+    if succeeded {
+        watched.retain(|path, _| files.read.contains(path) || *path == args.input);
+    }
+    for path in &files.read {
+        if !watched.contains_key(path) {
+            watched.insert(path.clone(), modified(path));
+        }
+    }
+}
+
+// Updates the modification times of the files in `watched`. Returns whether any of them changed.
+fn poll_changes(
+    watched: &mut BTreeMap<String, Option<SystemTime>>,
+    modified: impl Fn(&str) -> Option<SystemTime>,
+) -> bool {
+    let mut changed = false;
+    for (path, last_modified) in watched.iter_mut() {
+        let now_modified = modified(path);
+        if now_modified != *last_modified {
+            *last_modified = now_modified;
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Returns when the file at `path` was last modified, or `None` if that cannot be read, like for a
+// removed file.
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Formats `error`, and records the files it was found through as read: the one it is in, and
+// the ones that include, import or invoke the code it is in.
+fn error_message(error: PreprocessError, files: &mut Files) -> String {
+    let frame_locations = error.backtrace.iter().map(|frame| match frame {
+        BacktraceFrame::MacroExpansion { location, .. }
+        | BacktraceFrame::Include { location }
+        | BacktraceFrame::Import { location } => location,
+    });
+    for location in std::iter::once(&error.location)
+        .chain(frame_locations)
+        .flatten()
+    {
+        files.read.insert(location.path.clone());
+    }
+    error.to_string()
+}
+
+fn run_permutations(
+    preprocessor: &Preprocessor,
+    args: &Args,
+    files: &mut Files,
+) -> Result<(), String> {
+    if args.split_entry_points || !args.entry_points.is_empty() {
+        return Err("--permute cannot be combined with entry point options".to_owned());
+    }
+    let dir = args.output.as_ref().ok_or("--permute requires -o <DIR>")?;
+    let mut matrix = PermutationMatrix::new();
+    for (name, maybe_values) in &args.permute {
+        match maybe_values {
+            Some(values) => matrix.values(name, values),
+            None => matrix.toggle(name),
+        };
+    }
+    let is_excluded = |permutation: &Permutation| {
+        args.exclude.iter().any(|choices| {
+            choices
+                .iter()
+                .all(|(name, value)| permutation.choice(name) == Some(value))
+        })
+    };
+    let permutations = preprocessor
+        .preprocess_permutations(&args.input, &matrix, |permutation| {
+            !is_excluded(permutation)
+        })
+        .map_err(|error| {
+            let message = format!("in permutation {}: ", error.permutation.key());
+            message + &error_message(*error.error, files)
+        })?;
+    for output in &permutations.outputs {
+        files
+            .read
+            .extend(output.files.iter().map(|(_, file)| file.path.clone()));
+    }
+
+    std::fs::create_dir_all(dir).map_err(|error| format!("could not create {dir}: {error}"))?;
+    // Outputs are named by their code's hash, so that their names do not depend on the matrix.
+    let mut file_names = Vec::new();
+    for output in &permutations.outputs {
+        report_warnings(args, output)?;
+        let file_name = format!("{:016x}.wgsl", content_hash(&output.code));
+        let path = std::path::Path::new(dir).join(&file_name);
+        files.write(&path.to_string_lossy(), &output.code)?;
+        file_names.push(file_name);
+    }
+
+    let manifest = manifest(&args.input, &permutations.permutations, &file_names);
+    let path = std::path::Path::new(dir).join("manifest.json");
+    files.write(&path.to_string_lossy(), &manifest)
+}
+
+// Lists each permutation's key, defines and output file as JSON.
+fn manifest(input: &str, permutations: &[(Permutation, usize)], file_names: &[String]) -> String {
+    let mut manifest = format!(
+        "{{\n  \"input\": {},\n  \"permutations\": [",
+        json_string(input)
+    );
+    for (idx, (permutation, output_idx)) in permutations.iter().enumerate() {
+        let defines: Vec<String> = permutation
+            .choices
+            .iter()
+            .map(|(name, value)| format!("{}: {}", json_string(name), json_string(value)))
+            .collect();
+        manifest.push_str(if idx == 0 { "\n" } else { ",\n" });
+        manifest.push_str(&format!(
+            "    {{\"key\": {}, \"defines\": {{{}}}, \"file\": {}}}",
+            json_string(&permutation.key()),
+            defines.join(", "),
+            json_string(&file_names[*output_idx]),
+        ));
+    }
+    manifest.push_str("\n  ]\n}\n");
+    manifest
+}
+
+fn report_warnings(args: &Args, output: &PreprocessOutput) -> Result<(), String> {
+    for warning in &output.diagnostics {
+        eprintln!("{warning}");
+    }
+    match args.deny_warnings && !output.diagnostics.is_empty() {
+        true => Err("warnings are denied".to_owned()),
+        false => Ok(()),
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn entry_point(output: &PreprocessOutput, name: &str) -> Result<PreprocessOutput, String> {
+    output
+        .entry_point(name)
+        .ok_or_else(|| format!("no entry point named `{name}`"))
+}
+
+fn write(path: &str, contents: &str) -> Result<(), String> {
+    std::fs::write(path, contents).map_err(|error| format!("could not write {path}: {error}"))
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(message) => {
+            eprintln!("error: {message}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yet_another_wgsl_preprocessor::preprocessor::MemoryResolver;
+
+    fn parse(args: &[&str]) -> Result<Option<Args>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&[
+            "-I",
+            "shaders",
+            "-Ilib",
+            "-DFAST",
+            "-D",
+            "N=4",
+            "--deny-warnings",
+            "--preserve-lines",
+            "-MD",
+            "-MFdeps/main.d",
+            "--module-root",
+            "shaders",
+            "--mangle-names",
+            "--eliminate-dead-code",
+            "--keep",
+            "debug",
+            "--entry-point",
+            "vs",
+            "--entry-point",
+            "fs",
+            "--split-entry-points",
+            "--permute",
+            "SHADOWS",
+            "--permute",
+            "LIGHTS=4,8",
+            "--exclude",
+            "SHADOWS=off,LIGHTS=8",
+            "main.wgsl",
+            "-o",
+            "out.wgsl",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            args,
+            Args {
+                watch: false,
+                input: "main.wgsl".to_owned(),
+                output: Some("out.wgsl".to_owned()),
+                include_dirs: vec!["shaders".to_owned(), "lib".to_owned()],
+                defines: vec![
+                    ("FAST".to_owned(), String::new()),
+                    ("N".to_owned(), "4".to_owned())
+                ],
+                module_root: Some("shaders".to_owned()),
+                mangle_names: true,
+                eliminate_dead_code: true,
+                kept_symbols: vec!["debug".to_owned()],
+                entry_points: vec!["vs".to_owned(), "fs".to_owned()],
+                split_entry_points: true,
+                permute: vec![
+                    ("SHADOWS".to_owned(), None),
+                    (
+                        "LIGHTS".to_owned(),
+                        Some(vec!["4".to_owned(), "8".to_owned()])
+                    ),
+                ],
+                exclude: vec![vec![
+                    ("SHADOWS".to_owned(), "off".to_owned()),
+                    ("LIGHTS".to_owned(), "8".to_owned()),
+                ]],
+                deny_warnings: true,
+                preserve_lines: true,
+                write_depfile: true,
+                depfile: Some("deps/main.d".to_owned()),
+            }
+        );
+        assert_eq!(parse(&["main.wgsl", "--help"]), Ok(None));
+
+        let args = parse(&["watch", "main.wgsl", "-o", "out.wgsl"])
+            .unwrap()
+            .unwrap();
+        assert!(args.watch);
+        assert_eq!(args.input, "main.wgsl");
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["main.wgsl", "-o"]).is_err());
+        assert!(parse(&["main.wgsl", "-MF"]).is_err());
+        assert!(parse(&["main.wgsl", "--module-root"]).is_err());
+        assert!(parse(&["main.wgsl", "--keep"]).is_err());
+        assert!(parse(&["main.wgsl", "--entry-point"]).is_err());
+        assert!(parse(&["main.wgsl", "--exclude", "SHADOWS"]).is_err());
+        assert!(parse(&["main.wgsl", "--frobnicate"]).is_err());
+        assert!(parse(&["a.wgsl", "b.wgsl"]).is_err());
+        assert!(parse(&["main.wgsl", "watch"]).is_err());
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn test_manifest() {
+        let mut matrix = PermutationMatrix::new();
+        matrix.toggle("FAST").values("PATH", ["C:\\\"shaders\""]);
+        let permutations: Vec<_> = matrix.permutations().into_iter().zip([0, 0]).collect();
+        assert_eq!(
+            manifest("main.wgsl", &permutations, &["a.wgsl".to_owned()]),
+            r#"{
+  "input": "main.wgsl",
+  "permutations": [
+    {"key": "FAST=on,PATH=C:\\\"shaders\"", "defines": {"FAST": "on", "PATH": "C:\\\"shaders\""}, "file": "a.wgsl"},
+    {"key": "FAST=off,PATH=C:\\\"shaders\"", "defines": {"FAST": "off", "PATH": "C:\\\"shaders\""}, "file": "a.wgsl"}
+  ]
+}
+"#
+        );
+    }
+
+    // Builds `main.wgsl` from `files` and updates `watched` like `watch` does.
+    fn build_and_watch(
+        files: &[(&str, &str)],
+        watched: &mut BTreeMap<String, Option<SystemTime>>,
+    ) -> Result<(), String> {
+        let mut resolver = MemoryResolver::new();
+        for (path, source) in files {
+            resolver.add_file(*path, *source);
+        }
+        let preprocessor = Preprocessor::new(resolver);
+        let args = parse(&["main.wgsl"]).unwrap().unwrap();
+        let mut read = Files::default();
+        let result = build(&preprocessor, &args, &mut PreprocessCache::new(), &mut read);
+        update_watched(watched, &args, &read, result.is_ok(), |_| None);
+        result
+    }
+
+    fn paths(watched: &BTreeMap<String, Option<SystemTime>>) -> Vec<&str> {
+        watched.keys().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn test_update_watched() {
+        let mut watched = BTreeMap::from([("main.wgsl".to_owned(), None)]);
+        let files = [
+            ("main.wgsl", "// #include \"a.wgsl\";\n"),
+            ("a.wgsl", "// #include \"b.wgsl\";\n"),
+            ("b.wgsl", "const B = 1;\n"),
+        ];
+        build_and_watch(&files, &mut watched).unwrap();
+        assert_eq!(paths(&watched), ["a.wgsl", "b.wgsl", "main.wgsl"]);
+
+        // The files read before the error stay watched, and so does the one with the error.
+        let files = [
+            ("main.wgsl", "// #include \"c.wgsl\";\n"),
+            ("c.wgsl", "// #bogus;\n"),
+        ];
+        let message = build_and_watch(&files, &mut watched).unwrap_err();
+        assert!(message.starts_with("c.wgsl:1:4: "));
+        assert!(message.ends_with("\n  included from main.wgsl:1:4"));
+        assert_eq!(paths(&watched), ["a.wgsl", "b.wgsl", "c.wgsl", "main.wgsl"]);
+
+        let files = [
+            ("main.wgsl", "// #include \"a.wgsl\";\n"),
+            ("a.wgsl", "const A = 1;\n"),
+        ];
+        build_and_watch(&files, &mut watched).unwrap();
+        assert_eq!(paths(&watched), ["a.wgsl", "main.wgsl"]);
+    }
+
+    #[test]
+    fn test_watch_after_error() {
+        // Every file that leads to the error is watched, so that removing the include that leads
+        // to it rebuilds too.
+        let mut watched = BTreeMap::from([("main.wgsl".to_owned(), None)]);
+        let files = [
+            ("main.wgsl", "// #include \"a.wgsl\";\n"),
+            ("a.wgsl", "// #include \"b.wgsl\";\n"),
+            ("b.wgsl", "// #bogus;\n"),
+        ];
+        let message = build_and_watch(&files, &mut watched).unwrap_err();
+        assert_eq!(
+            message,
+            "b.wgsl:1:4: unknown directive #bogus\n  \
+             included from a.wgsl:1:4\n  \
+             included from main.wgsl:1:4"
+        );
+        assert_eq!(paths(&watched), ["a.wgsl", "b.wgsl", "main.wgsl"]);
+    }
+
+    #[test]
+    fn test_poll_changes() {
+        let earlier = SystemTime::UNIX_EPOCH;
+        let later = earlier + Duration::from_secs(1);
+        let mut watched = BTreeMap::from([
+            ("a.wgsl".to_owned(), Some(earlier)),
+            ("b.wgsl".to_owned(), Some(earlier)),
+        ]);
+        assert!(!poll_changes(&mut watched, |_| Some(earlier)));
+        assert!(poll_changes(&mut watched, |path| {
+            Some(if path == "b.wgsl" { later } else { earlier })
+        }));
+        assert_eq!(watched["b.wgsl"], Some(later));
+        assert!(!poll_changes(&mut watched, |path| {
+            Some(if path == "b.wgsl" { later } else { earlier })
+        }));
+        // Removing a file is a change too.
+        assert!(poll_changes(&mut watched, |_| None));
+    }
 }
@@ -0,0 +1,268 @@
+use core::fmt::Display;
+#[cfg(feature = "std")]
+use std::error::Error;
+
+// WGSL keywords.
+const KEYWORDS: &[&str] = &[
+    "alias",
+    "break",
+    "case",
+    "const",
+    "const_assert",
+    "continue",
+    "continuing",
+    "default",
+    "diagnostic",
+    "discard",
+    "else",
+    "enable",
+    "false",
+    "fn",
+    "for",
+    "if",
+    "let",
+    "loop",
+    "override",
+    "requires",
+    "return",
+    "struct",
+    "switch",
+    "true",
+    "var",
+    "while",
+];
+
+// Words reserved by WGSL for future use.
+const RESERVED_WORDS: &[&str] = &[
+    "NULL",
+    "Self",
+    "abstract",
+    "active",
+    "alignas",
+    "alignof",
+    "as",
+    "asm",
+    "asm_fragment",
+    "async",
+    "attribute",
+    "auto",
+    "await",
+    "become",
+    "cast",
+    "catch",
+    "class",
+    "co_await",
+    "co_return",
+    "co_yield",
+    "coherent",
+    "column_major",
+    "common",
+    "compile",
+    "compile_fragment",
+    "concept",
+    "const_cast",
+    "consteval",
+    "constexpr",
+    "constinit",
+    "crate",
+    "debugger",
+    "decltype",
+    "delete",
+    "demote",
+    "demote_to_helper",
+    "do",
+    "dynamic_cast",
+    "enum",
+    "explicit",
+    "export",
+    "extends",
+    "extern",
+    "external",
+    "fallthrough",
+    "filter",
+    "final",
+    "finally",
+    "friend",
+    "from",
+    "fxgroup",
+    "get",
+    "goto",
+    "groupshared",
+    "highp",
+    "impl",
+    "implements",
+    "import",
+    "inline",
+    "instanceof",
+    "interface",
+    "layout",
+    "lowp",
+    "macro",
+    "macro_rules",
+    "match",
+    "mediump",
+    "meta",
+    "mod",
+    "module",
+    "move",
+    "mut",
+    "mutable",
+    "namespace",
+    "new",
+    "nil",
+    "noexcept",
+    "noinline",
+    "nointerpolation",
+    "non_coherent",
+    "noncoherent",
+    "noperspective",
+    "null",
+    "nullptr",
+    "of",
+    "operator",
+    "package",
+    "packoffset",
+    "partition",
+    "pass",
+    "patch",
+    "pixelfragment",
+    "precise",
+    "precision",
+    "premerge",
+    "priv",
+    "protected",
+    "pub",
+    "public",
+    "readonly",
+    "ref",
+    "regardless",
+    "register",
+    "reinterpret_cast",
+    "require",
+    "resource",
+    "restrict",
+    "self",
+    "set",
+    "shared",
+    "sizeof",
+    "smooth",
+    "snorm",
+    "static",
+    "static_assert",
+    "static_cast",
+    "std",
+    "subroutine",
+    "super",
+    "target",
+    "template",
+    "this",
+    "thread_local",
+    "throw",
+    "trait",
+    "try",
+    "type",
+    "typedef",
+    "typeid",
+    "typename",
+    "typeof",
+    "union",
+    "unless",
+    "unorm",
+    "unsafe",
+    "unsized",
+    "use",
+    "using",
+    "varying",
+    "virtual",
+    "volatile",
+    "wgsl",
+    "where",
+    "with",
+    "writeonly",
+    "yield",
+];
+
+/// Returns true if `c` can start a WGSL identifier, which includes `_`.
+///
+/// This only looks at `c`. A lone `_` is not an identifier, so `_` only starts one if an identifier
+/// char follows it, which the crate checks separately in `ident_len`.
+pub fn is_ident_start(c: char) -> bool {
+    c == '_' || unicode_ident::is_xid_start(c)
+}
+
+/// Returns true if `c` can continue a WGSL identifier.
+pub fn is_ident_continue(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
+}
+
+// Returns the byte length of the identifier at the start of `s`, or 0 if there is none, like for
+// a lone `_`.
+pub(crate) fn ident_len(s: &str) -> usize {
+    let mut chars = s.chars();
+    let first_len = match chars.next() {
+        Some('_') if chars.clone().next().is_some_and(is_ident_continue) => 1,
+        Some(c) if c != '_' && is_ident_start(c) => c.len_utf8(),
+        _ => return 0,
+    };
+    first_len
+        + chars
+            .take_while(|c| is_ident_continue(*c))
+            .map(char::len_utf8)
+            .sum::<usize>()
+}
+
+/// Checks that `ident` may be declared as a WGSL identifier.
+///
+/// Besides the XID char rules, WGSL forbids a lone `_`, a `__` prefix, keywords and reserved
+/// words.
+pub fn validate_wgsl_identifier(ident: &str) -> Result<(), IdentifierError> {
+    if ident.is_empty() {
+        return Err(IdentifierError::Empty);
+    }
+    if ident_len(ident) != ident.len() {
+        return Err(IdentifierError::InvalidChars);
+    }
+    if ident.starts_with("__") {
+        return Err(IdentifierError::DoubleUnderscorePrefix);
+    }
+    if KEYWORDS.contains(&ident) {
+        return Err(IdentifierError::Keyword);
+    }
+    if RESERVED_WORDS.contains(&ident) {
+        return Err(IdentifierError::ReservedWord);
+    }
+    Ok(())
+}
+
+/// Why a string is not a valid WGSL identifier.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IdentifierError {
+    /// The string is empty.
+    Empty,
+    /// The string is not made of an identifier start char followed by identifier continue chars,
+    /// or is a lone `_`.
+    InvalidChars,
+    /// Identifiers starting with `__` are reserved.
+    DoubleUnderscorePrefix,
+    /// The string is a WGSL keyword.
+    Keyword,
+    /// The string is reserved by WGSL for future use.
+    ReservedWord,
+}
+
+impl Display for IdentifierError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            IdentifierError::Empty => write!(f, "identifier is empty"),
+            IdentifierError::InvalidChars => write!(f, "not a valid identifier"),
+            IdentifierError::DoubleUnderscorePrefix => {
+                write!(f, "identifiers starting with `__` are reserved")
+            }
+            IdentifierError::Keyword => write!(f, "identifier is a WGSL keyword"),
+            IdentifierError::ReservedWord => write!(f, "identifier is a reserved word in WGSL"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for IdentifierError {}
@@ -0,0 +1,435 @@
+use super::{MacroTokenError, MacroTokenErrorKind};
+use crate::utils::LocatedStr;
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+
+/// A double-quoted string literal in a directive, such as an include path or an error message.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct StringLiteral<'a> {
+    /// The literal as written, including both quotes.
+    pub source: LocatedStr<'a>,
+}
+
+impl<'a> StringLiteral<'a> {
+    /// Returns the text between the quotes, with escapes left as written.
+    pub fn raw_contents(&self) -> &'a str {
+        let inner = self.source.inner_str;
+        &inner[1..inner.len() - 1]
+    }
+
+    /// Iterates over the chars of the literal with escapes resolved.
+    pub fn chars(&self) -> StringLiteralChars<'a> {
+        StringLiteralChars {
+            remaining: self.raw_contents(),
+        }
+    }
+
+    /// Returns the contents of the literal with escapes resolved.
+    #[cfg(feature = "alloc")]
+    pub fn value(&self) -> String {
+        self.chars().collect()
+    }
+}
+
+/// Iterator over the unescaped chars of a [`StringLiteral`].
+#[derive(Clone, Debug)]
+pub struct StringLiteralChars<'a> {
+    remaining: &'a str,
+}
+
+impl<'a> Iterator for StringLiteralChars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let c = self.remaining.chars().next()?;
+        self.remaining = &self.remaining[c.len_utf8()..];
+        if c != '\\' {
+            return Some(c);
+        }
+        // Escapes were validated when the literal was tokenized.
+        let (maybe_char, escape_len) = parse_escape(self.remaining);
+        self.remaining = &self.remaining[escape_len..];
+        maybe_char
+    }
+}
+
+// Parses the escape in `after_backslash`. Returns the escaped char (if the escape is valid) and the
+// byte length of the escape. Invalid escapes never include a double quote, so that the closing
+// quote of the literal is still found.
+fn parse_escape(after_backslash: &str) -> (Option<char>, usize) {
+    let mut chars = after_backslash.chars();
+    let escape_char = match chars.next() {
+        Some(c) => c,
+        None => return (None, 0),
+    };
+    let simple_escape = match escape_char {
+        '"' => Some('"'),
+        '\\' => Some('\\'),
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        '0' => Some('\0'),
+        'u' => None,
+        '\n' => return (None, 0),
+        c => return (None, c.len_utf8()),
+    };
+    if simple_escape.is_some() {
+        return (simple_escape, 1);
+    }
+
+    // Unicode escape: u{X} to u{XXXXXX}
+    if chars.next() != Some('{') {
+        return (None, 1);
+    }
+    let mut code_point: u32 = 0;
+    let mut digit_count = 0;
+    let mut escape_len = 2;
+    for c in chars {
+        if c == '}' {
+            escape_len += 1;
+            if digit_count == 0 {
+                return (None, escape_len);
+            }
+            return (char::from_u32(code_point), escape_len);
+        }
+        match c.to_digit(16) {
+            Some(digit) if digit_count < 6 => {
+                code_point = code_point * 16 + digit;
+                digit_count += 1;
+                escape_len += 1;
+            }
+            _ if c == '"' => break,
+            _ => {
+                escape_len += c.len_utf8();
+                break;
+            }
+        }
+    }
+    (None, escape_len)
+}
+
+// Lexes the string literal at the start of `source`, which must start with a double quote.
+// Returns the literal (or the first error in it) and the number of bytes consumed.
+pub(super) fn lex_string_literal(
+    source: LocatedStr<'_>,
+) -> (Result<StringLiteral<'_>, MacroTokenError>, usize) {
+    let inner = source.inner_str;
+    let mut first_error = None;
+    // Skip the opening quote.
+    let mut i = 1;
+
+    while let Some(c) = inner[i..].chars().next() {
+        match c {
+            '"' => {
+                let literal_len = i + 1;
+                let output = match first_error {
+                    Some(error) => Err(error),
+                    None => Ok(StringLiteral {
+                        source: source.get_unchecked(0..literal_len),
+                    }),
+                };
+                return (output, literal_len);
+            }
+            '\\' => {
+                let (maybe_char, escape_len) = parse_escape(&inner[i + 1..]);
+                let escape_end = i + 1 + escape_len;
+                if maybe_char.is_none() {
+                    first_error.get_or_insert(MacroTokenError {
+                        kind: MacroTokenErrorKind::InvalidEscape,
+                        range: source.get_unchecked(i..escape_end).range(),
+                    });
+                }
+                i = escape_end;
+            }
+            _ => i += c.len_utf8(),
+        }
+    }
+
+    (
+        Err(MacroTokenError {
+            kind: MacroTokenErrorKind::UnterminatedStringLiteral {
+                opening_quote: source.start_location,
+            },
+            range: source.range(),
+        }),
+        inner.len(),
+    )
+}
+
+/// A numeric literal in a directive, such as `42`, `0x1Fu` or `1.5e3f`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct NumberLiteral<'a> {
+    /// The literal as written, including any suffix.
+    pub source: LocatedStr<'a>,
+    /// The parsed value.
+    pub value: NumberValue,
+}
+
+/// The value of a [`NumberLiteral`], typed by its WGSL suffix.
+///
+/// Literals without a suffix are abstract, like in WGSL.
+#[derive(Clone, Copy, Debug)]
+pub enum NumberValue {
+    /// An integer without a suffix.
+    AbstractInt(i64),
+    /// An integer with the `i` suffix.
+    I32(i32),
+    /// An integer with the `u` suffix.
+    U32(u32),
+    /// A float without a suffix.
+    AbstractFloat(f64),
+    /// A float with the `f` suffix.
+    F32(f32),
+    /// A float with the `h` suffix. Stored as an `f32` since Rust has no stable `f16`.
+    F16(f32),
+}
+
+impl NumberValue {
+    /// Returns true for the integer variants.
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            NumberValue::AbstractInt(_) | NumberValue::I32(_) | NumberValue::U32(_)
+        )
+    }
+
+    // Floats are compared bitwise so that `NumberValue` can be `Eq` and `Hash`.
+    fn comparison_key(&self) -> (u8, u64) {
+        match *self {
+            NumberValue::AbstractInt(v) => (0, v as u64),
+            NumberValue::I32(v) => (1, v as u64),
+            NumberValue::U32(v) => (2, v as u64),
+            NumberValue::AbstractFloat(v) => (3, v.to_bits()),
+            NumberValue::F32(v) => (4, v.to_bits() as u64),
+            NumberValue::F16(v) => (5, v.to_bits() as u64),
+        }
+    }
+}
+
+impl PartialEq for NumberValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.comparison_key() == other.comparison_key()
+    }
+}
+
+impl Eq for NumberValue {}
+
+impl core::hash::Hash for NumberValue {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.comparison_key().hash(state);
+    }
+}
+
+// The largest finite f16.
+const F16_MAX: f64 = 65504.0;
+
+// Returns true if `source` starts with something that must be lexed as a number.
+pub(super) fn starts_number(source: &str) -> bool {
+    let mut chars = source.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_digit() => true,
+        Some('.') => chars.next().is_some_and(|c| c.is_ascii_digit()),
+        _ => false,
+    }
+}
+
+// Returns the length of the number at the start of `source`. Everything up to the next char that
+// cannot continue a number is included, so that `2Line` is one malformed number rather than a
+// number and an ident.
+pub(crate) fn number_len(source: &str) -> usize {
+    let is_hex = source.starts_with("0x") || source.starts_with("0X");
+    let mut previous = None;
+    for (i, c) in source.char_indices() {
+        let is_exponent_sign = (c == '+' || c == '-')
+            && match previous {
+                Some('e' | 'E') => !is_hex,
+                Some('p' | 'P') => is_hex,
+                _ => false,
+            };
+        if !(c.is_alphanumeric() || c == '_' || c == '.' || is_exponent_sign) {
+            return i;
+        }
+        previous = Some(c);
+    }
+    source.len()
+}
+
+// Parses a whole numeric literal, such as one found by `number_len`.
+pub(crate) fn parse_number(text: &str) -> Result<NumberValue, MacroTokenErrorKind> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex_text) => parse_hex_number(hex_text),
+        None => parse_decimal_number(text),
+    }
+}
+
+// Lexes the number at the start of `source`. Returns the literal (or why it is malformed) and the
+// number of bytes consumed.
+pub(super) fn lex_number_literal(
+    source: LocatedStr<'_>,
+) -> (Result<NumberLiteral<'_>, MacroTokenError>, usize) {
+    let len = number_len(source.inner_str);
+    let literal_source = source.get_unchecked(0..len);
+    let output = parse_number(literal_source.inner_str)
+        .map(|value| NumberLiteral {
+            source: literal_source,
+            value,
+        })
+        .map_err(|kind| MacroTokenError {
+            kind,
+            range: literal_source.range(),
+        });
+
+    (output, len)
+}
+
+// Splits a leading run of digits (in the given radix) off of `s`.
+fn split_digits(s: &str, radix: u32) -> (&str, &str) {
+    let end = s
+        .char_indices()
+        .find(|(_, c)| !c.is_digit(radix))
+        .map(|(i, _)| i)
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+// Splits an optional exponent (`e` or `p` followed by an optionally signed decimal number) off of
+// `s`. Returns the exponent digits including the sign, if there is an exponent.
+fn split_exponent(
+    s: &str,
+    markers: [char; 2],
+) -> Result<(Option<&str>, &str), MacroTokenErrorKind> {
+    let Some(after_marker) = s.strip_prefix(markers) else {
+        return Ok((None, s));
+    };
+    let unsigned = after_marker
+        .strip_prefix(['+', '-'])
+        .unwrap_or(after_marker);
+    let (digits, rest) = split_digits(unsigned, 10);
+    if digits.is_empty() {
+        return Err(MacroTokenErrorKind::MalformedNumber);
+    }
+    let sign_len = after_marker.len() - unsigned.len();
+    Ok((Some(&after_marker[..sign_len + digits.len()]), rest))
+}
+
+fn parse_int_with_suffix(
+    magnitude: Option<u64>,
+    suffix: &str,
+) -> Result<NumberValue, MacroTokenErrorKind> {
+    let magnitude = magnitude.ok_or(MacroTokenErrorKind::NumberOutOfRange)?;
+    match suffix {
+        "" => i64::try_from(magnitude).map(NumberValue::AbstractInt),
+        "i" => i32::try_from(magnitude).map(NumberValue::I32),
+        "u" => u32::try_from(magnitude).map(NumberValue::U32),
+        _ => return Err(MacroTokenErrorKind::MalformedNumber),
+    }
+    .map_err(|_| MacroTokenErrorKind::NumberOutOfRange)
+}
+
+fn float_with_suffix(value: f64, suffix: &str) -> Result<NumberValue, MacroTokenErrorKind> {
+    let value = match suffix {
+        "" => NumberValue::AbstractFloat(value),
+        "f" => NumberValue::F32(value as f32),
+        "h" if value.abs() <= F16_MAX => NumberValue::F16(value as f32),
+        "h" => return Err(MacroTokenErrorKind::NumberOutOfRange),
+        _ => return Err(MacroTokenErrorKind::MalformedNumber),
+    };
+    match value {
+        NumberValue::AbstractFloat(v) if !v.is_finite() => {
+            Err(MacroTokenErrorKind::NumberOutOfRange)
+        }
+        NumberValue::F32(v) if !v.is_finite() => Err(MacroTokenErrorKind::NumberOutOfRange),
+        _ => Ok(value),
+    }
+}
+
+fn parse_decimal_number(text: &str) -> Result<NumberValue, MacroTokenErrorKind> {
+    let (int_digits, rest) = split_digits(text, 10);
+    let (frac_digits, rest) = match rest.strip_prefix('.') {
+        Some(after_point) => {
+            let (frac_digits, rest) = split_digits(after_point, 10);
+            (Some(frac_digits), rest)
+        }
+        None => (None, rest),
+    };
+    let (exponent, suffix) = split_exponent(rest, ['e', 'E'])?;
+    // Leading zeros are not allowed on anything but floats with a decimal point or an exponent.
+    let has_leading_zero = int_digits.len() > 1 && int_digits.starts_with('0');
+
+    if frac_digits.is_none() && exponent.is_none() {
+        if has_leading_zero {
+            return Err(MacroTokenErrorKind::MalformedNumber);
+        }
+        if suffix == "f" || suffix == "h" {
+            // Cannot fail, these are all ASCII digits.
+            let value: f64 = int_digits.parse().unwrap();
+            return float_with_suffix(value, suffix);
+        }
+        return parse_int_with_suffix(int_digits.parse().ok(), suffix);
+    }
+
+    if int_digits.is_empty() && frac_digits.is_none_or(str::is_empty) {
+        return Err(MacroTokenErrorKind::MalformedNumber);
+    }
+    let float_text = &text[..text.len() - suffix.len()];
+    let value: f64 = float_text
+        .parse()
+        .map_err(|_| MacroTokenErrorKind::MalformedNumber)?;
+    float_with_suffix(value, suffix)
+}
+
+// `text` is everything after the `0x`.
+fn parse_hex_number(text: &str) -> Result<NumberValue, MacroTokenErrorKind> {
+    let (int_digits, rest) = split_digits(text, 16);
+    let (frac_digits, rest) = match rest.strip_prefix('.') {
+        Some(after_point) => {
+            let (frac_digits, rest) = split_digits(after_point, 16);
+            (Some(frac_digits), rest)
+        }
+        None => (None, rest),
+    };
+    let (exponent, suffix) = split_exponent(rest, ['p', 'P'])?;
+
+    if frac_digits.is_none() && exponent.is_none() {
+        if int_digits.is_empty() {
+            return Err(MacroTokenErrorKind::MalformedNumber);
+        }
+        let magnitude = int_digits.chars().try_fold(0u64, |acc, c| {
+            acc.checked_mul(16)?.checked_add(c.to_digit(16)? as u64)
+        });
+        return parse_int_with_suffix(magnitude, suffix);
+    }
+
+    let frac_digits = frac_digits.unwrap_or("");
+    if int_digits.is_empty() && frac_digits.is_empty() {
+        return Err(MacroTokenErrorKind::MalformedNumber);
+    }
+    // Without an exponent, the `f` and `h` suffixes would be hex digits.
+    if exponent.is_none() && !suffix.is_empty() {
+        return Err(MacroTokenErrorKind::MalformedNumber);
+    }
+    let mut mantissa = 0f64;
+    for c in int_digits.chars().chain(frac_digits.chars()) {
+        mantissa = mantissa * 16.0 + c.to_digit(16).unwrap() as f64;
+    }
+    let exponent: i64 = match exponent {
+        Some(exponent) => exponent
+            .parse::<i64>()
+            .map_err(|_| MacroTokenErrorKind::NumberOutOfRange)?,
+        None => 0,
+    };
+    let exponent = exponent.saturating_sub(4 * frac_digits.len() as i64);
+    // `f64::powi` is not available without std, and the exponent is bounded by the loop below
+    // leaving the representable range.
+    let mut value = mantissa;
+    if exponent >= 0 {
+        for _ in 0..exponent.min(2048) {
+            value *= 2.0;
+        }
+    } else {
+        for _ in 0..(-exponent).min(2048) {
+            value /= 2.0;
+        }
+    }
+    float_with_suffix(value, suffix)
+}
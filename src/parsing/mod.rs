@@ -1,10 +1,16 @@
+//! Tokenization of preprocessor directives embedded in WGSL comments.
+
+pub(crate) mod ident;
+pub(crate) mod literals;
 mod utils;
 
 #[cfg(test)]
 mod tests;
 
-use crate::utils::{find_any_substring, LocatedStr, TextLocation};
+use crate::utils::{LocatedStr, TextLocation, TextRange};
 use core::fmt::Display;
+pub use ident::{is_ident_continue, is_ident_start, validate_wgsl_identifier, IdentifierError};
+pub use literals::{NumberLiteral, NumberValue, StringLiteral, StringLiteralChars};
 #[cfg(feature = "std")]
 use std::error::Error;
 use utils::CppCommentIter;
@@ -13,17 +19,32 @@ use utils::CppCommentIter;
 pub(crate) struct LocatedStrMacroTokenIter<'a, 'b> {
     source_remaining: LocatedStr<'a>,
     syntax_settings: SyntaxSettings<'b>,
+    // The number of brackets opened and not yet closed. The end ident inside of brackets is a
+    // symbol, so that macro bodies can contain whole statements.
+    bracket_depth: usize,
+    // The outermost bracket that is not yet closed, and its range.
+    open_bracket: Option<(char, TextRange)>,
 }
 
 impl<'a, 'b> LocatedStrMacroTokenIter<'a, 'b> {
-    pub(crate) fn new_with_default_syntax(
+    pub(crate) fn new(
         source: LocatedStr<'a>,
-    ) -> LocatedStrMacroTokenIter<'a, 'static> {
+        syntax_settings: SyntaxSettings<'b>,
+    ) -> LocatedStrMacroTokenIter<'a, 'b> {
         LocatedStrMacroTokenIter {
             source_remaining: source,
-            syntax_settings: SyntaxSettings::default(),
+            syntax_settings,
+            bracket_depth: 0,
+            open_bracket: None,
         }
     }
+
+    #[cfg(test)]
+    pub(crate) fn new_with_default_syntax(
+        source: LocatedStr<'a>,
+    ) -> LocatedStrMacroTokenIter<'a, 'static> {
+        LocatedStrMacroTokenIter::new(source, SyntaxSettings::default())
+    }
 }
 
 impl<'a, 'b> Iterator for LocatedStrMacroTokenIter<'a, 'b> {
@@ -36,62 +57,106 @@ impl<'a, 'b> Iterator for LocatedStrMacroTokenIter<'a, 'b> {
         }
         let end_ident = self.syntax_settings.macro_end_ident;
         let maybe_end_ident = self.source_remaining.inner_str.get(..end_ident.len());
-        if maybe_end_ident == Some(end_ident) {
-            let terminator_loc = self.source_remaining.start_location;
+        if maybe_end_ident == Some(end_ident) && self.bracket_depth == 0 {
+            let terminator_range = self
+                .source_remaining
+                .get_unchecked(0..end_ident.len())
+                .range();
             self.source_remaining = self.source_remaining.get_unchecked(
                 self.syntax_settings.macro_end_ident.len()..self.source_remaining.inner_str.len(),
             );
-            return Some(MacroTokenResult::Terminator(terminator_loc));
+            return Some(MacroTokenResult::Terminator(terminator_range));
         }
         let source_len = self.source_remaining.inner_str.len();
 
-        let mut char_indices = self.source_remaining.inner_str.char_indices();
-
-        // Symbol handling (for now, non-alphanumeric tokens are char-by-char).
-        // SAFETY: We know that there is a char in the str otherwise the above would have caught an
-        // empty str.
-        let (_, first_char) = char_indices.next().unwrap();
-        if !first_char.is_alphanumeric() {
-            let char_len = first_char.len_utf8();
-            let output = Some(MacroTokenResult::SymbolToken(
-                self.source_remaining.get_unchecked(0..char_len),
-            ));
-            self.source_remaining = self
-                .source_remaining
-                .get_unchecked(char_len..self.source_remaining.inner_str.len());
-            return output;
+        if self.source_remaining.inner_str.starts_with('"') {
+            let (literal_result, literal_len) = literals::lex_string_literal(self.source_remaining);
+            self.source_remaining = self.source_remaining.get_unchecked(literal_len..source_len);
+            return Some(match literal_result {
+                Ok(literal) => MacroTokenResult::StringLiteralToken(literal),
+                Err(error) => MacroTokenResult::Error(error),
+            });
         }
 
-        // Must be alphanum. Find the end.
-        for (i, c) in char_indices {
-            if !c.is_alphanumeric() {
-                let output = Some(MacroTokenResult::AlphanumStringToken(
-                    self.source_remaining.get_unchecked(0..i),
-                ));
-                self.source_remaining = self.source_remaining.get_unchecked(i..source_len);
-                return output;
-            }
-            let maybe_end_ident = self.source_remaining.inner_str.get(..end_ident.len());
-            if maybe_end_ident == Some(end_ident) {
-                let output = Some(MacroTokenResult::AlphanumStringToken(
-                    self.source_remaining.get_unchecked(0..i),
-                ));
-                self.source_remaining = self.source_remaining.get_unchecked(i..source_len);
-                return output;
+        if literals::starts_number(self.source_remaining.inner_str) {
+            let (literal_result, literal_len) = literals::lex_number_literal(self.source_remaining);
+            self.source_remaining = self.source_remaining.get_unchecked(literal_len..source_len);
+            return Some(match literal_result {
+                Ok(literal) => MacroTokenResult::NumberToken(literal),
+                Err(error) => MacroTokenResult::Error(error),
+            });
+        }
+
+        let ident_len = ident::ident_len(self.source_remaining.inner_str);
+        if ident_len == 0 {
+            // Symbol handling. The longest matching operator wins, anything else is char-by-char.
+            // SAFETY: We know that there is a char in the str otherwise the above would have
+            // caught an empty str.
+            let first_char = self.source_remaining.inner_str.chars().next().unwrap();
+            let symbol_len = self
+                .syntax_settings
+                .operators
+                .iter()
+                .filter(|operator| self.source_remaining.inner_str.starts_with(**operator))
+                .map(|operator| operator.len())
+                .fold(first_char.len_utf8(), usize::max);
+            let symbol = self.source_remaining.get_unchecked(0..symbol_len);
+            match symbol.inner_str {
+                "(" | "[" | "{" => {
+                    if self.bracket_depth == 0 {
+                        self.open_bracket = Some((first_char, symbol.range()));
+                    }
+                    self.bracket_depth += 1;
+                }
+                ")" | "]" | "}" => {
+                    self.bracket_depth = self.bracket_depth.saturating_sub(1);
+                    if self.bracket_depth == 0 {
+                        self.open_bracket = None;
+                    }
+                }
+                _ => {}
             }
+            self.source_remaining = self.source_remaining.get_unchecked(symbol_len..source_len);
+            return Some(MacroTokenResult::SymbolToken(symbol));
         }
 
-        // Must have hit end of source str. Source string is assumed to end on token boundery.
-        return Some(MacroTokenResult::AlphanumStringToken(
-            self.source_remaining.clone(),
+        // An identifier. It still ends early if the end ident starts inside of it.
+        let ident_end = self.source_remaining.inner_str[..ident_len]
+            .char_indices()
+            .skip(1)
+            .map(|(i, _)| i)
+            .find(|i| self.source_remaining.inner_str[*i..].starts_with(end_ident))
+            .unwrap_or(ident_len);
+        let output = Some(MacroTokenResult::AlphanumStringToken(
+            self.source_remaining.get_unchecked(0..ident_end),
         ));
+        self.source_remaining = self.source_remaining.get_unchecked(ident_end..source_len);
+        output
     }
 }
 
+/// The operators that are tokenized as a single [`MacroTokenResult::SymbolToken`] by default.
+pub const DEFAULT_OPERATORS: &[&str] = &[
+    "<<=", ">>=", "==", "!=", "<=", ">=", "&&", "||", "<<", ">>", "::", "->", "+=", "-=", "*=",
+    "/=", "%=", "&=", "|=", "^=", "++", "--", "##", "...",
+];
+
+/// The idents that open and close a directive, and the operators recognized within one.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct SyntaxSettings<'a> {
     marcro_start_ident: &'a str,
     macro_end_ident: &'a str,
+    operators: &'a [&'a str],
+}
+
+impl<'a> SyntaxSettings<'a> {
+    /// Replaces the table of multi-char operators.
+    ///
+    /// Operators are matched maximal-munch: the longest operator matching the source wins.
+    /// Symbols that start no operator are tokenized char-by-char.
+    pub fn with_operators(self, operators: &'a [&'a str]) -> Self {
+        SyntaxSettings { operators, ..self }
+    }
 }
 
 impl<'a> Default for SyntaxSettings<'a> {
@@ -99,21 +164,243 @@ impl<'a> Default for SyntaxSettings<'a> {
         SyntaxSettings {
             marcro_start_ident: "#",
             macro_end_ident: ";",
+            operators: DEFAULT_OPERATORS,
         }
     }
 }
 
+/// Iterates over the directive tokens of a whole WGSL source.
+///
+/// Directives live in `//` comments that start a line, with the start ident as the first
+/// non-whitespace text of the comment. Each directive yields its start ident as a
+/// [`MacroTokenResult::SymbolToken`], then its tokens, then a [`MacroTokenResult::Terminator`].
+/// A directive may continue over the following comment lines until the end ident is found. The
+/// end ident does not end the directive inside of `()`, `[]` or `{}`. The rest of a comment line
+/// after the end ident is an ordinary comment.
+///
+/// A directive that reaches a line without a comment, even a blank one, before its end ident
+/// yields a [`MacroTokenErrorKind::UnterminatedDirective`] error in place of a terminator.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct TotalMacroTokenIter<'a, 'b> {
     source_remaining: LocatedStr<'a>,
     maybe_current_cpp_comment_iter: Option<CppCommentIter<'a, 'b>>,
     current_macro_token_iter: LocatedStrMacroTokenIter<'a, 'b>,
     syntax_settings: SyntaxSettings<'b>,
+    // (start ident location, end of the last comment line) of the directive being tokenized.
+    maybe_open_directive: Option<(TextLocation, TextLocation)>,
+}
+
+impl<'a, 'b> TotalMacroTokenIter<'a, 'b> {
+    /// Creates an iterator over the directives in `source`.
+    pub fn new(source: LocatedStr<'a>, syntax_settings: SyntaxSettings<'b>) -> Self {
+        let end_location = source.end_location();
+        TotalMacroTokenIter {
+            source_remaining: source,
+            maybe_current_cpp_comment_iter: None,
+            current_macro_token_iter: LocatedStrMacroTokenIter::new(
+                LocatedStr::from(("", end_location)),
+                syntax_settings,
+            ),
+            syntax_settings,
+            maybe_open_directive: None,
+        }
+    }
+
+    /// Creates an iterator over the directives in `source` using the default syntax.
+    pub fn new_with_default_syntax(source: LocatedStr<'a>) -> TotalMacroTokenIter<'a, 'static> {
+        TotalMacroTokenIter::new(source, SyntaxSettings::default())
+    }
+
+    // Moves `source_remaining` to the next line that starts with a comment and returns an iterator
+    // over that comment block.
+    fn next_comment_block(&mut self) -> Option<CppCommentIter<'a, 'b>> {
+        loop {
+            self.source_remaining = self.source_remaining.trim_start();
+            let remaining_len = self.source_remaining.inner_str.len();
+            if remaining_len == 0 {
+                return None;
+            }
+            if self.source_remaining.inner_str.starts_with("//") {
+                return Some(CppCommentIter::new(
+                    self.source_remaining,
+                    self.syntax_settings,
+                ));
+            }
+            let next_line_idx = self
+                .source_remaining
+                .inner_str
+                .find('\n')
+                .map(|idx| idx + 1)
+                .unwrap_or(remaining_len);
+            self.source_remaining = self
+                .source_remaining
+                .get_unchecked(next_line_idx..remaining_len);
+        }
+    }
+}
+
+impl<'a, 'b> Iterator for TotalMacroTokenIter<'a, 'b> {
+    type Item = MacroTokenResult<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.current_macro_token_iter.next() {
+                if let MacroTokenResult::Terminator(range) = token {
+                    self.maybe_open_directive = None;
+                    // The rest of the line is an ordinary comment.
+                    self.current_macro_token_iter = LocatedStrMacroTokenIter::new(
+                        LocatedStr::from(("", range.end)),
+                        self.syntax_settings,
+                    );
+                }
+                return Some(token);
+            }
+
+            let comment_iter = match &mut self.maybe_current_cpp_comment_iter {
+                Some(comment_iter) => comment_iter,
+                None => {
+                    let comment_iter = self.next_comment_block()?;
+                    self.maybe_current_cpp_comment_iter.insert(comment_iter)
+                }
+            };
+
+            let Some(comment_line) = comment_iter.next() else {
+                // The comment block ended.
+                self.source_remaining = *comment_iter.source_remaining();
+                self.maybe_current_cpp_comment_iter = None;
+                if let Some((start, last_line_end)) = self.maybe_open_directive.take() {
+                    // An unclosed bracket is why the end ident was not found, if there is one.
+                    let error = match self.current_macro_token_iter.open_bracket {
+                        Some((bracket, range)) => MacroTokenError {
+                            kind: MacroTokenErrorKind::UnclosedBracket(bracket),
+                            range,
+                        },
+                        None => MacroTokenError {
+                            kind: MacroTokenErrorKind::UnterminatedDirective,
+                            range: TextRange::new(start, last_line_end),
+                        },
+                    };
+                    return Some(MacroTokenResult::Error(error));
+                }
+                continue;
+            };
+
+            match &mut self.maybe_open_directive {
+                Some((_, last_line_end)) => {
+                    *last_line_end = comment_line.end_location();
+                    let previous_line = core::mem::replace(
+                        &mut self.current_macro_token_iter,
+                        LocatedStrMacroTokenIter::new(comment_line, self.syntax_settings),
+                    );
+                    self.current_macro_token_iter.bracket_depth = previous_line.bracket_depth;
+                    self.current_macro_token_iter.open_bracket = previous_line.open_bracket;
+                }
+                None => {
+                    let comment_line = comment_line.trim_start();
+                    if comment_line
+                        .inner_str
+                        .starts_with(self.syntax_settings.marcro_start_ident)
+                    {
+                        self.maybe_open_directive =
+                            Some((comment_line.start_location, comment_line.end_location()));
+                        self.current_macro_token_iter =
+                            LocatedStrMacroTokenIter::new(comment_line, self.syntax_settings);
+                    }
+                }
+            }
+        }
+    }
 }
 
+/// A single token of a directive.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum MacroTokenResult<'a> {
+    /// An identifier, following WGSL's rules for identifier chars.
     AlphanumStringToken(LocatedStr<'a>),
+    /// An operator from [`SyntaxSettings`] or a single non-alphanumeric char.
     SymbolToken(LocatedStr<'a>),
-    Terminator(TextLocation),
+    /// A double-quoted string literal.
+    StringLiteralToken(StringLiteral<'a>),
+    /// An integer or float literal.
+    NumberToken(NumberLiteral<'a>),
+    /// The end of a directive, covering the end ident.
+    Terminator(TextRange),
+    /// Text that could not be tokenized. Tokenizing continues after it.
+    Error(MacroTokenError),
+}
+
+impl<'a> MacroTokenResult<'a> {
+    /// Returns the range of source text the token was made from.
+    pub fn range(&self) -> TextRange {
+        match self {
+            MacroTokenResult::AlphanumStringToken(s) | MacroTokenResult::SymbolToken(s) => {
+                s.range()
+            }
+            MacroTokenResult::StringLiteralToken(literal) => literal.source.range(),
+            MacroTokenResult::NumberToken(literal) => literal.source.range(),
+            MacroTokenResult::Terminator(range) => *range,
+            MacroTokenResult::Error(error) => error.range,
+        }
+    }
+}
+
+/// An error produced while tokenizing a directive.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct MacroTokenError {
+    /// What went wrong.
+    pub kind: MacroTokenErrorKind,
+    /// The offending text.
+    pub range: TextRange,
+}
+
+impl Display for MacroTokenError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.kind,
+            self.range.start.line_num + 1,
+            self.range.start.col_num + 1
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for MacroTokenError {}
+
+/// The kinds of [`MacroTokenError`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MacroTokenErrorKind {
+    /// A string literal has no closing quote before the end of the directive's line.
+    UnterminatedStringLiteral {
+        /// The location of the opening quote.
+        opening_quote: TextLocation,
+    },
+    /// A backslash in a string literal is not followed by a supported escape.
+    InvalidEscape,
+    /// Text that starts like a number is not a valid WGSL numeric literal.
+    MalformedNumber,
+    /// A numeric literal does not fit in the type given by its suffix.
+    NumberOutOfRange,
+    /// A directive reaches non-comment code before its end ident.
+    UnterminatedDirective,
+    /// A directive reaches non-comment code while this bracket is still open.
+    UnclosedBracket(char),
+}
+
+impl Display for MacroTokenErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MacroTokenErrorKind::UnterminatedStringLiteral { .. } => {
+                write!(f, "unterminated string literal")
+            }
+            MacroTokenErrorKind::InvalidEscape => write!(f, "invalid escape in string literal"),
+            MacroTokenErrorKind::MalformedNumber => write!(f, "malformed numeric literal"),
+            MacroTokenErrorKind::NumberOutOfRange => write!(f, "numeric literal out of range"),
+            MacroTokenErrorKind::UnterminatedDirective => {
+                write!(f, "directive is not terminated before the comment ends")
+            }
+            MacroTokenErrorKind::UnclosedBracket(bracket) => write!(f, "unclosed `{bracket}`"),
+        }
+    }
 }
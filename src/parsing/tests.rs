@@ -1,8 +1,13 @@
-use super::{*, utils::*};
+use super::{utils::*, *};
+
+fn range(start: (u64, u64, u64), end: (u64, u64, u64)) -> TextRange {
+    TextRange::new(start.into(), end.into())
+}
 
 mod cpp_comment_iter {
     use super::*;
 
+    #[allow(clippy::into_iter_on_ref)]
     #[test]
     fn test_iter_impl() {
         let test_cases = [
@@ -51,7 +56,7 @@ mod located_str_macro_token_iter {
 
     #[test]
     fn test_alphanum_and_symbol_tokens() {
-        use MacroTokenResult::{AlphanumStringToken, SymbolToken, Terminator};
+        use MacroTokenResult::{AlphanumStringToken, Error, SymbolToken, Terminator};
 
         let mut iter = LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new(
             "hi! Test+\nLine2 2Line;",
@@ -62,14 +67,47 @@ mod located_str_macro_token_iter {
             AlphanumStringToken(LocatedStr::new_with_loc("Test", 0, 4, 4)),
             SymbolToken(LocatedStr::new_with_loc("+", 0, 8, 8)),
             AlphanumStringToken(LocatedStr::new_with_loc("Line2", 1, 0, 10)),
-            AlphanumStringToken(LocatedStr::new_with_loc("2Line", 1, 6, 16)),
-            Terminator(TextLocation::new(1, 11, 21)),
+            Error(MacroTokenError {
+                kind: MacroTokenErrorKind::MalformedNumber,
+                range: range((1, 6, 16), (1, 11, 21)),
+            }),
+            Terminator(range((1, 11, 21), (1, 12, 22))),
         ];
         let results = core::array::from_fn(|_| iter.next().unwrap());
         assert_eq!(iter.next(), None);
         assert_eq!(results, expected_tokens);
     }
 
+    #[test]
+    fn test_token_ranges() {
+        let tokens: [_; 4] = core::array::from_fn({
+            let mut iter =
+                LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new("ab\n€cd;"));
+            move |_| iter.next().unwrap().range()
+        });
+        assert_eq!(
+            tokens,
+            [
+                range((0, 0, 0), (0, 2, 2)),
+                range((1, 0, 3), (1, 1, 6)),
+                range((1, 1, 6), (1, 3, 8)),
+                range((1, 3, 8), (1, 4, 9)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_source_ends() {
+        let mut iter = LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new("end"));
+        assert_eq!(
+            iter.next(),
+            Some(MacroTokenResult::AlphanumStringToken(LocatedStr::new(
+                "end"
+            )))
+        );
+        assert_eq!(iter.next(), None);
+    }
+
     #[test]
     fn test_empty_source_conditions() {
         let test_cases = &["", " ", "\n", "\r\n"];
@@ -85,10 +123,11 @@ mod located_str_macro_token_iter {
     fn test_find_end_ident_at_start() {
         assert_eq!(
             LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new(";")).next(),
-            Some(MacroTokenResult::Terminator(TextLocation::default()))
+            Some(MacroTokenResult::Terminator(range((0, 0, 0), (0, 1, 1))))
         );
     }
 
+    #[allow(clippy::partialeq_to_none)]
     #[test]
     fn test_get_symbol_token() {
         let mut iter =
@@ -110,7 +149,10 @@ mod located_str_macro_token_iter {
                     start_location: (*line, *col, *byte).into(),
                 })
             } else {
-                MacroTokenResult::Terminator((*line, *col, *byte).into())
+                MacroTokenResult::Terminator(range(
+                    (*line, *col, *byte),
+                    (*line, col + 1, byte + 1),
+                ))
             }
         });
         let results: [_; 7] = core::array::from_fn(|_| iter.next());
@@ -119,3 +161,439 @@ mod located_str_macro_token_iter {
         assert_eq!(results, expected_tokens);
     }
 }
+
+mod string_literals {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<MacroTokenResult<'_>> {
+        LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new(source)).collect()
+    }
+
+    #[test]
+    fn test_string_literal_token() {
+        assert_eq!(
+            tokens(r#"include "path/to/file.wgsl";"#),
+            [
+                MacroTokenResult::AlphanumStringToken(LocatedStr::new("include")),
+                MacroTokenResult::StringLiteralToken(StringLiteral {
+                    source: LocatedStr::new_with_loc(r#""path/to/file.wgsl""#, 0, 8, 8),
+                }),
+                MacroTokenResult::Terminator(range((0, 27, 27), (0, 28, 28))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escapes() {
+        let test_cases = [
+            (r#""plain""#, "plain"),
+            (r#""a \"quote\"""#, "a \"quote\""),
+            (r#""back\\slash""#, "back\\slash"),
+            (r#""new\nline""#, "new\nline"),
+            (r#""\u{41}\u{1F600}""#, "A😀"),
+            (r#""semi;colon""#, "semi;colon"),
+        ];
+        for (source, expected) in test_cases {
+            match tokens(source).as_slice() {
+                [MacroTokenResult::StringLiteralToken(literal)] => {
+                    assert_eq!(literal.value(), expected)
+                }
+                other => panic!("unexpected tokens for {source}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_unterminated() {
+        assert_eq!(
+            tokens(r#"error "oops;"#),
+            [
+                MacroTokenResult::AlphanumStringToken(LocatedStr::new("error")),
+                MacroTokenResult::Error(MacroTokenError {
+                    kind: MacroTokenErrorKind::UnterminatedStringLiteral {
+                        opening_quote: TextLocation::new(0, 6, 6),
+                    },
+                    range: range((0, 6, 6), (0, 12, 12)),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_escapes_keep_literal_boundaries() {
+        let test_cases = [
+            (r#""\q" x;"#, range((0, 1, 1), (0, 3, 3))),
+            (r#""\u{110000}" x;"#, range((0, 1, 1), (0, 11, 11))),
+            (r#""\u{zz}" x;"#, range((0, 1, 1), (0, 5, 5))),
+            (r#""\u{12" x;"#, range((0, 1, 1), (0, 6, 6))),
+        ];
+        for (source, error_range) in test_cases {
+            let results = tokens(source);
+            assert_eq!(
+                results[0],
+                MacroTokenResult::Error(MacroTokenError {
+                    kind: MacroTokenErrorKind::InvalidEscape,
+                    range: error_range,
+                }),
+                "{source}"
+            );
+            assert!(matches!(
+                results[1..],
+                [
+                    MacroTokenResult::AlphanumStringToken(LocatedStr { inner_str: "x", .. }),
+                    MacroTokenResult::Terminator(_),
+                ]
+            ));
+        }
+    }
+}
+
+mod number_literals {
+    use super::*;
+
+    fn single_token(source: &str) -> MacroTokenResult<'_> {
+        let mut iter = LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new(source));
+        let output = iter.next().unwrap();
+        assert_eq!(iter.next(), None, "{source} is more than one token");
+        output
+    }
+
+    #[test]
+    fn test_valid_numbers() {
+        use NumberValue::*;
+
+        let test_cases = [
+            ("42", AbstractInt(42)),
+            ("0", AbstractInt(0)),
+            ("7i", I32(7)),
+            ("4294967295u", U32(u32::MAX)),
+            ("0x1F", AbstractInt(31)),
+            ("0Xffu", U32(255)),
+            ("1.5", AbstractFloat(1.5)),
+            (".25", AbstractFloat(0.25)),
+            ("3.", AbstractFloat(3.0)),
+            ("1e3", AbstractFloat(1000.0)),
+            ("2.5e-1f", F32(0.25)),
+            ("0f", F32(0.0)),
+            ("12h", F16(12.0)),
+            ("0x1.8", AbstractFloat(1.5)),
+            ("0x1.8p1", AbstractFloat(3.0)),
+            ("0x1p-2f", F32(0.25)),
+        ];
+        for (source, expected) in test_cases {
+            assert_eq!(
+                single_token(source),
+                MacroTokenResult::NumberToken(NumberLiteral {
+                    source: LocatedStr::new(source),
+                    value: expected,
+                }),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_numbers() {
+        let test_cases = [
+            ("2Line", MacroTokenErrorKind::MalformedNumber),
+            ("007", MacroTokenErrorKind::MalformedNumber),
+            ("1e", MacroTokenErrorKind::MalformedNumber),
+            ("1.5u", MacroTokenErrorKind::MalformedNumber),
+            ("0x", MacroTokenErrorKind::MalformedNumber),
+            ("4294967296u", MacroTokenErrorKind::NumberOutOfRange),
+            ("2147483648i", MacroTokenErrorKind::NumberOutOfRange),
+            ("70000h", MacroTokenErrorKind::NumberOutOfRange),
+            ("0x1ffffffffffffffff", MacroTokenErrorKind::NumberOutOfRange),
+        ];
+        for (source, expected_kind) in test_cases {
+            assert_eq!(
+                single_token(source),
+                MacroTokenResult::Error(MacroTokenError {
+                    kind: expected_kind,
+                    range: LocatedStr::new(source).range(),
+                }),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_numbers_between_tokens() {
+        let results: Vec<_> =
+            LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new("if 1e-3 <2u;"))
+                .map(|token| token.range().byte_range())
+                .collect();
+        assert_eq!(results, [0..2, 3..7, 8..9, 9..11, 11..12]);
+    }
+}
+
+mod operators {
+    use super::*;
+
+    fn symbols<'a>(source: &'a str, syntax_settings: SyntaxSettings<'_>) -> Vec<&'a str> {
+        LocatedStrMacroTokenIter::new(LocatedStr::new(source), syntax_settings)
+            .filter_map(|token| match token {
+                MacroTokenResult::SymbolToken(s) => Some(s.inner_str),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_maximal_munch() {
+        assert_eq!(
+            symbols(
+                "a==b != c<<=d ::e <<< = = &&|| !",
+                SyntaxSettings::default()
+            ),
+            ["==", "!=", "<<=", "::", "<<", "<", "=", "=", "&&", "||", "!"]
+        );
+        assert_eq!(
+            symbols("a ## b ### #", SyntaxSettings::default()),
+            ["##", "##", "#", "#"]
+        );
+    }
+
+    #[test]
+    fn test_custom_operator_table() {
+        let syntax_settings = SyntaxSettings::default().with_operators(&["=>", "==="]);
+        assert_eq!(
+            symbols("a===b==c=>d", syntax_settings),
+            ["===", "=", "=", "=>"]
+        );
+    }
+
+    #[test]
+    fn test_operator_ranges() {
+        let mut iter =
+            LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new("x <=\n>>"));
+        iter.next();
+        assert_eq!(
+            iter.next(),
+            Some(MacroTokenResult::SymbolToken(LocatedStr::new_with_loc(
+                "<=", 0, 2, 2
+            )))
+        );
+        assert_eq!(
+            iter.next().map(|token| token.range()),
+            Some(range((1, 0, 5), (1, 2, 7)))
+        );
+    }
+}
+
+mod identifiers {
+    use super::*;
+
+    fn token_strs(source: &str) -> Vec<(&str, bool)> {
+        LocatedStrMacroTokenIter::new_with_default_syntax(LocatedStr::new(source))
+            .map(|token| match token {
+                MacroTokenResult::AlphanumStringToken(s) => (s.inner_str, true),
+                MacroTokenResult::SymbolToken(s) => (s.inner_str, false),
+                other => panic!("unexpected token {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_underscores() {
+        assert_eq!(
+            token_strs("my_define _private a_ _ __x"),
+            [
+                ("my_define", true),
+                ("_private", true),
+                ("a_", true),
+                ("_", false),
+                ("__x", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unicode_identifiers() {
+        assert_eq!(
+            token_strs("été Δx2 x😀y"),
+            [
+                ("été", true),
+                ("Δx2", true),
+                ("x", true),
+                ("😀", false),
+                ("y", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lone_underscore_and_emoji_stay_symbols() {
+        assert_eq!(
+            token_strs("😀_ _😀"),
+            [("😀", false), ("_", false), ("_", false), ("😀", false)]
+        );
+    }
+
+    #[test]
+    fn test_validate_wgsl_identifier() {
+        let test_cases = [
+            ("my_define", Ok(())),
+            ("_x", Ok(())),
+            ("été", Ok(())),
+            ("", Err(IdentifierError::Empty)),
+            ("_", Err(IdentifierError::InvalidChars)),
+            ("2x", Err(IdentifierError::InvalidChars)),
+            ("a-b", Err(IdentifierError::InvalidChars)),
+            ("__x", Err(IdentifierError::DoubleUnderscorePrefix)),
+            ("fn", Err(IdentifierError::Keyword)),
+            ("const_assert", Err(IdentifierError::Keyword)),
+            ("module", Err(IdentifierError::ReservedWord)),
+        ];
+        for (ident, expected) in test_cases {
+            assert_eq!(validate_wgsl_identifier(ident), expected, "{ident}");
+        }
+    }
+}
+
+mod total_macro_token_iter {
+    use super::*;
+
+    fn token_strs(source: &str) -> Vec<(&str, TextLocation)> {
+        TotalMacroTokenIter::new_with_default_syntax(LocatedStr::new(source))
+            .map(|token| match token {
+                MacroTokenResult::AlphanumStringToken(s) | MacroTokenResult::SymbolToken(s) => {
+                    (s.inner_str, s.start_location)
+                }
+                MacroTokenResult::NumberToken(n) => (n.source.inner_str, n.source.start_location),
+                MacroTokenResult::Terminator(range) => (";", range.start),
+                other => panic!("unexpected token {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_directives_between_code() {
+        let source = "// Just a comment\n\
+                      // #define A 1; trailing comment\n\
+                      fn main() {} // #define NOT_A_DIRECTIVE;\n\
+                      \t//#ifdef A;\n";
+        assert_eq!(
+            token_strs(source),
+            [
+                ("#", (1, 3, 21).into()),
+                ("define", (1, 4, 22).into()),
+                ("A", (1, 11, 29).into()),
+                ("1", (1, 13, 31).into()),
+                (";", (1, 14, 32).into()),
+                ("#", (3, 3, 95).into()),
+                ("ifdef", (3, 4, 96).into()),
+                ("A", (3, 10, 102).into()),
+                (";", (3, 11, 103).into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directive_continues_over_comment_lines() {
+        let source = "// #if A &&\n//     B\n  // || C;\n// #else;";
+        assert_eq!(
+            token_strs(source),
+            [
+                ("#", (0, 3, 3).into()),
+                ("if", (0, 4, 4).into()),
+                ("A", (0, 7, 7).into()),
+                ("&&", (0, 9, 9).into()),
+                ("B", (1, 7, 19).into()),
+                ("||", (2, 5, 26).into()),
+                ("C", (2, 8, 29).into()),
+                (";", (2, 9, 30).into()),
+                ("#", (3, 3, 35).into()),
+                ("else", (3, 4, 36).into()),
+                (";", (3, 8, 40).into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blank_line_ends_directive() {
+        let source = "// #if A &&\n//     B\n\n// || C;\n// #else;";
+        let results: Vec<_> =
+            TotalMacroTokenIter::new_with_default_syntax(LocatedStr::new(source)).collect();
+        assert_eq!(results.len(), 9);
+        assert_eq!(
+            results[5],
+            MacroTokenResult::Error(MacroTokenError {
+                kind: MacroTokenErrorKind::UnterminatedDirective,
+                range: range((0, 3, 3), (1, 8, 20)),
+            })
+        );
+        // `|| C;` is an ordinary comment.
+        assert_eq!(
+            results[6],
+            MacroTokenResult::SymbolToken(LocatedStr::new_with_loc("#", 4, 3, 34))
+        );
+    }
+
+    #[test]
+    fn test_end_ident_inside_brackets() {
+        let source = "// #define F(x) fn f() {\n//   return x; };";
+        let strs: Vec<_> = token_strs(source).into_iter().map(|(s, _)| s).collect();
+        assert_eq!(
+            strs,
+            [
+                "#", "define", "F", "(", "x", ")", "fn", "f", "(", ")", "{", "return", "x", ";",
+                "}", ";"
+            ]
+        );
+        let results: Vec<_> =
+            TotalMacroTokenIter::new_with_default_syntax(LocatedStr::new(source)).collect();
+        assert!(matches!(results[13], MacroTokenResult::SymbolToken(_)));
+        assert!(matches!(results[15], MacroTokenResult::Terminator(_)));
+    }
+
+    #[test]
+    fn test_unterminated_directive_before_code() {
+        let source = "// #define A\n//   1\nfn main() {}\n// #define B;";
+        let results: Vec<_> =
+            TotalMacroTokenIter::new_with_default_syntax(LocatedStr::new(source)).collect();
+        assert_eq!(results.len(), 9);
+        assert_eq!(
+            results[4],
+            MacroTokenResult::Error(MacroTokenError {
+                kind: MacroTokenErrorKind::UnterminatedDirective,
+                range: range((0, 3, 3), (1, 6, 19)),
+            })
+        );
+        assert_eq!(
+            results[5],
+            MacroTokenResult::SymbolToken(LocatedStr::new_with_loc("#", 3, 3, 36))
+        );
+    }
+
+    #[test]
+    fn test_unclosed_bracket() {
+        let source = "// #if (A && [B]\n//     || C;\nfn main() {}\n// #endif;";
+        let results: Vec<_> =
+            TotalMacroTokenIter::new_with_default_syntax(LocatedStr::new(source)).collect();
+        assert_eq!(
+            results[11],
+            MacroTokenResult::Error(MacroTokenError {
+                kind: MacroTokenErrorKind::UnclosedBracket('('),
+                range: range((0, 7, 7), (0, 8, 8)),
+            })
+        );
+        assert_eq!(
+            results[12],
+            MacroTokenResult::SymbolToken(LocatedStr::new_with_loc("#", 3, 3, 46))
+        );
+    }
+
+    #[test]
+    fn test_unterminated_directive_at_end_of_source() {
+        let results: Vec<_> =
+            TotalMacroTokenIter::new_with_default_syntax(LocatedStr::new("// #endif")).collect();
+        assert!(matches!(
+            results.last(),
+            Some(MacroTokenResult::Error(MacroTokenError {
+                kind: MacroTokenErrorKind::UnterminatedDirective,
+                ..
+            }))
+        ));
+    }
+}
@@ -1,5 +1,5 @@
-use crate::utils::{LocatedStr,find_any_substring};
 use super::SyntaxSettings;
+use crate::utils::{find_any_substring, LocatedStr};
 
 // Stops when it encounters end or something that isn't a c++ style comment.
 // Expects first non-whitespace chars to be //
@@ -10,13 +10,21 @@ pub(crate) struct CppCommentIter<'a, 'b> {
 }
 
 impl<'a, 'b> CppCommentIter<'a, 'b> {
-    pub(crate) fn new_with_default_syntax(source: LocatedStr<'a>) -> CppCommentIter<'a, 'static> {
+    pub(crate) fn new(
+        source: LocatedStr<'a>,
+        syntax_settings: SyntaxSettings<'b>,
+    ) -> CppCommentIter<'a, 'b> {
         CppCommentIter {
             source_remaining: source,
-            syntax_settings: SyntaxSettings::default(),
+            syntax_settings,
         }
     }
 
+    #[cfg(test)]
+    pub(crate) fn new_with_default_syntax(source: LocatedStr<'a>) -> CppCommentIter<'a, 'static> {
+        CppCommentIter::new(source, SyntaxSettings::default())
+    }
+
     pub(crate) fn source_remaining(&self) -> &LocatedStr<'a> {
         &self.source_remaining
     }
@@ -26,10 +34,14 @@ impl<'a, 'b> Iterator for CppCommentIter<'a, 'b> {
     type Item = LocatedStr<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.source_remaining = self.source_remaining.trim_start();
-        if self.source_remaining.inner_str.get(..2) != Some("//") {
+        // A line without a comment, even a blank one, ends the block.
+        let trimmed = self.source_remaining.trim_start();
+        let skipped = &self.source_remaining.inner_str
+            [..self.source_remaining.inner_str.len() - trimmed.inner_str.len()];
+        if skipped.contains('\n') || trimmed.inner_str.get(..2) != Some("//") {
             return None;
         }
+        self.source_remaining = trimmed;
         self.source_remaining = self
             .source_remaining
             .get_unchecked(2..self.source_remaining.inner_str.len());
@@ -0,0 +1,284 @@
+use super::files::Fnv1a;
+use super::{content_hash, PreprocessOutput};
+use crate::macros::{Directive, DirectiveIter, DirectiveParseError};
+use crate::parsing::{
+    MacroTokenError, MacroTokenResult, NumberLiteral, NumberValue, StringLiteral, SyntaxSettings,
+};
+use crate::utils::{LocatedStr, TextRange};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+
+/// Keeps preprocessed outputs between runs, so that only the outputs affected by a change are
+/// preprocessed again.
+///
+/// Used with [`Preprocessor::preprocess_cached`](super::Preprocessor::preprocess_cached). Outputs
+/// are keyed by the path they were preprocessed from and by the preprocessor's defines and
+/// settings, and remember the content hash of every file they read. An output is reused as long as
+/// the resolver still finds the same contents for all of those files. The tokens of the directives
+/// in each file are also kept by content hash, so unchanged files are not tokenized again when an
+/// output that reads them is preprocessed again.
+///
+/// Custom directive handlers are assumed to always produce the same code for the same directive. A
+/// file that would shadow an included one, such as in an earlier include directory, is only
+/// noticed once the output is invalidated.
+#[derive(Clone, Debug, Default)]
+pub struct PreprocessCache {
+    outputs: BTreeMap<(String, u64), CachedOutput>,
+    pub(super) directives: DirectiveCache,
+}
+
+#[derive(Clone, Debug)]
+struct CachedOutput {
+    output: Arc<PreprocessOutput>,
+    // The path and content hash of every file that was read.
+    dependencies: Vec<(String, u64)>,
+}
+
+impl PreprocessCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets the outputs that read the file at `path`, as resolved. Returns the paths that the
+    /// forgotten outputs were preprocessed from, which are the ones to preprocess again.
+    pub fn invalidate(&mut self, path: &str) -> Vec<String> {
+        let mut affected = BTreeSet::new();
+        self.outputs.retain(|(root, _), cached| {
+            let reads_path = cached.dependencies.iter().any(|(dep, _)| dep == path);
+            if reads_path {
+                affected.insert(root.clone());
+            }
+            !reads_path
+        });
+        self.prune_directives();
+        affected.into_iter().collect()
+    }
+
+    /// Forgets everything.
+    pub fn clear(&mut self) {
+        self.outputs.clear();
+        self.directives.files.clear();
+    }
+
+    /// Returns the number of cached outputs.
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// Returns true if no output is cached.
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    // Returns the output for `key` if `content_hash` still gives the same hash for every file it
+    // read.
+    pub(super) fn get(
+        &self,
+        key: &(String, u64),
+        mut content_hash: impl FnMut(&str) -> Option<u64>,
+    ) -> Option<Arc<PreprocessOutput>> {
+        let cached = self.outputs.get(key)?;
+        cached
+            .dependencies
+            .iter()
+            .all(|(path, hash)| content_hash(path) == Some(*hash))
+            .then(|| cached.output.clone())
+    }
+
+    pub(super) fn insert(
+        &mut self,
+        key: (String, u64),
+        output: PreprocessOutput,
+    ) -> Arc<PreprocessOutput> {
+        let dependencies = output
+            .files
+            .iter()
+            .map(|(_, file)| (file.path.clone(), content_hash(&file.source)))
+            .collect();
+        let output = Arc::new(output);
+        self.outputs.insert(
+            key,
+            CachedOutput {
+                output: output.clone(),
+                dependencies,
+            },
+        );
+        self.prune_directives();
+        output
+    }
+
+    // Forgets the directives of the files that no cached output read, like older versions of
+    // edited files.
+    fn prune_directives(&mut self) {
+        let used: BTreeSet<u64> = self
+            .outputs
+            .values()
+            .flat_map(|cached| cached.dependencies.iter().map(|(_, hash)| *hash))
+            .collect();
+        self.directives
+            .files
+            .retain(|_, file| used.contains(&file.content_hash));
+    }
+}
+
+// The directives found in files, by the hash of their contents and the syntax settings.
+#[derive(Clone, Debug, Default)]
+pub(super) struct DirectiveCache {
+    files: BTreeMap<u64, ScannedFile>,
+}
+
+#[derive(Clone, Debug)]
+struct ScannedFile {
+    content_hash: u64,
+    directives: Arc<ScannedDirectives>,
+}
+
+// The token streams of the directives of a file, kept so that they can be parsed without
+// tokenizing the file again.
+#[derive(Debug)]
+pub(super) struct ScannedDirectives {
+    source: Arc<str>,
+    directives: Vec<Vec<ScannedToken>>,
+}
+
+// A `MacroTokenResult` whose text is left in the source.
+#[derive(Clone, Copy, Debug)]
+enum ScannedToken {
+    Alphanum(TextRange),
+    Symbol(TextRange),
+    StringLiteral(TextRange),
+    Number(TextRange, NumberValue),
+    Terminator(TextRange),
+    Error(MacroTokenError),
+}
+
+impl DirectiveCache {
+    // Returns the directives of `source`, tokenizing it if it was not tokenized before.
+    pub(super) fn scan(
+        &mut self,
+        source: &Arc<str>,
+        syntax_settings: SyntaxSettings<'_>,
+    ) -> Arc<ScannedDirectives> {
+        let content_hash = content_hash(source);
+        let key = hash_of(&(content_hash, syntax_settings));
+        if let Some(file) = self
+            .files
+            .get(&key)
+            .filter(|file| file.directives.source == *source)
+        {
+            return file.directives.clone();
+        }
+        let directives = Arc::new(ScannedDirectives::new(source.clone(), syntax_settings));
+        self.files.insert(
+            key,
+            ScannedFile {
+                content_hash,
+                directives: directives.clone(),
+            },
+        );
+        directives
+    }
+}
+
+impl ScannedDirectives {
+    fn new(source: Arc<str>, syntax_settings: SyntaxSettings<'_>) -> Self {
+        let mut iter = DirectiveIter::new(LocatedStr::new(&source), syntax_settings);
+        let directives = core::iter::from_fn(|| iter.next_tokens())
+            .map(|tokens| tokens.iter().map(ScannedToken::new).collect())
+            .collect();
+        ScannedDirectives { source, directives }
+    }
+
+    // Parses the directives from their tokens.
+    pub(super) fn directives(
+        &self,
+    ) -> impl Iterator<Item = Result<Directive<'_>, DirectiveParseError>> + '_ {
+        self.directives.iter().map(|tokens| {
+            let tokens: Vec<_> = tokens
+                .iter()
+                .map(|token| token.to_token(&self.source))
+                .collect();
+            Directive::parse(&tokens)
+        })
+    }
+}
+
+impl ScannedToken {
+    fn new(token: &MacroTokenResult<'_>) -> Self {
+        match token {
+            MacroTokenResult::AlphanumStringToken(s) => ScannedToken::Alphanum(s.range()),
+            MacroTokenResult::SymbolToken(s) => ScannedToken::Symbol(s.range()),
+            MacroTokenResult::StringLiteralToken(literal) => {
+                ScannedToken::StringLiteral(literal.source.range())
+            }
+            MacroTokenResult::NumberToken(literal) => {
+                ScannedToken::Number(literal.source.range(), literal.value)
+            }
+            MacroTokenResult::Terminator(range) => ScannedToken::Terminator(*range),
+            MacroTokenResult::Error(error) => ScannedToken::Error(*error),
+        }
+    }
+
+    // Gives the token back its text from `source`, the source it was tokenized from.
+    fn to_token(self, source: &str) -> MacroTokenResult<'_> {
+        let located = |range: TextRange| LocatedStr {
+            inner_str: &source[range.byte_range()],
+            start_location: range.start,
+        };
+        match self {
+            ScannedToken::Alphanum(range) => MacroTokenResult::AlphanumStringToken(located(range)),
+            ScannedToken::Symbol(range) => MacroTokenResult::SymbolToken(located(range)),
+            ScannedToken::StringLiteral(range) => {
+                MacroTokenResult::StringLiteralToken(StringLiteral {
+                    source: located(range),
+                })
+            }
+            ScannedToken::Number(range, value) => MacroTokenResult::NumberToken(NumberLiteral {
+                source: located(range),
+                value,
+            }),
+            ScannedToken::Terminator(range) => MacroTokenResult::Terminator(range),
+            ScannedToken::Error(error) => MacroTokenResult::Error(error),
+        }
+    }
+}
+
+// Hashes `value`, such as the settings of a preprocessor.
+pub(super) fn hash_of(value: &impl Hash) -> u64 {
+    let mut hasher = Fnv1a::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scanned_directives() {
+        let source = "const A = 1;\n// #define X 1; #define Y\n//     2;\n\
+                      // #ifdef X;\n  // #bogus(;\nfn f() {}\n// #if 0x1u;\n// #endif; #endif;";
+        let settings = SyntaxSettings::default();
+        let scanned = ScannedDirectives::new(Arc::from(source), settings);
+        let replayed: Vec<_> = scanned.directives().collect();
+        let direct: Vec<_> = DirectiveIter::new(LocatedStr::new(source), settings).collect();
+        assert_eq!(replayed, direct);
+    }
+
+    #[test]
+    fn test_unchanged_source_is_not_scanned_again() {
+        let mut cache = DirectiveCache::default();
+        let settings = SyntaxSettings::default();
+        let source: Arc<str> = Arc::from("// #define X 1;\nfn f() {}");
+        let first = cache.scan(&source, settings);
+        // The same contents, read again.
+        let second = cache.scan(&Arc::from(&*source), settings);
+        assert!(Arc::ptr_eq(&first, &second));
+        let edited = cache.scan(&Arc::from("// #define X 2;\nfn f() {}"), settings);
+        assert!(!Arc::ptr_eq(&first, &edited));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::macros::CustomDirective;
+use crate::utils::TextRange;
+use alloc::string::String;
+
+/// Handles a directive that is not built in, such as `#bindings material;`.
+///
+/// Register handlers with [`Preprocessor::register_directive`](super::Preprocessor). Closures
+/// with the same signature as [`handle`](CustomDirectiveHandler::handle) are handlers.
+pub trait CustomDirectiveHandler {
+    /// Returns the text that replaces the directive.
+    fn handle(
+        &self,
+        directive: &CustomDirective<'_>,
+        context: &DirectiveContext<'_>,
+    ) -> Result<String, CustomDirectiveError>;
+}
+
+impl<F> CustomDirectiveHandler for F
+where
+    F: Fn(&CustomDirective<'_>, &DirectiveContext<'_>) -> Result<String, CustomDirectiveError>,
+{
+    fn handle(
+        &self,
+        directive: &CustomDirective<'_>,
+        context: &DirectiveContext<'_>,
+    ) -> Result<String, CustomDirectiveError> {
+        self(directive, context)
+    }
+}
+
+/// Where a custom directive was found.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DirectiveContext<'a> {
+    /// The path of the file containing the directive.
+    pub path: &'a str,
+}
+
+/// An error returned by a [`CustomDirectiveHandler`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CustomDirectiveError {
+    /// What went wrong.
+    pub message: String,
+    /// The offending part of the directive. The whole directive is reported if this is `None`.
+    pub range: Option<TextRange>,
+}
+
+impl CustomDirectiveError {
+    /// Creates an error about the whole directive.
+    pub fn new(message: impl Into<String>) -> Self {
+        CustomDirectiveError {
+            message: message.into(),
+            range: None,
+        }
+    }
+
+    /// Points the error at part of the directive, such as one of its tokens.
+    pub fn with_range(self, range: TextRange) -> Self {
+        CustomDirectiveError {
+            range: Some(range),
+            ..self
+        }
+    }
+}
+
+impl From<String> for CustomDirectiveError {
+    fn from(message: String) -> Self {
+        CustomDirectiveError::new(message)
+    }
+}
+
+impl From<&str> for CustomDirectiveError {
+    fn from(message: &str) -> Self {
+        CustomDirectiveError::new(message)
+    }
+}
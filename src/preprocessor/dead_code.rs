@@ -0,0 +1,71 @@
+use super::source_map::{self, Span};
+use crate::utils::LocatedStr;
+use crate::wgsl::{scan_declarations, Declaration, DeclarationKind};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Removes the `fn`, `const`, `struct` and `alias` declarations of `code` that are not used by the
+// roots, directly or through other declarations. Declarations of other kinds are always roots.
+//
+// A removed declaration takes its first and last lines with it if only whitespace remains there.
+// With `line_preserving`, its lines are replaced with blank lines instead.
+pub(crate) fn eliminate_dead_code(
+    code: &str,
+    spans: Vec<Span>,
+    is_root: impl Fn(&Declaration<'_>) -> bool,
+    line_preserving: bool,
+) -> (String, Vec<Span>) {
+    let declarations = scan_declarations(LocatedStr::new(code));
+    let removable = |declaration: &Declaration<'_>| {
+        matches!(
+            declaration.kind,
+            DeclarationKind::Function
+                | DeclarationKind::Const
+                | DeclarationKind::Struct
+                | DeclarationKind::Alias
+        )
+    };
+    let mut by_name = BTreeMap::new();
+    for (idx, declaration) in declarations.iter().enumerate() {
+        if let Some(name) = declaration.name {
+            by_name.entry(name.inner_str).or_insert(idx);
+        }
+    }
+
+    let mut used = vec![false; declarations.len()];
+    let mut pending: Vec<usize> = (0..declarations.len())
+        .filter(|&idx| !removable(&declarations[idx]) || is_root(&declarations[idx]))
+        .collect();
+    while let Some(idx) = pending.pop() {
+        if core::mem::replace(&mut used[idx], true) {
+            continue;
+        }
+        for reference in &declarations[idx].references {
+            pending.extend(by_name.get(reference.inner_str));
+        }
+    }
+
+    let mut removals = Vec::new();
+    for (declaration, _) in declarations.iter().zip(&used).filter(|(_, used)| !**used) {
+        let mut start = declaration.text.start_location.byte_num as usize;
+        let mut end = start + declaration.text.inner_str.len();
+        let line_start = code[..start].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = code[end..]
+            .find('\n')
+            .map_or(code.len(), |idx| end + idx + 1);
+        if code[end..line_end].trim().is_empty() {
+            end = line_end;
+            if code[line_start..start].trim().is_empty() {
+                start = line_start;
+            }
+        }
+        let replacement = match line_preserving {
+            true => "\n".repeat(code[start..end].matches('\n').count()),
+            false => String::new(),
+        };
+        removals.push((start..end, replacement));
+    }
+    source_map::replace_ranges(code, spans, &removals, false)
+}
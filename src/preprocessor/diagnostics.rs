@@ -0,0 +1,83 @@
+use super::{BacktraceFrame, SourceLocation};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Display;
+
+/// A problem that did not stop preprocessing.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Diagnostic {
+    /// What the problem is.
+    pub kind: DiagnosticKind,
+    /// Where the problem is.
+    pub location: Option<Box<SourceLocation>>,
+    /// The macro invocations, includes and imports that brought the problem into the output,
+    /// innermost first.
+    pub backtrace: Vec<BacktraceFrame>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{location}: warning: {}", self.kind)?,
+            None => write!(f, "warning: {}", self.kind)?,
+        }
+        for frame in &self.backtrace {
+            write!(f, "\n  {frame}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The kinds of [`Diagnostic`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum DiagnosticKind {
+    /// A `#warning` directive was reached.
+    WarningDirective(String),
+    /// In [`OutputMode::LinePreserving`](super::OutputMode::LinePreserving), the file at this
+    /// path was included inside braces, so it stays in place and shifts the lines after it.
+    IncludeShiftsLines(String),
+}
+
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DiagnosticKind::WarningDirective(message) => write!(f, "#warning: {message}"),
+            DiagnosticKind::IncludeShiftsLines(path) => write!(
+                f,
+                "\"{path}\" is included inside braces, so it shifts the lines after it"
+            ),
+        }
+    }
+}
+
+/// The warnings collected while preprocessing, in the order they were found.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Diagnostics {
+    warnings: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    /// Returns the collected warnings.
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    /// Returns `true` if there are no warnings.
+    pub fn is_empty(&self) -> bool {
+        self.warnings.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, diagnostic: Diagnostic) {
+        self.warnings.push(diagnostic);
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = core::slice::Iter<'a, Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.warnings.iter()
+    }
+}
@@ -0,0 +1,750 @@
+use super::{FileDatabase, FileId, PendingError, PreprocessErrorKind};
+use crate::parsing::{validate_wgsl_identifier, MacroTokenResult};
+use crate::utils::{LocatedStr, TextRange};
+use crate::wgsl::{TokenIter, TokenKind};
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// Where a token comes from. `file` is `None` for macros defined through the API.
+// `included_from` is the `#include` or `#import` directive that `file` was reached through, and
+// `expansion` the macro invocation that produced the token, if any.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct Location {
+    pub(crate) file: Option<FileId>,
+    pub(crate) range: TextRange,
+    pub(crate) included_from: Option<Rc<IncludeSite>>,
+    pub(crate) expansion: Option<Rc<Expansion>>,
+}
+
+// An `#include` or `#import` directive that a file was reached through.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct IncludeSite {
+    pub(crate) location: Location,
+    pub(crate) import: bool,
+}
+
+impl Location {
+    pub(crate) fn new(file: Option<FileId>, range: TextRange) -> Self {
+        Location {
+            file,
+            range,
+            included_from: None,
+            expansion: None,
+        }
+    }
+
+    // Returns the location that the outermost macro invocation producing this one was written at.
+    fn origin(&self) -> &Location {
+        match &self.expansion {
+            Some(expansion) => expansion.invocation.origin(),
+            None => self,
+        }
+    }
+
+    // The number of macro invocations that the location is nested in.
+    fn expansion_depth(&self) -> usize {
+        let mut depth = 0;
+        let mut maybe_expansion = &self.expansion;
+        while let Some(expansion) = maybe_expansion {
+            depth += 1;
+            maybe_expansion = &expansion.invocation.expansion;
+        }
+        depth
+    }
+}
+
+// A macro invocation, which the tokens of the macro's body are located in after substitution.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct Expansion {
+    pub(crate) macro_name: String,
+    pub(crate) invocation: Location,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum PpTokenKind {
+    Ident,
+    Number,
+    String,
+    Symbol,
+    Whitespace,
+    Comment,
+}
+
+// The names of the macros whose expansion produced a token, which are not expanded again when the
+// token is rescanned.
+type HideSet = Rc<BTreeSet<String>>;
+
+// A token of code or of a directive, owned so that it can outlive the source it came from.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct PpToken {
+    pub(crate) kind: PpTokenKind,
+    pub(crate) text: String,
+    pub(crate) location: Location,
+    hide_set: HideSet,
+    // Whether the token names a macro that was not expanded because of the hide set. Like in C,
+    // such a token is never expanded, even after it leaves the expansion.
+    painted: bool,
+}
+
+impl PpToken {
+    pub(crate) fn new(kind: PpTokenKind, text: String, location: Location) -> Self {
+        PpToken {
+            kind,
+            text,
+            location,
+            hide_set: HideSet::default(),
+            painted: false,
+        }
+    }
+
+    pub(crate) fn is_trivia(&self) -> bool {
+        matches!(self.kind, PpTokenKind::Whitespace | PpTokenKind::Comment)
+    }
+
+    pub(crate) fn is_symbol(&self, symbol: &str) -> bool {
+        self.kind == PpTokenKind::Symbol && self.text == symbol
+    }
+}
+
+// Lexes code, locating the tokens' ranges with `locate`.
+pub(crate) fn lex_code(
+    source: LocatedStr<'_>,
+    locate: impl Fn(TextRange) -> Location,
+) -> Vec<PpToken> {
+    TokenIter::new(source)
+        .map(|token| {
+            let kind = match token.kind {
+                TokenKind::Ident => PpTokenKind::Ident,
+                TokenKind::Number => PpTokenKind::Number,
+                TokenKind::Symbol => PpTokenKind::Symbol,
+                TokenKind::Whitespace => PpTokenKind::Whitespace,
+                TokenKind::Comment => PpTokenKind::Comment,
+            };
+            let location = locate(token.text.range());
+            PpToken::new(kind, token.text.inner_str.to_owned(), location)
+        })
+        .collect()
+}
+
+// Converts directive tokens, separating tokens that had anything between them with a space.
+pub(crate) fn from_directive_tokens(
+    tokens: &[MacroTokenResult<'_>],
+    locate: impl Fn(TextRange) -> Location,
+) -> Vec<PpToken> {
+    let mut output = Vec::with_capacity(tokens.len());
+    let mut maybe_previous_end = None;
+    for token in tokens {
+        let (kind, text) = match token {
+            MacroTokenResult::AlphanumStringToken(s) => (PpTokenKind::Ident, s.inner_str),
+            MacroTokenResult::SymbolToken(s) => (PpTokenKind::Symbol, s.inner_str),
+            MacroTokenResult::StringLiteralToken(literal) => {
+                (PpTokenKind::String, literal.source.inner_str)
+            }
+            MacroTokenResult::NumberToken(literal) => {
+                (PpTokenKind::Number, literal.source.inner_str)
+            }
+            MacroTokenResult::Terminator(_) | MacroTokenResult::Error(_) => continue,
+        };
+        let range = token.range();
+        if maybe_previous_end.is_some_and(|end: TextRange| end.end.byte_num != range.start.byte_num)
+        {
+            let gap = TextRange::new(maybe_previous_end.unwrap().end, range.start);
+            output.push(PpToken::new(
+                PpTokenKind::Whitespace,
+                " ".to_owned(),
+                locate(gap),
+            ));
+        }
+        output.push(PpToken::new(kind, text.to_owned(), locate(range)));
+        maybe_previous_end = Some(range);
+    }
+    output
+}
+
+// A macro defined by `#define` or through the API.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct MacroDefinition {
+    // For variadic macros, the last parameter is `__VA_ARGS__`.
+    pub(crate) params: Option<Vec<String>>,
+    pub(crate) variadic: bool,
+    pub(crate) body: Vec<PpToken>,
+    // The macro's name in its definition.
+    pub(crate) location: Location,
+}
+
+pub(crate) type MacroTable = BTreeMap<String, MacroDefinition>;
+
+// The macros that the preprocessor defines itself.
+pub(crate) const BUILTIN_MACROS: &[&str] =
+    &["__FILE__", "__LINE__", "__COUNTER__", "__YAWGSL_VERSION__"];
+
+// The crate version as `MAJOR * 10000 + MINOR * 100 + PATCH`.
+const VERSION: u64 = parse_decimal(env!("CARGO_PKG_VERSION_MAJOR")) * 10000
+    + parse_decimal(env!("CARGO_PKG_VERSION_MINOR")) * 100
+    + parse_decimal(env!("CARGO_PKG_VERSION_PATCH"));
+
+const fn parse_decimal(digits: &str) -> u64 {
+    let bytes = digits.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+    value
+}
+
+// Limits that keep hostile macros from making preprocessing take unbounded time or memory.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct ExpansionLimits {
+    // How deeply macro invocations may be nested, in the expansions of other macros or in their
+    // arguments.
+    pub(crate) max_depth: usize,
+    // How many bytes of code a run may produce, counting the expansions that are expanded further.
+    pub(crate) max_output_size: usize,
+}
+
+impl Default for ExpansionLimits {
+    fn default() -> Self {
+        ExpansionLimits {
+            max_depth: 256,
+            max_output_size: 16 << 20,
+        }
+    }
+}
+
+// Expands macros, with the state of the run that built-in macros and the limits need.
+pub(crate) struct Expander<'a> {
+    pub(crate) macros: &'a MacroTable,
+    pub(crate) files: &'a FileDatabase,
+    pub(crate) counter: &'a mut u64,
+    pub(crate) limits: ExpansionLimits,
+    // The number of bytes produced so far in the run.
+    pub(crate) produced: &'a mut usize,
+    // The names looked up as macros so far in the run.
+    pub(crate) looked_up: &'a mut BTreeSet<String>,
+}
+
+// The arguments of a macro invocation.
+struct Args {
+    raw: Vec<Vec<PpToken>>,
+    // The fully expanded arguments, expanded when first needed.
+    expanded: Vec<Option<Vec<PpToken>>>,
+    // The number of macro arguments that the arguments are nested in.
+    depth: usize,
+}
+
+impl<'a> Expander<'a> {
+    // Expands the macros used in `tokens`. Like in C, expansions are rescanned for further macros,
+    // except for the macros they came from.
+    pub(crate) fn expand(&mut self, tokens: &[PpToken]) -> Result<Vec<PpToken>, PendingError> {
+        self.expand_nested(tokens.to_vec(), 0)
+    }
+
+    // `arg_depth` is the number of macro arguments that `tokens` are nested in.
+    fn expand_nested(
+        &mut self,
+        tokens: Vec<PpToken>,
+        arg_depth: usize,
+    ) -> Result<Vec<PpToken>, PendingError> {
+        let mut output = Vec::with_capacity(tokens.len());
+        let mut pending = VecDeque::from(tokens);
+        while let Some(mut token) = pending.pop_front() {
+            let maybe_definition = match (token.kind, token.painted) {
+                (PpTokenKind::Ident, false) => {
+                    if !self.looked_up.contains(&token.text) {
+                        self.looked_up.insert(token.text.clone());
+                    }
+                    self.macros.get(&token.text)
+                }
+                _ => None,
+            };
+            let Some(definition) = maybe_definition else {
+                self.emit(&mut output, token)?;
+                continue;
+            };
+            if token.hide_set.contains(&token.text) {
+                token.painted = true;
+                self.emit(&mut output, token)?;
+                continue;
+            }
+            if arg_depth + token.location.expansion_depth() >= self.limits.max_depth {
+                return Err(PendingError {
+                    kind: PreprocessErrorKind::ExpansionDepthLimit(self.limits.max_depth),
+                    location: Box::new(token.location),
+                });
+            }
+
+            let mut hide_set = token.hide_set.clone();
+            let mut args = Args {
+                raw: Vec::new(),
+                expanded: Vec::new(),
+                depth: arg_depth + 1,
+            };
+            // The newlines in the invocation and the location after it.
+            let mut maybe_invocation_end = None;
+            if let Some(params) = &definition.params {
+                // Function-like macros are only invoked when followed by an argument list.
+                let rest = pending.make_contiguous();
+                let maybe_open = rest
+                    .iter()
+                    .position(|t| !t.is_trivia())
+                    .filter(|open| rest[*open].is_symbol("("));
+                let Some(open) = maybe_open else {
+                    self.emit(&mut output, token)?;
+                    continue;
+                };
+                let named_param_count = params.len() - definition.variadic as usize;
+                // The variadic arguments are collected into one, commas included.
+                let split_limit = match definition.variadic {
+                    true => named_param_count,
+                    false => usize::MAX,
+                };
+                let Some((raw_args, close)) = collect_args(rest, open, split_limit) else {
+                    return Err(PendingError {
+                        kind: PreprocessErrorKind::UnterminatedMacroCall,
+                        location: Box::new(token.location),
+                    });
+                };
+                args.raw = raw_args;
+                match (params.len(), args.raw.as_slice()) {
+                    // `F()` passes no arguments rather than one empty one.
+                    (0, [only_arg]) if only_arg.is_empty() => args.raw.clear(),
+                    // Like in C23, the variadic arguments may be left out entirely.
+                    _ if definition.variadic && args.raw.len() == named_param_count => {
+                        args.raw.push(Vec::new())
+                    }
+                    _ => {}
+                }
+                if args.raw.len() != params.len() {
+                    return Err(PendingError {
+                        kind: PreprocessErrorKind::MacroArgumentCount {
+                            expected: named_param_count,
+                            variadic: definition.variadic,
+                            found: args.raw.len(),
+                        },
+                        location: Box::new(token.location),
+                    });
+                }
+                args.expanded = alloc::vec![None; args.raw.len()];
+
+                let newlines = count_newlines(&rest[..=close]);
+                let close_paren = pending.drain(..=close).next_back().unwrap();
+                // Like in C, only the macros hidden from both the name and the closing parenthesis
+                // stay hidden, since the arguments may come from outside of an expansion.
+                hide_set = Rc::new(
+                    hide_set
+                        .intersection(&close_paren.hide_set)
+                        .cloned()
+                        .collect(),
+                );
+                maybe_invocation_end = Some((newlines, close_paren.location));
+            }
+            Rc::make_mut(&mut hide_set).insert(token.text.clone());
+
+            let frame = Rc::new(Expansion {
+                macro_name: token.text.clone(),
+                invocation: token.location.clone(),
+            });
+            let mut expansion = Vec::new();
+            self.substitute(
+                &definition.body,
+                definition,
+                &mut args,
+                &frame,
+                &mut expansion,
+            )?;
+            // Keep the lines after an invocation that spans several lines where they were.
+            if let Some((newlines, end_location)) = maybe_invocation_end {
+                let lost_newlines = newlines.saturating_sub(count_newlines(&expansion));
+                if lost_newlines > 0 {
+                    expansion.push(PpToken::new(
+                        PpTokenKind::Whitespace,
+                        "\n".repeat(lost_newlines),
+                        end_location,
+                    ));
+                }
+            }
+            let size = expansion.iter().map(|t| t.text.len()).sum();
+            self.produce(size, &token.location)?;
+            add_to_hide_sets(&mut expansion, &hide_set);
+            for expanded_token in expansion.into_iter().rev() {
+                pending.push_front(expanded_token);
+            }
+        }
+        Ok(output)
+    }
+
+    // Pushes `body`, part of a macro's body, with the macro's parameters replaced by `args`,
+    // `#param` stringified, `##` pasted and `__VA_OPT__` resolved. The body's tokens are located
+    // in `frame`. Built-in macros are expanded before pasting, so that `helper_ ## __COUNTER__`
+    // gives `helper_0`.
+    fn substitute(
+        &mut self,
+        body: &[PpToken],
+        definition: &MacroDefinition,
+        args: &mut Args,
+        frame: &Rc<Expansion>,
+        output: &mut Vec<PpToken>,
+    ) -> Result<(), PendingError> {
+        let params = definition.params.as_deref().unwrap_or_default();
+        let output_start = output.len();
+        // The location of a `##` whose right operand comes next.
+        let mut maybe_paste = None;
+        // Whether the last operand was an empty argument, which leaves nothing to paste onto.
+        let mut last_operand_empty = false;
+        let mut j = 0;
+        while j < body.len() {
+            let body_token = &body[j];
+            j += 1;
+            let location = Location {
+                expansion: Some(frame.clone()),
+                ..body_token.location.clone()
+            };
+            if body_token.is_trivia() {
+                output.push(PpToken::new(
+                    body_token.kind,
+                    body_token.text.clone(),
+                    location,
+                ));
+                continue;
+            }
+            if body_token.is_symbol("##") {
+                while output.len() > output_start && output.last().is_some_and(PpToken::is_trivia) {
+                    output.pop();
+                }
+                while body.get(j).is_some_and(PpToken::is_trivia) {
+                    j += 1;
+                }
+                maybe_paste = Some(location);
+                continue;
+            }
+
+            let mut operand = Vec::new();
+            let maybe_stringified_idx =
+                match definition.params.is_some() && body_token.is_symbol("#") {
+                    true => body[j..]
+                        .iter()
+                        .position(|t| !t.is_trivia())
+                        .map(|offset| j + offset)
+                        .and_then(|idx| Some((idx, param_idx(params, &body[idx])?))),
+                    false => None,
+                };
+            if definition.variadic
+                && body_token.kind == PpTokenKind::Ident
+                && body_token.text == "__VA_OPT__"
+            {
+                // `__VA_OPT__(tokens)` is `tokens` if there are variadic arguments.
+                let (open, close) = va_opt_parens(body, j - 1).unwrap();
+                if args.raw.last().is_some_and(|va_args| !va_args.is_empty()) {
+                    let content = &body[open + 1..close];
+                    self.substitute(content, definition, args, frame, &mut operand)?;
+                    operand = trim_trivia(operand);
+                }
+                j = close + 1;
+            } else if let Some((idx, param_idx)) = maybe_stringified_idx {
+                operand.push(PpToken::new(
+                    PpTokenKind::String,
+                    stringify(&args.raw[param_idx]),
+                    location,
+                ));
+                j = idx + 1;
+            } else if let Some(param_idx) = param_idx(params, body_token) {
+                // Like in C, operands of `##` are not expanded before pasting.
+                let pasted = maybe_paste.is_some()
+                    || body[j..]
+                        .iter()
+                        .find(|t| !t.is_trivia())
+                        .is_some_and(|t| t.is_symbol("##"));
+                match pasted {
+                    true => {
+                        for arg_token in &args.raw[param_idx] {
+                            self.push_token(&mut operand, arg_token.clone());
+                        }
+                    }
+                    false => operand.extend_from_slice(self.expanded_arg(args, param_idx)?),
+                }
+            } else {
+                let token = PpToken::new(body_token.kind, body_token.text.clone(), location);
+                self.push_token(&mut operand, token);
+            }
+
+            match maybe_paste.take() {
+                Some(paste_location) if !last_operand_empty && !operand.is_empty() => {
+                    // The left operand is the last token before the `##`. `check_body` makes sure
+                    // there is one.
+                    let left = match output.len() > output_start {
+                        true => output.pop(),
+                        false => None,
+                    };
+                    let Some(left) = left else {
+                        return Err(PendingError {
+                            kind: PreprocessErrorKind::PasteAtBodyEdge,
+                            location: Box::new(paste_location),
+                        });
+                    };
+                    let mut operand = operand.into_iter();
+                    let right = operand.next().unwrap();
+                    let text = left.text + &right.text;
+                    if validate_wgsl_identifier(&text).is_err() {
+                        return Err(PendingError {
+                            kind: PreprocessErrorKind::InvalidPaste(text),
+                            location: Box::new(paste_location),
+                        });
+                    }
+                    output.push(PpToken::new(PpTokenKind::Ident, text, paste_location));
+                    output.extend(operand);
+                    last_operand_empty = false;
+                }
+                Some(_) => {
+                    last_operand_empty &= operand.is_empty();
+                    output.extend(operand);
+                }
+                None => {
+                    last_operand_empty = operand.is_empty();
+                    output.extend(operand);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn expanded_arg<'b>(
+        &mut self,
+        args: &'b mut Args,
+        idx: usize,
+    ) -> Result<&'b [PpToken], PendingError> {
+        if args.expanded[idx].is_none() {
+            let expanded = self.expand_nested(args.raw[idx].clone(), args.depth)?;
+            args.expanded[idx] = Some(expanded);
+        }
+        Ok(args.expanded[idx].as_deref().unwrap())
+    }
+
+    // Pushes a token that is done being expanded.
+    fn emit(&mut self, output: &mut Vec<PpToken>, token: PpToken) -> Result<(), PendingError> {
+        self.push_token(output, token);
+        let token = output.last().unwrap();
+        self.produce(token.text.len(), &token.location)
+    }
+
+    // Counts `size` more bytes against the output size limit.
+    fn produce(&mut self, size: usize, location: &Location) -> Result<(), PendingError> {
+        *self.produced += size;
+        match *self.produced > self.limits.max_output_size {
+            true => Err(PendingError {
+                kind: PreprocessErrorKind::OutputSizeLimit(self.limits.max_output_size),
+                location: Box::new(location.clone()),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    // Pushes `token`, or the value of the built-in macro it names. Built-in macros count as being
+    // used where the outermost macro invocation that produced them was written.
+    fn push_token(&mut self, output: &mut Vec<PpToken>, token: PpToken) {
+        let used_at = token.location.origin();
+        let (kind, text) = match (token.kind, token.text.as_str()) {
+            (PpTokenKind::Ident, "__FILE__") => {
+                let path = used_at.file.map_or("", |file| &self.files.get(file).path);
+                (PpTokenKind::String, quote(path))
+            }
+            (PpTokenKind::Ident, "__LINE__") => (
+                PpTokenKind::Number,
+                (used_at.range.start.line_num + 1).to_string(),
+            ),
+            (PpTokenKind::Ident, "__COUNTER__") => {
+                *self.counter += 1;
+                (PpTokenKind::Number, (*self.counter - 1).to_string())
+            }
+            (PpTokenKind::Ident, "__YAWGSL_VERSION__") => {
+                (PpTokenKind::Number, VERSION.to_string())
+            }
+            _ => {
+                output.push(token);
+                return;
+            }
+        };
+        output.push(PpToken::new(kind, text, token.location));
+    }
+}
+
+// Adds `hide_set` to the hide set of each token. Tokens that had equal hide sets share the result.
+fn add_to_hide_sets(tokens: &mut [PpToken], hide_set: &HideSet) {
+    let mut unions: Vec<(HideSet, HideSet)> = Vec::new();
+    for token in tokens {
+        let maybe_union = unions
+            .iter()
+            .find(|(before, _)| *before == token.hide_set)
+            .map(|(_, after)| after.clone());
+        let union = maybe_union.unwrap_or_else(|| {
+            let union = Rc::new(token.hide_set.union(hide_set).cloned().collect());
+            unions.push((token.hide_set.clone(), Rc::clone(&union)));
+            union
+        });
+        token.hide_set = union;
+    }
+}
+
+fn param_idx(params: &[String], token: &PpToken) -> Option<usize> {
+    match token.kind {
+        PpTokenKind::Ident => params.iter().position(|p| *p == token.text),
+        _ => None,
+    }
+}
+
+// Writes an argument as a string literal, with each run of whitespace and comments as one space.
+fn stringify(arg: &[PpToken]) -> String {
+    let mut text = String::new();
+    for (i, token) in arg.iter().enumerate() {
+        match token.is_trivia() {
+            true if arg[i - 1].is_trivia() => {}
+            true => text.push(' '),
+            false => text.push_str(&token.text),
+        }
+    }
+    quote(&text)
+}
+
+// Returns the indices of the parentheses after the `__VA_OPT__` at `idx`.
+fn va_opt_parens(body: &[PpToken], idx: usize) -> Option<(usize, usize)> {
+    let open = body[idx + 1..]
+        .iter()
+        .position(|t| !t.is_trivia())
+        .map(|offset| idx + 1 + offset)
+        .filter(|open| body[*open].is_symbol("("))?;
+    let mut depth = 0usize;
+    for (i, token) in body.iter().enumerate().skip(open + 1) {
+        if token.is_symbol("(") {
+            depth += 1;
+        } else if token.is_symbol(")") {
+            if depth == 0 {
+                return Some((open, i));
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// Checks the uses of `#`, `##`, `__VA_ARGS__` and `__VA_OPT__` in a macro's body, which must be
+// valid before the macro is used.
+pub(crate) fn check_body(definition: &MacroDefinition) -> Result<(), PendingError> {
+    let body = &definition.body;
+    for (idx, token) in body.iter().enumerate() {
+        let is_va_opt = match (token.kind, token.text.as_str()) {
+            (PpTokenKind::Ident, "__VA_OPT__") => true,
+            (PpTokenKind::Ident, "__VA_ARGS__") => false,
+            _ => continue,
+        };
+        let kind = match (definition.variadic, is_va_opt) {
+            (false, _) => PreprocessErrorKind::VariadicOutsideVariadicMacro,
+            (true, true) => match va_opt_parens(body, idx) {
+                // The content may be all there is on either side of a `##`.
+                Some((open, close)) => {
+                    check_paste_edges(&body[open + 1..close])?;
+                    continue;
+                }
+                None => PreprocessErrorKind::InvalidVaOpt,
+            },
+            (true, false) => continue,
+        };
+        return Err(PendingError {
+            kind,
+            location: Box::new(token.location.clone()),
+        });
+    }
+    check_paste_edges(body)?;
+
+    let Some(params) = &definition.params else {
+        return Ok(());
+    };
+    let mut non_trivia = body.iter().filter(|t| !t.is_trivia()).peekable();
+    while let Some(token) = non_trivia.next() {
+        let is_stringified_param = non_trivia
+            .peek()
+            .is_some_and(|next| param_idx(params, next).is_some());
+        if token.is_symbol("#") && !is_stringified_param {
+            return Err(PendingError {
+                kind: PreprocessErrorKind::StringifyWithoutParameter,
+                location: Box::new(token.location.clone()),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Fails if `##` is the first or last token of `tokens`, where it has nothing to paste.
+fn check_paste_edges(tokens: &[PpToken]) -> Result<(), PendingError> {
+    let mut non_trivia = tokens.iter().filter(|t| !t.is_trivia());
+    for maybe_edge in [non_trivia.next(), non_trivia.next_back()] {
+        if let Some(edge) = maybe_edge.filter(|t| t.is_symbol("##")) {
+            return Err(PendingError {
+                kind: PreprocessErrorKind::PasteAtBodyEdge,
+                location: Box::new(edge.location.clone()),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Writes `text` as a string literal.
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn count_newlines(tokens: &[PpToken]) -> usize {
+    tokens.iter().map(|t| t.text.matches('\n').count()).sum()
+}
+
+// Collects the arguments of a function-like macro invocation, given the index of the opening
+// parenthesis. Arguments are split on commas outside of nested parentheses, until there are
+// `split_limit` arguments before the current one, and trimmed of whitespace and comments. Returns
+// the arguments and the index of the closing parenthesis.
+pub(crate) fn collect_args(
+    tokens: &[PpToken],
+    open: usize,
+    split_limit: usize,
+) -> Option<(Vec<Vec<PpToken>>, usize)> {
+    let mut args = Vec::new();
+    let mut current_arg: Vec<PpToken> = Vec::new();
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate().skip(open + 1) {
+        if token.is_symbol("(") {
+            depth += 1;
+        } else if token.is_symbol(")") {
+            if depth == 0 {
+                args.push(trim_trivia(current_arg));
+                return Some((args, i));
+            }
+            depth -= 1;
+        } else if token.is_symbol(",") && depth == 0 && args.len() < split_limit {
+            args.push(trim_trivia(core::mem::take(&mut current_arg)));
+            continue;
+        }
+        current_arg.push(token.clone());
+    }
+    None
+}
+
+pub(crate) fn trim_trivia(mut tokens: Vec<PpToken>) -> Vec<PpToken> {
+    while tokens.last().is_some_and(PpToken::is_trivia) {
+        tokens.pop();
+    }
+    let leading = tokens.iter().take_while(|t| t.is_trivia()).count();
+    tokens.drain(..leading);
+    tokens
+}
@@ -0,0 +1,229 @@
+use super::expand::{Location, PpToken, PpTokenKind};
+use super::{PendingError, PreprocessErrorKind};
+use crate::parsing::literals::parse_number;
+use crate::parsing::NumberValue;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+// Replaces `defined NAME` and `defined(NAME)` with `1` or `0`. This has to happen before macros in
+// the condition are expanded.
+pub(crate) fn resolve_defined(
+    tokens: Vec<PpToken>,
+    mut is_defined: impl FnMut(&str) -> bool,
+) -> Result<Vec<PpToken>, PendingError> {
+    let mut output = Vec::with_capacity(tokens.len());
+    let mut iter = tokens.into_iter().filter(|t| !t.is_trivia()).peekable();
+    while let Some(token) = iter.next() {
+        if token.kind != PpTokenKind::Ident || token.text != "defined" {
+            output.push(token);
+            continue;
+        }
+        let parenthesized = iter.next_if(|t| t.is_symbol("(")).is_some();
+        let name = iter
+            .next_if(|t| t.kind == PpTokenKind::Ident)
+            .ok_or_else(|| PendingError {
+                kind: PreprocessErrorKind::InvalidCondition(
+                    "expected a macro name after `defined`",
+                ),
+                location: Box::new(token.location.clone()),
+            })?;
+        if parenthesized && iter.next_if(|t| t.is_symbol(")")).is_none() {
+            return Err(PendingError {
+                kind: PreprocessErrorKind::InvalidCondition("expected `)` after the macro name"),
+                location: Box::new(name.location),
+            });
+        }
+        output.push(PpToken::new(
+            PpTokenKind::Number,
+            if is_defined(&name.text) { "1" } else { "0" }.to_owned(),
+            token.location,
+        ));
+    }
+    Ok(output)
+}
+
+// Evaluates a fully expanded `#if` condition. Like in C, identifiers that are left after expansion
+// evaluate to 0, except for `true` and `false`.
+pub(crate) fn evaluate(
+    tokens: &[PpToken],
+    directive_location: Location,
+) -> Result<i64, PendingError> {
+    let tokens: Vec<&PpToken> = tokens.iter().filter(|t| !t.is_trivia()).collect();
+    let mut evaluator = Evaluator {
+        tokens: &tokens,
+        pos: 0,
+        end_location: directive_location,
+    };
+    let value = evaluator.binary(0, true)?;
+    match evaluator.tokens.get(evaluator.pos) {
+        Some(token) => Err(error(
+            "unexpected token in condition",
+            token.location.clone(),
+        )),
+        None => Ok(value),
+    }
+}
+
+fn error(message: &'static str, location: Location) -> PendingError {
+    PendingError {
+        kind: PreprocessErrorKind::InvalidCondition(message),
+        location: Box::new(location),
+    }
+}
+
+// Returns the result of an operator at `location`, which is `None` if it overflowed. Operands that
+// are short-circuited away evaluate to 0 instead of failing.
+fn checked(value: Option<i64>, live: bool, location: Location) -> Result<i64, PendingError> {
+    match value {
+        Some(value) => Ok(value),
+        None if live => Err(error("integer overflow in #if expression", location)),
+        None => Ok(0),
+    }
+}
+
+// (operator, precedence). Higher binds tighter.
+const BINARY_OPERATORS: &[(&str, u8)] = &[
+    ("||", 1),
+    ("&&", 2),
+    ("|", 3),
+    ("^", 4),
+    ("&", 5),
+    ("==", 6),
+    ("!=", 6),
+    ("<", 7),
+    ("<=", 7),
+    (">", 7),
+    (">=", 7),
+    ("<<", 8),
+    (">>", 8),
+    ("+", 9),
+    ("-", 9),
+    ("*", 10),
+    ("/", 10),
+    ("%", 10),
+];
+
+struct Evaluator<'t> {
+    tokens: &'t [&'t PpToken],
+    pos: usize,
+    end_location: Location,
+}
+
+impl<'t> Evaluator<'t> {
+    fn peek_binary_operator(&self) -> Option<(&'t str, u8)> {
+        let token = self.tokens.get(self.pos)?;
+        if token.kind != PpTokenKind::Symbol {
+            return None;
+        }
+        BINARY_OPERATORS
+            .iter()
+            .find(|(operator, _)| *operator == token.text)
+            .map(|(_, precedence)| (token.text.as_str(), *precedence))
+    }
+
+    // `live` is false for operands that are short-circuited away, which must parse but may not
+    // fail to evaluate.
+    fn binary(&mut self, min_precedence: u8, live: bool) -> Result<i64, PendingError> {
+        let mut lhs = self.unary(live)?;
+        while let Some((operator, precedence)) = self.peek_binary_operator() {
+            if precedence <= min_precedence {
+                break;
+            }
+            let operator_location = self.tokens[self.pos].location.clone();
+            self.pos += 1;
+            let rhs_live = match operator {
+                "&&" => live && lhs != 0,
+                "||" => live && lhs == 0,
+                _ => live,
+            };
+            let rhs = self.binary(precedence, rhs_live)?;
+            let value = match operator {
+                "||" => Some((lhs != 0 || rhs != 0) as i64),
+                "&&" => Some((lhs != 0 && rhs != 0) as i64),
+                "|" => Some(lhs | rhs),
+                "^" => Some(lhs ^ rhs),
+                "&" => Some(lhs & rhs),
+                "==" => Some((lhs == rhs) as i64),
+                "!=" => Some((lhs != rhs) as i64),
+                "<" => Some((lhs < rhs) as i64),
+                "<=" => Some((lhs <= rhs) as i64),
+                ">" => Some((lhs > rhs) as i64),
+                ">=" => Some((lhs >= rhs) as i64),
+                // Like in C, bits may not be shifted out of a left shift, and shift counts must be
+                // in range.
+                "<<" => u32::try_from(rhs)
+                    .ok()
+                    .and_then(|count| lhs.checked_shl(count).filter(|v| v >> count == lhs)),
+                ">>" => u32::try_from(rhs)
+                    .ok()
+                    .and_then(|count| lhs.checked_shr(count)),
+                "+" => lhs.checked_add(rhs),
+                "-" => lhs.checked_sub(rhs),
+                "*" => lhs.checked_mul(rhs),
+                "/" | "%" if rhs == 0 => {
+                    if live {
+                        return Err(error("division by zero", operator_location));
+                    }
+                    Some(0)
+                }
+                "/" => lhs.checked_div(rhs),
+                "%" => lhs.checked_rem(rhs),
+                _ => unreachable!(),
+            };
+            lhs = checked(value, live, operator_location)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self, live: bool) -> Result<i64, PendingError> {
+        let Some(token) = self.tokens.get(self.pos) else {
+            return Err(error(
+                "unexpected end of condition",
+                self.end_location.clone(),
+            ));
+        };
+        self.pos += 1;
+        match token.kind {
+            PpTokenKind::Symbol => match token.text.as_str() {
+                "!" => Ok((self.unary(live)? == 0) as i64),
+                "-" => {
+                    let value = self.unary(live)?.checked_neg();
+                    checked(value, live, token.location.clone())
+                }
+                "~" => Ok(!self.unary(live)?),
+                "+" => self.unary(live),
+                "(" => {
+                    let value = self.binary(0, live)?;
+                    match self.tokens.get(self.pos) {
+                        Some(close) if close.is_symbol(")") => {
+                            self.pos += 1;
+                            Ok(value)
+                        }
+                        Some(other) => Err(error("expected `)`", other.location.clone())),
+                        None => Err(error("expected `)`", self.end_location.clone())),
+                    }
+                }
+                _ => Err(error(
+                    "unexpected token in condition",
+                    token.location.clone(),
+                )),
+            },
+            PpTokenKind::Number => match parse_number(&token.text) {
+                Ok(NumberValue::AbstractInt(v)) => Ok(v),
+                Ok(NumberValue::I32(v)) => Ok(v as i64),
+                Ok(NumberValue::U32(v)) => Ok(v as i64),
+                Ok(_) => Err(error(
+                    "floats are not allowed in conditions",
+                    token.location.clone(),
+                )),
+                Err(_) => Err(error("malformed numeric literal", token.location.clone())),
+            },
+            PpTokenKind::Ident => Ok((token.text == "true") as i64),
+            _ => Err(error(
+                "unexpected token in condition",
+                token.location.clone(),
+            )),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Display;
+use core::hash::Hasher;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+/// Identifies a file in a [`FileDatabase`].
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FileId(pub(crate) u32);
+
+/// A file read during preprocessing.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SourceFile {
+    /// The path the file was resolved to.
+    pub path: String,
+    /// The file's contents.
+    pub source: Arc<str>,
+}
+
+/// Every file read during preprocessing, in the order they were first read.
+///
+/// The first file is the one preprocessing started from.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct FileDatabase {
+    files: Vec<SourceFile>,
+}
+
+impl FileDatabase {
+    /// Returns the file with the given id.
+    pub fn get(&self, id: FileId) -> &SourceFile {
+        &self.files[id.0 as usize]
+    }
+
+    /// Returns the id of the file with the given path, if it was read.
+    pub fn id_of(&self, path: &str) -> Option<FileId> {
+        self.files
+            .iter()
+            .position(|file| file.path == path)
+            .map(|i| FileId(i as u32))
+    }
+
+    /// Iterates over all files and their ids.
+    pub fn iter(&self) -> impl Iterator<Item = (FileId, &SourceFile)> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (FileId(i as u32), file))
+    }
+
+    /// Returns the number of files.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns true if no file was read.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    // Adds a file, or returns the id of the file with the same path if there is one.
+    pub(crate) fn insert(&mut self, file: SourceFile) -> FileId {
+        match self.id_of(&file.path) {
+            Some(id) => id,
+            None => {
+                self.files.push(file);
+                FileId(self.files.len() as u32 - 1)
+            }
+        }
+    }
+}
+
+/// Returns a Makefile-style depfile, as written by `gcc -MD`, with a rule that makes `targets`
+/// depend on `dependencies`.
+///
+/// Make, Ninja and similar build tools read these to know when to rebuild the targets. Spaces, `#`
+/// and `$` in paths are escaped.
+///
+/// ```
+/// use yet_another_wgsl_preprocessor::preprocessor::depfile;
+///
+/// let rule = depfile(["out/main.wgsl"], ["main.wgsl", "my lib.wgsl"]);
+/// assert_eq!(rule, "out/main.wgsl: \\\n  main.wgsl \\\n  my\\ lib.wgsl\n");
+/// ```
+pub fn depfile<'a>(
+    targets: impl IntoIterator<Item = &'a str>,
+    dependencies: impl IntoIterator<Item = &'a str>,
+) -> String {
+    let escape = |path: &str| {
+        path.replace('$', "$$")
+            .replace(' ', "\\ ")
+            .replace('#', "\\#")
+    };
+    let targets: Vec<String> = targets.into_iter().map(escape).collect();
+    let mut rule = targets.join(" ");
+    rule.push(':');
+    for dependency in dependencies {
+        rule.push_str(" \\\n  ");
+        rule.push_str(&escape(dependency));
+    }
+    rule.push('\n');
+    rule
+}
+
+/// Returns the 64-bit FNV-1a hash of `source`.
+///
+/// The hash stays the same across runs, platforms and versions of this crate, unlike the hashers
+/// of the standard library, so it can name files or key caches that are kept on disk.
+pub fn content_hash(source: &str) -> u64 {
+    let mut hasher = Fnv1a::default();
+    hasher.write(source.as_bytes());
+    hasher.finish()
+}
+
+// The 64-bit FNV-1a hasher.
+pub(crate) struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// A file found by an [`IncludeResolver`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ResolvedFile {
+    /// The file's path. Used to tell files apart, so the same file should always resolve to the
+    /// same path.
+    pub path: String,
+    /// The file's contents.
+    pub source: String,
+}
+
+/// Finds included files.
+pub trait IncludeResolver {
+    /// Resolves `path` as written in an include directive of `including_path`.
+    ///
+    /// `including_path` is `None` for the file preprocessing starts from.
+    fn resolve(
+        &self,
+        path: &str,
+        including_path: Option<&str>,
+    ) -> Result<ResolvedFile, ResolveError>;
+}
+
+/// Why an [`IncludeResolver`] could not resolve a path.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ResolveError {
+    /// There is no file at the path.
+    NotFound,
+    /// The file exists but could not be read.
+    Other(String),
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ResolveError::NotFound => write!(f, "file not found"),
+            ResolveError::Other(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for ResolveError {}
+
+/// Resolves includes from files held in memory.
+///
+/// Paths are `/`-separated. Relative paths are resolved from the including file's directory.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct MemoryResolver {
+    files: BTreeMap<String, String>,
+}
+
+impl MemoryResolver {
+    /// Creates a resolver without any files.
+    pub fn new() -> Self {
+        MemoryResolver::default()
+    }
+
+    /// Adds a file, replacing any file with the same path.
+    pub fn add_file(&mut self, path: impl Into<String>, source: impl Into<String>) -> &mut Self {
+        self.files
+            .insert(normalize_path(&path.into()), source.into());
+        self
+    }
+}
+
+impl IncludeResolver for MemoryResolver {
+    fn resolve(
+        &self,
+        path: &str,
+        including_path: Option<&str>,
+    ) -> Result<ResolvedFile, ResolveError> {
+        let full_path = match including_path {
+            Some(including_path) if !path.starts_with('/') => match including_path.rsplit_once('/')
+            {
+                Some((directory, _)) => normalize_path(&[directory, path].join("/")),
+                None => normalize_path(path),
+            },
+            _ => normalize_path(path),
+        };
+        match self.files.get(&full_path) {
+            Some(source) => Ok(ResolvedFile {
+                path: full_path,
+                source: source.clone(),
+            }),
+            None => Err(ResolveError::NotFound),
+        }
+    }
+}
+
+// Removes empty and `.` segments and resolves `..` segments of a `/`-separated path.
+fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.last().is_some_and(|last| *last != "..") {
+                    segments.pop();
+                } else {
+                    segments.push("..");
+                }
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let joined = segments.join("/");
+    if path.starts_with('/') {
+        ["/", &joined].concat()
+    } else {
+        joined.to_owned()
+    }
+}
+
+/// Resolves includes from the file system.
+///
+/// Relative paths are resolved from the including file's directory first, then from each include
+/// directory in order.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct FsResolver {
+    include_dirs: Vec<PathBuf>,
+}
+
+#[cfg(feature = "std")]
+impl FsResolver {
+    /// Creates a resolver without include directories.
+    pub fn new() -> Self {
+        FsResolver::default()
+    }
+
+    /// Adds a directory to search for includes.
+    pub fn add_include_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+}
+
+#[cfg(feature = "std")]
+impl IncludeResolver for FsResolver {
+    fn resolve(
+        &self,
+        path: &str,
+        including_path: Option<&str>,
+    ) -> Result<ResolvedFile, ResolveError> {
+        let first_candidate = match including_path.and_then(|p| Path::new(p).parent()) {
+            Some(including_dir) => including_dir.join(path),
+            None => PathBuf::from(path),
+        };
+        let candidates = core::iter::once(first_candidate)
+            .chain(self.include_dirs.iter().map(|dir| dir.join(path)));
+
+        for candidate in candidates {
+            match std::fs::read_to_string(&candidate) {
+                Ok(source) => {
+                    let canonical = std::fs::canonicalize(&candidate).unwrap_or(candidate);
+                    return Ok(ResolvedFile {
+                        path: canonical.to_string_lossy().into_owned(),
+                        source,
+                    });
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    return Err(ResolveError::Other(alloc::format!(
+                        "could not read {}: {error}",
+                        candidate.display()
+                    )))
+                }
+            }
+        }
+        Err(ResolveError::NotFound)
+    }
+}
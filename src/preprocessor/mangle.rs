@@ -0,0 +1,288 @@
+use super::modules::{Module, ModuleImport};
+use super::source_map::{self, Span};
+use super::FileId;
+use crate::utils::LocatedStr;
+use crate::wgsl::{scan_declarations, Token, TokenIter, TokenKind};
+use alloc::borrow::ToOwned;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+// The attributes whose arguments are enumerants, like `position` in `@builtin(position)`, rather
+// than expressions.
+const ENUMERANT_ATTRIBUTES: &[&str] = &["builtin", "diagnostic", "interpolate"];
+
+// The enumerants that the template lists of `var`, `ptr` and storage textures take: address
+// spaces, access modes and texel formats.
+const TEMPLATE_ENUMERANTS: &[&str] = &[
+    "function",
+    "private",
+    "workgroup",
+    "uniform",
+    "storage",
+    "read",
+    "write",
+    "read_write",
+    "rgba8unorm",
+    "rgba8snorm",
+    "rgba8uint",
+    "rgba8sint",
+    "rgba16uint",
+    "rgba16sint",
+    "rgba16float",
+    "r32uint",
+    "r32sint",
+    "r32float",
+    "rg32uint",
+    "rg32sint",
+    "rg32float",
+    "rgba32uint",
+    "rgba32sint",
+    "rgba32float",
+    "bgra8unorm",
+];
+
+// Returns the name that `name`, declared in the module at `module_path`, is mangled to.
+fn mangled_name(module_path: &str, name: &str) -> String {
+    format!("{}__{name}", module_path.replace("::", "__"))
+}
+
+// Whose code `Mangler::rewrite` rewrites.
+pub(crate) enum Owner<'a> {
+    // The module at this index.
+    Module(usize),
+    // The preprocessed file, which declares these names. They stay as they are.
+    Root(&'a BTreeSet<String>),
+}
+
+// Returns the names declared in `code`.
+pub(crate) fn declared_names(code: &str) -> BTreeSet<String> {
+    scan_declarations(LocatedStr::new(code))
+        .iter()
+        .filter_map(|declaration| declaration.name)
+        .map(|name| name.inner_str.to_owned())
+        .collect()
+}
+
+// Rewrites the names declared by modules to their mangled names.
+pub(crate) struct Mangler {
+    // Each module's path and the names that it declares.
+    modules: Vec<(String, BTreeSet<String>)>,
+}
+
+impl Mangler {
+    pub(crate) fn new(modules: &[Module]) -> Self {
+        let modules = modules
+            .iter()
+            .map(|module| (module.path.clone(), declared_names(&module.code)))
+            .collect();
+        Mangler { modules }
+    }
+
+    // Maps every mangled name to the qualified name it was mangled from.
+    pub(crate) fn demangle_table(&self) -> BTreeMap<String, String> {
+        let mut table = BTreeMap::new();
+        for (path, names) in &self.modules {
+            for name in names {
+                table.insert(mangled_name(path, name), format!("{path}::{name}"));
+            }
+        }
+        table
+    }
+
+    // Returns the mangled name of `item`, declared in the module at `module_idx`.
+    pub(crate) fn mangle_item(&self, module_idx: usize, item: &str) -> String {
+        mangled_name(&self.modules[module_idx].0, item)
+    }
+
+    // Rewrites the names in the code of `importer`, which is `owner`. Names resolve to the
+    // importer's own declarations first, then to the items it imports, and then to the names of
+    // the modules it imports as a whole. Qualified names like `lighting::saturate` resolve to the
+    // item of any imported module.
+    pub(crate) fn rewrite(
+        &self,
+        code: &str,
+        spans: Vec<Span>,
+        importer: Option<FileId>,
+        owner: Owner<'_>,
+        imports: &[ModuleImport],
+    ) -> (String, Vec<Span>) {
+        let mut names: BTreeMap<&str, String> = BTreeMap::new();
+        let imports: Vec<&ModuleImport> = imports
+            .iter()
+            .filter(|import| import.importer == importer)
+            .collect();
+        // Like in Rust, imported items take precedence over the names of whole modules.
+        let item_imports = imports.iter().filter(|import| import.items.is_some());
+        let whole_imports = imports.iter().filter(|import| import.items.is_none());
+        for import in item_imports.chain(whole_imports) {
+            let (path, declared) = &self.modules[import.module];
+            let imported = import
+                .items
+                .as_ref()
+                .map_or(declared.iter().collect(), |items| {
+                    items.iter().collect::<Vec<&String>>()
+                });
+            for name in imported {
+                names
+                    .entry(name.as_str())
+                    .or_insert_with(|| mangled_name(path, name));
+            }
+        }
+        match owner {
+            Owner::Module(idx) => {
+                let (path, declared) = &self.modules[idx];
+                for name in declared {
+                    names.insert(name.as_str(), mangled_name(path, name));
+                }
+            }
+            Owner::Root(declared) => {
+                for name in declared {
+                    names.remove(name.as_str());
+                }
+            }
+        }
+
+        let tokens: Vec<Token<'_>> = TokenIter::new(LocatedStr::new(code)).collect();
+        let mut replacements: Vec<(Range<usize>, String)> = Vec::new();
+        let mut previous: Option<&str> = None;
+        let mut depth = 0usize;
+        // The depth of the braces of the struct being declared, whose member names stay as they
+        // are, like member names after `.`.
+        let mut struct_depth = None;
+        let mut struct_pending = false;
+        // The nesting of the parentheses of an attribute whose arguments are enumerants, and of the
+        // template list of a `var`, `ptr` or storage texture. Neither has names to rewrite, other
+        // than types in template lists.
+        let mut attribute_parens = None;
+        let mut template_angles = None;
+        let (mut attribute_pending, mut template_pending) = (false, false);
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = tokens[i];
+            let start = token.text.start_location.byte_num as usize;
+            let mut end = start + token.text.inner_str.len();
+            let (opens_attribute, opens_template) = match token.is_trivia() {
+                true => (false, false),
+                false => (
+                    core::mem::take(&mut attribute_pending),
+                    core::mem::take(&mut template_pending),
+                ),
+            };
+            match (token.kind, token.text.inner_str) {
+                (TokenKind::Symbol, "(") => open(&mut attribute_parens, opens_attribute),
+                (TokenKind::Symbol, ")") => close(&mut attribute_parens),
+                (TokenKind::Symbol, "<") => open(&mut template_angles, opens_template),
+                (TokenKind::Symbol, ">") => close(&mut template_angles),
+                (TokenKind::Symbol, "{") => {
+                    if core::mem::take(&mut struct_pending) {
+                        struct_depth = Some(depth);
+                    }
+                    depth += 1;
+                }
+                (TokenKind::Symbol, "}") => {
+                    depth = depth.saturating_sub(1);
+                    if struct_depth == Some(depth) {
+                        struct_depth = None;
+                    }
+                }
+                (TokenKind::Ident, "struct") if depth == 0 => struct_pending = true,
+                (TokenKind::Ident, name) => {
+                    let mut path = alloc::vec![name];
+                    while is_path_separator(&tokens[i + 1..]) {
+                        let segment = tokens[i + 3].text;
+                        path.push(segment.inner_str);
+                        end = segment.start_location.byte_num as usize + segment.inner_str.len();
+                        i += 3;
+                    }
+                    let replacement = match path.split_last() {
+                        Some((item, module_path)) if !module_path.is_empty() => {
+                            self.resolve_qualified(&module_path.join("::"), item)
+                        }
+                        _ if previous == Some(".") => None,
+                        // Attribute names, and the enumerants that some attributes take.
+                        _ if previous == Some("@") || attribute_parens.is_some() => None,
+                        _ if template_angles.is_some() && TEMPLATE_ENUMERANTS.contains(&name) => {
+                            None
+                        }
+                        _ if struct_depth.is_some() && is_member_name(&tokens[i + 1..]) => None,
+                        _ => names.get(name).cloned(),
+                    };
+                    if let Some(replacement) = replacement {
+                        replacements.push((start..end, replacement));
+                    }
+                    attribute_pending =
+                        previous == Some("@") && ENUMERANT_ATTRIBUTES.contains(&name);
+                    template_pending =
+                        matches!(name, "var" | "ptr") || name.starts_with("texture_storage_");
+                }
+                _ => {}
+            }
+            if !tokens[i].is_trivia() {
+                previous = Some(tokens[i].text.inner_str);
+            }
+            i += 1;
+        }
+        source_map::replace_ranges(code, spans, &replacements, true)
+    }
+
+    fn resolve_qualified(&self, module_path: &str, item: &str) -> Option<String> {
+        self.modules
+            .iter()
+            .find(|(path, declared)| path == module_path && declared.contains(item))
+            .map(|(path, _)| mangled_name(path, item))
+    }
+}
+
+// Enters a bracket: a nested one if `depth` is already set, or the outermost one if `opens` is true.
+fn open(depth: &mut Option<usize>, opens: bool) {
+    match depth {
+        Some(depth) => *depth += 1,
+        None if opens => *depth = Some(1),
+        None => {}
+    }
+}
+
+// Leaves a bracket entered with `open`.
+fn close(depth: &mut Option<usize>) {
+    if let Some(inner) = depth {
+        *inner -= 1;
+        if *inner == 0 {
+            *depth = None;
+        }
+    }
+}
+
+// Returns whether `tokens` start with `::` and an identifier.
+fn is_path_separator(tokens: &[Token<'_>]) -> bool {
+    let text = |idx: usize| {
+        tokens
+            .get(idx)
+            .map(|token| (token.kind, token.text.inner_str))
+    };
+    text(0) == Some((TokenKind::Symbol, ":"))
+        && text(1) == Some((TokenKind::Symbol, ":"))
+        && tokens
+            .get(2)
+            .is_some_and(|token| token.kind == TokenKind::Ident)
+}
+
+// Returns whether the tokens after an identifier make it a struct member's name.
+fn is_member_name(tokens: &[Token<'_>]) -> bool {
+    let mut rest = tokens.iter().filter(|token| !token.is_trivia());
+    rest.next().is_some_and(|token| token.text.inner_str == ":")
+}
+
+// Replaces the mangled names in `text` with the names they were mangled from.
+pub(crate) fn demangle(text: &str, table: &BTreeMap<String, String>) -> String {
+    TokenIter::new(LocatedStr::new(text))
+        .map(|token| match token.kind {
+            TokenKind::Ident => table
+                .get(token.text.inner_str)
+                .map_or(token.text.inner_str, String::as_str),
+            _ => token.text.inner_str,
+        })
+        .collect()
+}
@@ -0,0 +1,1558 @@
+//! The preprocessor, which runs directives and expands macros.
+//!
+//! ```
+//! use yet_another_wgsl_preprocessor::preprocessor::{MemoryResolver, Preprocessor};
+//!
+//! let mut files = MemoryResolver::new();
+//! files.add_file("common.wgsl", "const PI = 3.14159;\n");
+//! files.add_file(
+//!     "main.wgsl",
+//!     "// #include \"common.wgsl\";\n\
+//!      // #ifdef DOUBLE;\n\
+//!      fn tau() -> f32 { return 2.0 * PI; }\n\
+//!      // #endif;\n",
+//! );
+//!
+//! let mut preprocessor = Preprocessor::new(files);
+//! preprocessor.define("DOUBLE", "").unwrap();
+//! let output = preprocessor.preprocess("main.wgsl").unwrap();
+//! assert_eq!(
+//!     output.code,
+//!     "const PI = 3.14159;\nfn tau() -> f32 { return 2.0 * PI; }\n"
+//! );
+//! ```
+//!
+//! # Macros
+//!
+//! Like in C, `a ## b` in a macro's body pastes its operands into one identifier, and `#param` in
+//! the body of a function-like macro becomes its argument as a string literal. A parameter list
+//! ending with `...` makes a macro variadic: its further arguments are `__VA_ARGS__`, and
+//! `__VA_OPT__(tokens)` is `tokens` only if there are any. A macro's body may contain the end
+//! ident inside of brackets, so a macro can define whole functions:
+//!
+//! ```text
+//! // #define SUM(T) fn sum_##T(v: array<T, 2>) -> T {
+//! //     return v[0] + v[1];
+//! // };
+//! ```
+//!
+//! Expansions are rescanned for further macros. As in C, a macro is not expanded again inside of
+//! its own expansion, so `#define f f + 1;` turns `f` into `f + 1` instead of recursing forever.
+//! Since sources may be untrusted, how deeply expansions nest and how much code they produce are
+//! limited; see [`Preprocessor::set_max_expansion_depth`] and
+//! [`Preprocessor::set_max_output_size`].
+//!
+//! # Modules
+//!
+//! `#import lighting::pbr;` imports the module at `lighting/pbr.wgsl` under the module root (see
+//! [`Preprocessor::set_module_root`]). Unlike an included file, a module is preprocessed at most
+//! once per run, and its code goes before the preprocessed file's, after the modules it imports,
+//! so that declarations come before their uses. Macros defined in a module are visible after the
+//! `#import`. Import cycles are errors.
+//!
+//! `#import lighting::{pbr_direct, fresnel_schlick};` only emits the listed functions, structs,
+//! constants and so on, with the declarations they use from any module. A module is only emitted
+//! as a whole if the preprocessed file imports it as a whole, directly or through modules that are
+//! emitted as a whole. [`Preprocessor::set_mangle_names`] lets modules declare the same names.
+//!
+//! # Caching
+//!
+//! [`Preprocessor::preprocess_cached`] keeps outputs in a [`PreprocessCache`] and only preprocesses
+//! a file again when its defines or settings change, or when the contents of any file it read do.
+//! [`PreprocessCache::invalidate`] tells which outputs read a changed file, such as for hot
+//! reloading shaders.
+//!
+//! # Built-in macros
+//!
+//! These macros are always defined and cannot be redefined. When used in a macro's body, they
+//! refer to where the outermost macro was invoked.
+//!
+//! - `__FILE__`: the current file's path, as a string literal.
+//! - `__LINE__`: the current line number, starting at 1.
+//! - `__COUNTER__`: `0` the first time it is expanded in a run, then `1`, and so on.
+//! - `__YAWGSL_VERSION__`: this crate's version as `MAJOR * 10000 + MINOR * 100 + PATCH`.
+
+mod cache;
+mod custom;
+mod dead_code;
+mod diagnostics;
+mod expand;
+mod expr;
+mod files;
+mod mangle;
+mod modules;
+mod permutations;
+mod source_map;
+
+#[cfg(test)]
+mod tests;
+
+use crate::macros::{
+    Directive, DirectiveHandler, DirectiveIter, DirectiveParseErrorKind, Import, BUILTIN_DIRECTIVES,
+};
+use crate::parsing::ident::ident_len;
+use crate::parsing::{
+    validate_wgsl_identifier, LocatedStrMacroTokenIter, MacroTokenResult, SyntaxSettings,
+};
+use crate::utils::{LocatedStr, TextRange};
+use crate::wgsl::{scan_declarations, Declaration, TokenIter, TokenKind};
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use cache::DirectiveCache;
+pub use cache::PreprocessCache;
+use core::fmt::Display;
+use core::ops::Range;
+pub use custom::{CustomDirectiveError, CustomDirectiveHandler, DirectiveContext};
+pub use diagnostics::{Diagnostic, DiagnosticKind, Diagnostics};
+use expand::{
+    Expander, ExpansionLimits, IncludeSite, Location, MacroDefinition, MacroTable, PpTokenKind,
+    BUILTIN_MACROS,
+};
+#[cfg(feature = "std")]
+pub use files::FsResolver;
+pub use files::{
+    content_hash, depfile, FileDatabase, FileId, IncludeResolver, MemoryResolver, ResolveError,
+    ResolvedFile, SourceFile,
+};
+use mangle::{Mangler, Owner};
+use modules::{Module, ModuleImport};
+pub use permutations::{Permutation, PermutationError, PermutationMatrix, PermutationOutputs};
+pub use source_map::{BacktraceFrame, Origin, SourceMap};
+use source_map::{SourceMapBuilder, Span};
+#[cfg(feature = "std")]
+use std::error::Error;
+
+/// Runs directives and expands macros in WGSL sources.
+///
+/// A `Preprocessor` holds the configuration shared by every run: the include resolver, the macros
+/// defined up front and the custom directive handlers. Directives in the sources do not change it,
+/// so one `Preprocessor` can preprocess many files.
+pub struct Preprocessor<'r> {
+    resolver: Box<dyn IncludeResolver + 'r>,
+    syntax_settings: SyntaxSettings<'r>,
+    macros: MacroTable,
+    custom_directives: BTreeMap<String, Box<dyn CustomDirectiveHandler + 'r>>,
+    pass_through_unknown_directives: bool,
+    output_mode: OutputMode,
+    limits: ExpansionLimits,
+    module_root: String,
+    mangle_names: bool,
+    eliminate_dead_code: bool,
+    kept_symbols: BTreeSet<String>,
+}
+
+/// How the preprocessed code is laid out.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum OutputMode {
+    /// Directive lines and disabled code are removed, and included files are inserted in place of
+    /// their `#include`.
+    #[default]
+    Compact,
+    /// Every line of the preprocessed file stays on the same line in the output, so that line
+    /// numbers reported by later stages point into the source.
+    ///
+    /// Directive lines and disabled code are replaced with blank lines. Included files are moved
+    /// after the preprocessed file and wrapped in `// yawgsl: begin include "path"` and
+    /// `// yawgsl: end include "path"` comments, which is fine for the module-scope declarations
+    /// that WGSL allows in any order. Includes inside braces, such as in a function body, stay in
+    /// place with a [`DiagnosticKind::IncludeShiftsLines`] warning, since moving them would change
+    /// the code. They and custom directive replacements longer than the directive still shift the
+    /// lines after them.
+    LinePreserving,
+}
+
+impl<'r> Preprocessor<'r> {
+    /// Creates a preprocessor that finds files with `resolver`.
+    pub fn new(resolver: impl IncludeResolver + 'r) -> Self {
+        Preprocessor {
+            resolver: Box::new(resolver),
+            syntax_settings: SyntaxSettings::default(),
+            macros: MacroTable::new(),
+            custom_directives: BTreeMap::new(),
+            pass_through_unknown_directives: false,
+            output_mode: OutputMode::default(),
+            limits: ExpansionLimits::default(),
+            module_root: String::new(),
+            mangle_names: false,
+            eliminate_dead_code: false,
+            kept_symbols: BTreeSet::new(),
+        }
+    }
+
+    /// Replaces the directive syntax.
+    pub fn set_syntax_settings(&mut self, syntax_settings: SyntaxSettings<'r>) -> &mut Self {
+        self.syntax_settings = syntax_settings;
+        self
+    }
+
+    /// Defines an object-like macro, as if by `#define name value;` before the first line.
+    pub fn define(&mut self, name: &str, value: &str) -> Result<(), PreprocessError> {
+        let definition = self.parse_define(name, value)?;
+        self.macros.insert(name.to_owned(), definition);
+        Ok(())
+    }
+
+    fn parse_define(&self, name: &str, value: &str) -> Result<MacroDefinition, PreprocessError> {
+        validate_wgsl_identifier(name).map_err(|error| PreprocessError {
+            kind: PreprocessErrorKind::Directive(DirectiveParseErrorKind::InvalidMacroName(error)),
+            location: None,
+            backtrace: Vec::new(),
+        })?;
+        if BUILTIN_MACROS.contains(&name) {
+            return Err(PreprocessError {
+                kind: PreprocessErrorKind::BuiltinMacroRedefined(name.to_owned()),
+                location: None,
+                backtrace: Vec::new(),
+            });
+        }
+        let mut tokens = Vec::new();
+        for token in LocatedStrMacroTokenIter::new(LocatedStr::new(value), self.syntax_settings) {
+            match token {
+                MacroTokenResult::Error(error) => {
+                    return Err(PreprocessError {
+                        kind: PreprocessErrorKind::Directive(DirectiveParseErrorKind::Token(
+                            error.kind,
+                        )),
+                        location: None,
+                        backtrace: Vec::new(),
+                    })
+                }
+                MacroTokenResult::Terminator(_) => {
+                    return Err(PreprocessError {
+                        kind: PreprocessErrorKind::Directive(
+                            DirectiveParseErrorKind::UnexpectedToken,
+                        ),
+                        location: None,
+                        backtrace: Vec::new(),
+                    })
+                }
+                token => tokens.push(token),
+            }
+        }
+
+        let definition = MacroDefinition {
+            params: None,
+            variadic: false,
+            body: expand::from_directive_tokens(&tokens, |range| Location::new(None, range)),
+            location: Location::new(None, TextRange::default()),
+        };
+        expand::check_body(&definition).map_err(|pending| PreprocessError {
+            kind: pending.kind,
+            location: None,
+            backtrace: Vec::new(),
+        })?;
+        Ok(definition)
+    }
+
+    /// Removes a macro defined with [`define`](Preprocessor::define).
+    pub fn undefine(&mut self, name: &str) -> &mut Self {
+        self.macros.remove(name);
+        self
+    }
+
+    /// Registers the handler for directives named `name`.
+    ///
+    /// Fails if `name` is not an identifier, or is the name of a built-in directive like `define`,
+    /// since those cannot be overridden.
+    pub fn register_directive(
+        &mut self,
+        name: impl Into<String>,
+        handler: impl CustomDirectiveHandler + 'r,
+    ) -> Result<&mut Self, PreprocessError> {
+        let name = name.into();
+        let kind = if name.is_empty() || ident_len(&name) != name.len() {
+            Some(PreprocessErrorKind::InvalidDirectiveName(name))
+        } else if BUILTIN_DIRECTIVES.contains(&name.as_str()) {
+            Some(PreprocessErrorKind::BuiltinDirectiveOverridden(name))
+        } else {
+            self.custom_directives.insert(name, Box::new(handler));
+            None
+        };
+        match kind {
+            Some(kind) => Err(PreprocessError {
+                kind,
+                location: None,
+                backtrace: Vec::new(),
+            }),
+            None => Ok(self),
+        }
+    }
+
+    /// Sets whether directives without a built-in meaning or registered handler are copied to the
+    /// output unchanged, instead of being rejected. Defaults to `false`.
+    pub fn pass_through_unknown_directives(&mut self, pass_through: bool) -> &mut Self {
+        self.pass_through_unknown_directives = pass_through;
+        self
+    }
+
+    /// Sets how the preprocessed code is laid out. Defaults to [`OutputMode::Compact`].
+    pub fn set_output_mode(&mut self, output_mode: OutputMode) -> &mut Self {
+        self.output_mode = output_mode;
+        self
+    }
+
+    /// Sets the path that modules are found from. Defaults to the empty path.
+    ///
+    /// `#import lighting::pbr;` imports the file that the resolver finds at
+    /// `module_root/lighting/pbr.wgsl`, as if it was the file preprocessing started from.
+    pub fn set_module_root(&mut self, module_root: impl Into<String>) -> &mut Self {
+        self.module_root = module_root.into();
+        self
+    }
+
+    /// Sets whether the names declared by modules are mangled. Defaults to false.
+    ///
+    /// When enabled, `fn saturate` in the module `lighting::pbr` becomes
+    /// `fn lighting__pbr__saturate`, so that modules can declare the same names. Uses of the name
+    /// are rewritten in the module and in the files that import it, where `lighting::pbr::saturate`
+    /// can also name it explicitly. [`PreprocessOutput::demangle_table`] maps the mangled names
+    /// back.
+    pub fn set_mangle_names(&mut self, mangle_names: bool) -> &mut Self {
+        self.mangle_names = mangle_names;
+        self
+    }
+
+    /// Sets whether unused declarations are removed from the output. Defaults to false.
+    ///
+    /// When enabled, `fn`, `const`, `struct` and `alias` declarations are removed unless they are
+    /// entry points (`@vertex`, `@fragment` or `@compute`) or kept with
+    /// [`keep_symbol`](Preprocessor::keep_symbol), or are used by those or by the declarations that
+    /// are always kept, like `var`s, directly or through other declarations. The source map stays
+    /// correct for the remaining code.
+    pub fn set_eliminate_dead_code(&mut self, eliminate_dead_code: bool) -> &mut Self {
+        self.eliminate_dead_code = eliminate_dead_code;
+        self
+    }
+
+    /// Keeps the declaration named `name` when eliminating dead code. Mangled names can be kept by
+    /// their qualified names, like `lighting::saturate`.
+    pub fn keep_symbol(&mut self, name: impl Into<String>) -> &mut Self {
+        self.kept_symbols.insert(name.into());
+        self
+    }
+
+    /// Sets how deeply macro invocations may be nested, in the expansions of other macros or in
+    /// their arguments. Defaults to 256.
+    ///
+    /// Exceeding the limit fails with [`PreprocessErrorKind::ExpansionDepthLimit`].
+    pub fn set_max_expansion_depth(&mut self, max_depth: usize) -> &mut Self {
+        self.limits.max_depth = max_depth;
+        self
+    }
+
+    /// Sets how many bytes of code preprocessing may produce, counting macro expansions that are
+    /// expanded further. Defaults to 16 MiB.
+    ///
+    /// Exceeding the limit fails with [`PreprocessErrorKind::OutputSizeLimit`]. Together with
+    /// [`set_max_expansion_depth`](Preprocessor::set_max_expansion_depth), this bounds the time
+    /// that untrusted sources can make preprocessing take.
+    pub fn set_max_output_size(&mut self, max_output_size: usize) -> &mut Self {
+        self.limits.max_output_size = max_output_size;
+        self
+    }
+
+    /// Preprocesses the file that the resolver finds at `path`.
+    pub fn preprocess(&self, path: &str) -> Result<PreprocessOutput, PreprocessError> {
+        let resolved = self.resolve_root(path)?;
+        self.preprocess_source(&resolved.path, &resolved.source)
+    }
+
+    /// Preprocesses the file that the resolver finds at `path` once for each permutation of
+    /// `matrix` that `filter` accepts, with the permutation's defines applied on top of the
+    /// preprocessor's.
+    pub fn preprocess_permutations(
+        &self,
+        path: &str,
+        matrix: &PermutationMatrix,
+        mut filter: impl FnMut(&Permutation) -> bool,
+    ) -> Result<PermutationOutputs, PermutationError> {
+        let mut outputs = PermutationOutputs::default();
+        let mut output_indices: BTreeMap<String, usize> = BTreeMap::new();
+        for permutation in matrix.permutations() {
+            if !filter(&permutation) {
+                continue;
+            }
+            let result = self.resolve_root(path).and_then(|resolved| {
+                let mut macros = self.macros.clone();
+                for (name, maybe_value) in permutation.defines() {
+                    match maybe_value {
+                        Some(value) => {
+                            macros.insert(name.to_owned(), self.parse_define(name, value)?);
+                        }
+                        None => {
+                            macros.remove(name);
+                        }
+                    }
+                }
+                self.preprocess_with_macros(&resolved.path, &resolved.source, macros, None)
+            });
+            let output = match result {
+                Ok(output) => output,
+                Err(error) => {
+                    return Err(PermutationError {
+                        permutation,
+                        error: Box::new(error),
+                    })
+                }
+            };
+            let output_idx = *output_indices
+                .entry(output.code.clone())
+                .or_insert_with(|| {
+                    outputs.outputs.push(output);
+                    outputs.outputs.len() - 1
+                });
+            outputs.permutations.push((permutation, output_idx));
+        }
+        Ok(outputs)
+    }
+
+    /// Preprocesses the file that the resolver finds at `path`, reusing the output in `cache` if
+    /// the preprocessor's defines and settings and the contents of every file it read are the
+    /// same. Otherwise the output is preprocessed again and kept in `cache`.
+    ///
+    /// Errors are not cached.
+    pub fn preprocess_cached(
+        &self,
+        path: &str,
+        cache: &mut PreprocessCache,
+    ) -> Result<Arc<PreprocessOutput>, PreprocessError> {
+        let key = (path.to_owned(), self.settings_hash());
+        let content_hash = |path: &str| {
+            let resolved = self.resolver.resolve(path, None).ok()?;
+            Some(content_hash(&resolved.source))
+        };
+        if let Some(output) = cache.get(&key, content_hash) {
+            return Ok(output);
+        }
+        let resolved = self.resolve_root(path)?;
+        let output = self.preprocess_with_macros(
+            &resolved.path,
+            &resolved.source,
+            self.macros.clone(),
+            Some(&mut cache.directives),
+        )?;
+        Ok(cache.insert(key, output))
+    }
+
+    // Hashes everything but the resolver and the custom directive handlers that the output
+    // depends on.
+    fn settings_hash(&self) -> u64 {
+        cache::hash_of(&(
+            &self.macros,
+            self.syntax_settings,
+            self.pass_through_unknown_directives,
+            self.output_mode,
+            self.limits,
+            &self.module_root,
+            (
+                self.mangle_names,
+                self.eliminate_dead_code,
+                &self.kept_symbols,
+            ),
+        ))
+    }
+
+    fn resolve_root(&self, path: &str) -> Result<ResolvedFile, PreprocessError> {
+        self.resolver
+            .resolve(path, None)
+            .map_err(|error| PreprocessError {
+                kind: PreprocessErrorKind::Include {
+                    path: path.to_owned(),
+                    error,
+                },
+                location: None,
+                backtrace: Vec::new(),
+            })
+    }
+
+    /// Preprocesses `source`. Includes are resolved as if `source` was found at `path`.
+    pub fn preprocess_source(
+        &self,
+        path: &str,
+        source: &str,
+    ) -> Result<PreprocessOutput, PreprocessError> {
+        self.preprocess_with_macros(path, source, self.macros.clone(), None)
+    }
+
+    fn preprocess_with_macros(
+        &self,
+        path: &str,
+        source: &str,
+        macros: MacroTable,
+        directive_cache: Option<&mut DirectiveCache>,
+    ) -> Result<PreprocessOutput, PreprocessError> {
+        let mut run = Run {
+            preprocessor: self,
+            directive_cache,
+            files: FileDatabase::default(),
+            macros,
+            code: String::new(),
+            include_stack: Vec::new(),
+            include_sites: Vec::new(),
+            once_files: BTreeSet::new(),
+            warnings: Vec::new(),
+            deferred_code: String::new(),
+            deferred_spans: Vec::new(),
+            import_stack: Vec::new(),
+            imported_modules: BTreeMap::new(),
+            modules: Vec::new(),
+            module_imports: Vec::new(),
+            source_map: SourceMapBuilder::default(),
+            counter: 0,
+            produced: 0,
+            looked_up: BTreeSet::new(),
+        };
+        let root_id = run.files.insert(SourceFile {
+            path: path.to_owned(),
+            source: Arc::from(source),
+        });
+        run.process_file(root_id, None)
+            .map_err(|pending| pending.resolve(&run.files))?;
+        let mut demangle_table = BTreeMap::new();
+        if self.mangle_names {
+            demangle_table = run.mangle_names();
+        }
+        // Modules are independent of lines, so they go after the preprocessed file in
+        // line-preserving mode, like included files.
+        let line_preserving = self.output_mode == OutputMode::LinePreserving;
+        let (module_code, mut module_spans) =
+            modules::link(&run.modules, &run.module_imports, line_preserving);
+        if line_preserving {
+            source_map::shift_spans(&mut module_spans, run.deferred_code.len());
+            run.deferred_spans.extend(module_spans);
+            run.deferred_code.push_str(&module_code);
+        } else if !module_code.is_empty() {
+            let mut spans = run.source_map.map.take_spans(0);
+            source_map::shift_spans(&mut spans, module_code.len());
+            run.source_map.map.extend_spans(module_spans);
+            run.source_map.map.extend_spans(spans);
+            run.code.insert_str(0, &module_code);
+        }
+        if !run.deferred_code.is_empty() {
+            if !run.code.is_empty() && !run.code.ends_with('\n') {
+                run.code.push('\n');
+            }
+            source_map::shift_spans(&mut run.deferred_spans, run.code.len());
+            run.source_map.map.extend_spans(run.deferred_spans);
+            run.code.push_str(&run.deferred_code);
+        }
+        if self.eliminate_dead_code {
+            let is_root = |declaration: &Declaration<'_>| {
+                declaration.is_entry_point()
+                    || declaration.name.is_some_and(|name| {
+                        self.kept_symbols.contains(name.inner_str)
+                            || demangle_table
+                                .get(name.inner_str)
+                                .is_some_and(|qualified| self.kept_symbols.contains(qualified))
+                    })
+            };
+            let spans = run.source_map.map.take_spans(0);
+            let (code, spans) =
+                dead_code::eliminate_dead_code(&run.code, spans, is_root, line_preserving);
+            run.code = code;
+            run.source_map.map.extend_spans(spans);
+        }
+
+        let mut diagnostics = Diagnostics::default();
+        for (kind, location) in run.warnings {
+            diagnostics.push(Diagnostic {
+                kind,
+                location: resolve_location(&location, &run.files),
+                backtrace: source_map::backtrace(&location, &run.files),
+            });
+        }
+        Ok(PreprocessOutput {
+            code: run.code,
+            files: run.files,
+            diagnostics,
+            source_map: run.source_map.map,
+            demangle_table,
+            output_mode: self.output_mode,
+            used_defines: run.looked_up,
+        })
+    }
+}
+
+/// The result of preprocessing.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PreprocessOutput {
+    /// The preprocessed WGSL.
+    pub code: String,
+    /// Every file that was read, starting with the preprocessed file.
+    pub files: FileDatabase,
+    /// The warnings found while preprocessing.
+    pub diagnostics: Diagnostics,
+    /// Where the tokens of `code` came from.
+    pub source_map: SourceMap,
+    /// Maps the mangled names in `code` to the qualified names they were mangled from, like
+    /// `lighting__saturate` to `lighting::saturate`. Empty unless
+    /// [`Preprocessor::set_mangle_names`] is enabled.
+    pub demangle_table: BTreeMap<String, String>,
+    /// How `code` is laid out.
+    pub output_mode: OutputMode,
+    /// The names that were looked up as macros, whether they were defined or not: by `#ifdef`,
+    /// `#ifndef` and `defined`, and for every identifier that could have been expanded.
+    ///
+    /// Preprocessing with defines that only differ outside of this set gives the same output, so
+    /// caches of permutations can be keyed on the defines in this set alone.
+    pub used_defines: BTreeSet<String>,
+}
+
+impl PreprocessOutput {
+    /// Returns where the token that contains byte `offset` of the code came from.
+    pub fn origin_at(&self, offset: usize) -> Option<Origin> {
+        self.source_map.origin_at(offset, &self.files)
+    }
+
+    /// Returns a Makefile-style depfile that makes `target`, the path the code is written to,
+    /// depend on every file that was read. See [`depfile`].
+    pub fn depfile(&self, target: &str) -> String {
+        depfile(
+            [target],
+            self.files.iter().map(|(_, file)| file.path.as_str()),
+        )
+    }
+
+    /// Returns the names of the `@vertex`, `@fragment` and `@compute` functions in the code.
+    pub fn entry_points(&self) -> Vec<String> {
+        scan_declarations(LocatedStr::new(&self.code))
+            .iter()
+            .filter(|declaration| declaration.is_entry_point())
+            .filter_map(|declaration| declaration.name)
+            .map(|name| name.inner_str.to_owned())
+            .collect()
+    }
+
+    /// Returns the output for the entry point `name` alone, or `None` if there is no such entry
+    /// point.
+    ///
+    /// The other entry points are removed, and so are the `fn`, `const`, `struct` and `alias`
+    /// declarations that the entry point does not use, like with
+    /// [`Preprocessor::set_eliminate_dead_code`]. The source map is updated to match.
+    pub fn entry_point(&self, name: &str) -> Option<PreprocessOutput> {
+        let is_chosen = |declaration: &Declaration<'_>| {
+            declaration.is_entry_point() && declaration.name.is_some_and(|n| n.inner_str == name)
+        };
+        if !scan_declarations(LocatedStr::new(&self.code))
+            .iter()
+            .any(is_chosen)
+        {
+            return None;
+        }
+        let mut source_map = self.source_map.clone();
+        let spans = source_map.take_spans(0);
+        let line_preserving = self.output_mode == OutputMode::LinePreserving;
+        let (code, spans) =
+            dead_code::eliminate_dead_code(&self.code, spans, is_chosen, line_preserving);
+        source_map.extend_spans(spans);
+        Some(PreprocessOutput {
+            code,
+            files: self.files.clone(),
+            diagnostics: self.diagnostics.clone(),
+            source_map,
+            demangle_table: self.demangle_table.clone(),
+            output_mode: self.output_mode,
+            used_defines: self.used_defines.clone(),
+        })
+    }
+
+    /// Replaces the mangled names in `text`, such as a shader compiler's error message, with the
+    /// names they were mangled from.
+    pub fn demangle(&self, text: &str) -> String {
+        mangle::demangle(text, &self.demangle_table)
+    }
+}
+
+// The state of one preprocessing run.
+struct Run<'p, 'r> {
+    preprocessor: &'p Preprocessor<'r>,
+    // Where the directives of files are kept between runs, if anywhere.
+    directive_cache: Option<&'p mut DirectiveCache>,
+    files: FileDatabase,
+    macros: MacroTable,
+    code: String,
+    include_stack: Vec<FileId>,
+    // The `#include` and `#import` directives of the files in `include_stack` after the first.
+    include_sites: Vec<Rc<IncludeSite>>,
+    once_files: BTreeSet<FileId>,
+    warnings: Vec<(DiagnosticKind, Location)>,
+    // Included files that go after the preprocessed file in line-preserving mode.
+    deferred_code: String,
+    deferred_spans: Vec<Span>,
+    // The modules being imported, innermost last, with their paths.
+    import_stack: Vec<(FileId, String)>,
+    // The indices of imported modules in `modules`.
+    imported_modules: BTreeMap<FileId, usize>,
+    // Imported modules in the order they finished, which goes before the preprocessed file in
+    // compact mode.
+    modules: Vec<Module>,
+    module_imports: Vec<ModuleImport>,
+    source_map: SourceMapBuilder,
+    // The next value of `__COUNTER__`.
+    counter: u64,
+    // The number of bytes that macro expansion produced, counted against the output size limit.
+    produced: usize,
+    // The names looked up as macros, defined or not.
+    looked_up: BTreeSet<String>,
+}
+
+// An open `#if`, `#ifdef` or `#ifndef`.
+struct Conditional {
+    // Whether the lines after the last directive of the conditional are emitted.
+    branch_active: bool,
+    // Whether the conditional is inside of an emitted region.
+    parent_active: bool,
+    any_branch_taken: bool,
+    else_seen: bool,
+}
+
+impl<'p, 'r> Run<'p, 'r> {
+    // Processes a whole file, or only its `anchor` region. Returns whether the anchor was found.
+    fn process_file(
+        &mut self,
+        file_id: FileId,
+        maybe_anchor: Option<&str>,
+    ) -> Result<bool, PendingError> {
+        self.include_stack.push(file_id);
+        let source = self.files.get(file_id).source.clone();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut open_anchors: Vec<&str> = Vec::new();
+        let mut anchor_found = false;
+        let mut code_start = 0;
+
+        let syntax_settings = self.preprocessor.syntax_settings;
+        let scanned = self
+            .directive_cache
+            .as_deref_mut()
+            .map(|cache| cache.scan(&source, syntax_settings));
+        let directives: Box<dyn Iterator<Item = _>> = match &scanned {
+            Some(scanned) => Box::new(scanned.directives()),
+            None => Box::new(DirectiveIter::new(
+                LocatedStr::new(&source),
+                syntax_settings,
+            )),
+        };
+        for directive_result in directives {
+            let in_region = maybe_anchor.is_none_or(|anchor| open_anchors.contains(&anchor));
+            let active = in_region && conditionals.last().is_none_or(|c| c.branch_active);
+            let directive = match directive_result {
+                Ok(directive) => directive,
+                // Like in C, skipped lines only need to be lexically valid.
+                Err(_) if !active => continue,
+                Err(error) => {
+                    return Err(PendingError {
+                        kind: PreprocessErrorKind::Directive(error.kind),
+                        location: Box::new(self.location(error.range)),
+                    })
+                }
+            };
+            let location = self.location(directive.range());
+            let lines = directive_lines(&source, directive.range());
+            if active {
+                self.emit_code(&source, code_start..lines.start)?;
+            } else if in_region {
+                self.emit_blank_lines(&source[code_start..lines.start]);
+            }
+            code_start = lines.end;
+            // The number of lines that replaced the directive.
+            let mut replacement_lines = 0;
+
+            match directive {
+                Directive::Anchor(anchor) => {
+                    if maybe_anchor == Some(anchor.name.inner_str) {
+                        anchor_found = true;
+                    }
+                    open_anchors.push(anchor.name.inner_str);
+                }
+                Directive::EndAnchor(_) => {
+                    if open_anchors.pop().is_none() {
+                        return Err(PendingError {
+                            kind: PreprocessErrorKind::UnmatchedDirective("endanchor"),
+                            location: Box::new(location),
+                        });
+                    }
+                }
+                _ if !in_region => {}
+                Directive::If(_) | Directive::Ifdef(_) | Directive::Ifndef(_) => {
+                    let parent_active = active;
+                    let branch_active = parent_active && self.evaluate(&directive)?;
+                    conditionals.push(Conditional {
+                        branch_active,
+                        parent_active,
+                        any_branch_taken: branch_active,
+                        else_seen: false,
+                    });
+                }
+                Directive::Elif(_) | Directive::Else(_) | Directive::Endif(_) => {
+                    let Some(conditional) = conditionals.last_mut() else {
+                        return Err(PendingError {
+                            kind: PreprocessErrorKind::UnmatchedDirective(closing_name(&directive)),
+                            location: Box::new(location),
+                        });
+                    };
+                    if conditional.else_seen && !matches!(directive, Directive::Endif(_)) {
+                        return Err(PendingError {
+                            kind: PreprocessErrorKind::DirectiveAfterElse(closing_name(&directive)),
+                            location: Box::new(location),
+                        });
+                    }
+                    match directive {
+                        Directive::Elif(_) => {
+                            let take = conditional.parent_active && !conditional.any_branch_taken;
+                            let branch_active = take && self.evaluate(&directive)?;
+                            conditional.branch_active = branch_active;
+                            conditional.any_branch_taken |= branch_active;
+                        }
+                        Directive::Else(_) => {
+                            conditional.branch_active =
+                                conditional.parent_active && !conditional.any_branch_taken;
+                            conditional.any_branch_taken = true;
+                            conditional.else_seen = true;
+                        }
+                        _ => {
+                            conditionals.pop();
+                        }
+                    }
+                }
+                _ if !active => {}
+                Directive::Include(include) => {
+                    let path = include.path.value();
+                    let maybe_anchor = include.anchor.map(|anchor| anchor.inner_str);
+                    self.include(file_id, &path, maybe_anchor, location)?;
+                }
+                Directive::Import(import) => self.import(&import, location)?,
+                Directive::Define(define) => {
+                    self.check_not_builtin(define.name)?;
+                    let definition = MacroDefinition {
+                        params: define.params.map(|params| {
+                            let named = params.iter().map(|p| p.inner_str.to_owned());
+                            let variadic = define.variadic.then(|| "__VA_ARGS__".to_owned());
+                            named.chain(variadic).collect()
+                        }),
+                        variadic: define.variadic,
+                        body: expand::from_directive_tokens(&define.body, |range| {
+                            self.location(range)
+                        }),
+                        location: self.location(define.name.range()),
+                    };
+                    expand::check_body(&definition)?;
+                    self.macros
+                        .insert(define.name.inner_str.to_owned(), definition);
+                }
+                Directive::Undef(undef) => {
+                    self.check_not_builtin(undef.name)?;
+                    self.macros.remove(undef.name.inner_str);
+                }
+                Directive::Error(error) => {
+                    return Err(PendingError {
+                        kind: PreprocessErrorKind::ErrorDirective(error.message.value()),
+                        location: Box::new(location),
+                    });
+                }
+                Directive::Warning(warning) => {
+                    self.warnings.push((
+                        DiagnosticKind::WarningDirective(warning.message.value()),
+                        location,
+                    ));
+                }
+                Directive::Pragma(pragma) => {
+                    let is_once = matches!(
+                        pragma.tokens.as_slice(),
+                        [MacroTokenResult::AlphanumStringToken(s)] if s.inner_str == "once"
+                    );
+                    if is_once {
+                        self.once_files.insert(file_id);
+                    }
+                }
+                Directive::Custom(custom) => {
+                    let name = custom.name.inner_str;
+                    match self.preprocessor.custom_directives.get(name) {
+                        Some(handler) => {
+                            let context = DirectiveContext {
+                                path: &self.files.get(file_id).path,
+                            };
+                            let replacement =
+                                handler.handle(&custom, &context).map_err(|error| {
+                                    PendingError {
+                                        kind: PreprocessErrorKind::CustomDirective {
+                                            name: name.to_owned(),
+                                            message: error.message,
+                                        },
+                                        location: Box::new(
+                                            self.location(error.range.unwrap_or(location.range)),
+                                        ),
+                                    }
+                                })?;
+                            replacement_lines = self.emit_lines(&replacement);
+                        }
+                        None if self.preprocessor.pass_through_unknown_directives => {
+                            replacement_lines = self.emit_lines(&source[lines.clone()]);
+                        }
+                        None => {
+                            return Err(PendingError {
+                                kind: PreprocessErrorKind::UnknownDirective(name.to_owned()),
+                                location: Box::new(location),
+                            });
+                        }
+                    }
+                }
+            }
+            if in_region && self.preprocessor.output_mode == OutputMode::LinePreserving {
+                let directive_line_count = source[lines].matches('\n').count();
+                self.push_newlines(directive_line_count.saturating_sub(replacement_lines));
+            }
+        }
+
+        let in_region = maybe_anchor.is_none();
+        let active = in_region && conditionals.is_empty();
+        if active {
+            self.emit_code(&source, code_start..source.len())?;
+        } else if in_region {
+            self.emit_blank_lines(&source[code_start..]);
+        }
+        if !conditionals.is_empty() {
+            return Err(PendingError {
+                kind: PreprocessErrorKind::UnterminatedConditional,
+                location: Box::new(
+                    self.location(TextRange::empty_at(LocatedStr::new(&source).end_location())),
+                ),
+            });
+        }
+
+        self.include_stack.pop();
+        Ok(anchor_found)
+    }
+
+    fn include(
+        &mut self,
+        including_file: FileId,
+        path: &str,
+        maybe_anchor: Option<&str>,
+        location: Location,
+    ) -> Result<(), PendingError> {
+        let including_path = &self.files.get(including_file).path;
+        let resolved = self
+            .preprocessor
+            .resolver
+            .resolve(path, Some(including_path))
+            .map_err(|error| PendingError {
+                kind: PreprocessErrorKind::Include {
+                    path: path.to_owned(),
+                    error,
+                },
+                location: Box::new(location.clone()),
+            })?;
+        let included_file = self.files.insert(SourceFile {
+            path: resolved.path,
+            source: Arc::from(resolved.source),
+        });
+        if self.include_stack.contains(&included_file) {
+            return Err(PendingError {
+                kind: PreprocessErrorKind::RecursiveInclude {
+                    path: path.to_owned(),
+                },
+                location: Box::new(location.clone()),
+            });
+        }
+        if self.once_files.contains(&included_file) {
+            return Ok(());
+        }
+
+        let line_preserving = self.preprocessor.output_mode == OutputMode::LinePreserving;
+        // Only the preprocessed file's lines are preserved, and only its includes at module scope
+        // can be moved after it. Others stay in place, like in compact mode.
+        let top_level = line_preserving && self.include_stack.len() == 1;
+        let deferred = top_level && brace_depth(&self.code) == 0;
+        let outer_code = match line_preserving {
+            true => core::mem::take(&mut self.code),
+            false => String::new(),
+        };
+        // In line-preserving mode, the included file's spans are relative to its own output.
+        let span_count = self.source_map.map.span_count();
+        self.include_sites.push(Rc::new(IncludeSite {
+            location: location.clone(),
+            import: false,
+        }));
+        let anchor_found = self.process_file(included_file, maybe_anchor)?;
+        self.include_sites.pop();
+        if let (Some(anchor), false) = (maybe_anchor, anchor_found) {
+            return Err(PendingError {
+                kind: PreprocessErrorKind::AnchorNotFound {
+                    anchor: anchor.to_owned(),
+                },
+                location: Box::new(location),
+            });
+        }
+        if !self.code.is_empty() && !self.code.ends_with('\n') {
+            self.code.push('\n');
+        }
+
+        if line_preserving {
+            let included_code = core::mem::replace(&mut self.code, outer_code);
+            let included_path = &self.files.get(included_file).path;
+            let header = format!("// yawgsl: begin include \"{included_path}\"\n");
+            let wrapped =
+                format!("{header}{included_code}// yawgsl: end include \"{included_path}\"\n");
+            let mut included_spans = self.source_map.map.take_spans(span_count);
+            match deferred {
+                true => {
+                    let offset = self.deferred_code.len() + header.len();
+                    source_map::shift_spans(&mut included_spans, offset);
+                    self.deferred_spans.extend(included_spans);
+                    self.deferred_code.push_str(&wrapped);
+                }
+                false => {
+                    let offset = self.code.len() + header.len();
+                    source_map::shift_spans(&mut included_spans, offset);
+                    self.source_map.map.extend_spans(included_spans);
+                    self.code.push_str(&wrapped);
+                }
+            }
+        }
+        if top_level && !deferred {
+            let path = self.files.get(included_file).path.clone();
+            self.warnings
+                .push((DiagnosticKind::IncludeShiftsLines(path), location));
+        }
+        Ok(())
+    }
+
+    fn import(&mut self, import: &Import<'_>, location: Location) -> Result<(), PendingError> {
+        let module_path = import.module_path();
+        let segments: Vec<&str> = import.path.iter().map(|s| s.inner_str).collect();
+        let path = match self.preprocessor.module_root.as_str() {
+            "" => format!("{}.wgsl", segments.join("/")),
+            root => format!("{root}/{}.wgsl", segments.join("/")),
+        };
+        let resolved = self
+            .preprocessor
+            .resolver
+            .resolve(&path, None)
+            .map_err(|error| PendingError {
+                kind: PreprocessErrorKind::Import {
+                    module: module_path.clone(),
+                    error,
+                },
+                location: Box::new(location.clone()),
+            })?;
+        let module_file = self.files.insert(SourceFile {
+            path: resolved.path,
+            source: Arc::from(resolved.source),
+        });
+        let module_idx = match self.imported_modules.get(&module_file) {
+            Some(&module_idx) => module_idx,
+            None => self.process_module(module_file, module_path, location)?,
+        };
+
+        if let Some(items) = &import.items {
+            let module = &self.modules[module_idx];
+            if let Some(missing) = items.iter().find(|item| !module.declares(item.inner_str)) {
+                return Err(PendingError {
+                    kind: PreprocessErrorKind::ImportedItemNotFound {
+                        module: import.module_path(),
+                        item: missing.inner_str.to_owned(),
+                    },
+                    location: Box::new(self.location(missing.range())),
+                });
+            }
+        }
+        self.module_imports.push(ModuleImport {
+            importer: self.import_stack.last().map(|(file, _)| *file),
+            module: module_idx,
+            items: import
+                .items
+                .as_ref()
+                .map(|items| items.iter().map(|item| item.inner_str.to_owned()).collect()),
+        });
+        Ok(())
+    }
+
+    // Processes a module that was not imported yet, and returns its index in `modules`.
+    fn process_module(
+        &mut self,
+        module_file: FileId,
+        module_path: String,
+        location: Location,
+    ) -> Result<usize, PendingError> {
+        if self.include_stack.contains(&module_file) {
+            // The cycle starts at the module, or at the preprocessed file if that is not one.
+            let start = self
+                .import_stack
+                .iter()
+                .position(|(file, _)| *file == module_file);
+            let mut cycle: Vec<String> = match start {
+                Some(_) => Vec::new(),
+                None => alloc::vec![self.files.get(module_file).path.clone()],
+            };
+            let stack = &self.import_stack[start.unwrap_or(0)..];
+            cycle.extend(stack.iter().map(|(_, path)| path.clone()));
+            cycle.push(module_path);
+            return Err(PendingError {
+                kind: PreprocessErrorKind::ImportCycle(cycle),
+                location: Box::new(location),
+            });
+        }
+
+        let outer_code = core::mem::take(&mut self.code);
+        let span_count = self.source_map.map.span_count();
+        self.import_stack.push((module_file, module_path.clone()));
+        self.include_sites.push(Rc::new(IncludeSite {
+            location,
+            import: true,
+        }));
+        self.process_file(module_file, None)?;
+        self.include_sites.pop();
+        self.import_stack.pop();
+        if !self.code.is_empty() && !self.code.ends_with('\n') {
+            self.code.push('\n');
+        }
+
+        let module_idx = self.modules.len();
+        self.imported_modules.insert(module_file, module_idx);
+        self.modules.push(Module {
+            file: module_file,
+            path: module_path,
+            code: core::mem::replace(&mut self.code, outer_code),
+            spans: self.source_map.map.take_spans(span_count),
+        });
+        Ok(module_idx)
+    }
+
+    // Rewrites the names declared by modules, in the modules and in the preprocessed file, and
+    // returns the demangle table.
+    fn mangle_names(&mut self) -> BTreeMap<String, String> {
+        let mangler = Mangler::new(&self.modules);
+        for (module_idx, module) in self.modules.iter_mut().enumerate() {
+            let spans = core::mem::take(&mut module.spans);
+            let (code, spans) = mangler.rewrite(
+                &module.code,
+                spans,
+                Some(module.file),
+                Owner::Module(module_idx),
+                &self.module_imports,
+            );
+            (module.code, module.spans) = (code, spans);
+        }
+        // Included files share the preprocessed file's names, wherever their code goes.
+        let mut root_names = mangle::declared_names(&self.code);
+        root_names.extend(mangle::declared_names(&self.deferred_code));
+        let root = || Owner::Root(&root_names);
+        let spans = self.source_map.map.take_spans(0);
+        let (code, spans) = mangler.rewrite(&self.code, spans, None, root(), &self.module_imports);
+        self.code = code;
+        self.source_map.map.extend_spans(spans);
+        let spans = core::mem::take(&mut self.deferred_spans);
+        let (code, spans) = mangler.rewrite(
+            &self.deferred_code,
+            spans,
+            None,
+            root(),
+            &self.module_imports,
+        );
+        (self.deferred_code, self.deferred_spans) = (code, spans);
+
+        for import in &mut self.module_imports {
+            for item in import.items.iter_mut().flatten() {
+                *item = mangler.mangle_item(import.module, item);
+            }
+        }
+        mangler.demangle_table()
+    }
+
+    fn check_not_builtin(&self, name: LocatedStr<'_>) -> Result<(), PendingError> {
+        match BUILTIN_MACROS.contains(&name.inner_str) {
+            true => Err(PendingError {
+                kind: PreprocessErrorKind::BuiltinMacroRedefined(name.inner_str.to_owned()),
+                location: Box::new(self.location(name.range())),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    // Returns the location of `range` in the file being processed.
+    fn location(&self, range: TextRange) -> Location {
+        Location {
+            file: self.include_stack.last().copied(),
+            range,
+            included_from: self.include_sites.last().cloned(),
+            expansion: None,
+        }
+    }
+
+    fn is_defined(&mut self, name: &str) -> bool {
+        if !self.looked_up.contains(name) {
+            self.looked_up.insert(name.to_owned());
+        }
+        self.macros.contains_key(name) || BUILTIN_MACROS.contains(&name)
+    }
+
+    fn expander(&mut self) -> Expander<'_> {
+        Expander {
+            macros: &self.macros,
+            files: &self.files,
+            counter: &mut self.counter,
+            limits: self.preprocessor.limits,
+            produced: &mut self.produced,
+            looked_up: &mut self.looked_up,
+        }
+    }
+
+    fn evaluate(&mut self, directive: &Directive<'_>) -> Result<bool, PendingError> {
+        let location = self.location(directive.range());
+        let condition = match directive {
+            Directive::Ifdef(ifdef) => return Ok(self.is_defined(ifdef.name.inner_str)),
+            Directive::Ifndef(ifndef) => return Ok(!self.is_defined(ifndef.name.inner_str)),
+            Directive::If(if_directive) => &if_directive.condition,
+            Directive::Elif(elif) => &elif.condition,
+            _ => unreachable!(),
+        };
+        let tokens = expand::from_directive_tokens(condition, |range| self.location(range));
+        let tokens = expr::resolve_defined(tokens, |name| self.is_defined(name))?;
+        let tokens = self.expander().expand(&tokens)?;
+        Ok(expr::evaluate(&tokens, location)? != 0)
+    }
+
+    fn emit_code(&mut self, source: &str, byte_range: Range<usize>) -> Result<(), PendingError> {
+        if byte_range.is_empty() {
+            return Ok(());
+        }
+        let code = LocatedStr::new(source).get_unchecked(byte_range);
+        let tokens = expand::lex_code(code, |range| self.location(range));
+        let expanded = self.expander().expand(&tokens)?;
+        for token in &expanded {
+            if token.kind != PpTokenKind::Whitespace {
+                let start = self.code.len();
+                self.source_map
+                    .record(start..start + token.text.len(), &token.location);
+            }
+            self.code.push_str(&token.text);
+        }
+        Ok(())
+    }
+
+    // Emits text that replaces whole lines. Returns the number of lines emitted.
+    fn emit_lines(&mut self, text: &str) -> usize {
+        self.code.push_str(text);
+        if !text.is_empty() && !text.ends_with('\n') {
+            self.code.push('\n');
+        }
+        text.lines().count()
+    }
+
+    // Emits a blank line for each line of `skipped`, in line-preserving mode.
+    fn emit_blank_lines(&mut self, skipped: &str) {
+        if self.preprocessor.output_mode == OutputMode::LinePreserving {
+            self.push_newlines(skipped.matches('\n').count());
+        }
+    }
+
+    fn push_newlines(&mut self, count: usize) {
+        self.code.extend(core::iter::repeat_n('\n', count));
+    }
+}
+
+// Returns the byte range of the whole lines that a directive spans, including the final newline.
+fn directive_lines(source: &str, range: TextRange) -> Range<usize> {
+    let byte_range = range.byte_range();
+    let start = source[..byte_range.start].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[byte_range.end..]
+        .find('\n')
+        .map_or(source.len(), |i| byte_range.end + i + 1);
+    start..end
+}
+
+// Returns how many braces are open at the end of `code`.
+fn brace_depth(code: &str) -> usize {
+    TokenIter::new(LocatedStr::new(code)).fold(0, |depth, token| {
+        match (token.kind, token.text.inner_str) {
+            (TokenKind::Symbol, "{") => depth + 1,
+            (TokenKind::Symbol, "}") => depth.saturating_sub(1),
+            _ => depth,
+        }
+    })
+}
+
+// The name of a directive that closes or continues a conditional, for error messages.
+fn closing_name(directive: &Directive<'_>) -> &'static str {
+    match directive {
+        Directive::Elif(_) => "elif",
+        Directive::Else(_) => "else",
+        _ => "endif",
+    }
+}
+
+// An error whose location has not been resolved to a path yet.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct PendingError {
+    pub(crate) kind: PreprocessErrorKind,
+    pub(crate) location: Box<Location>,
+}
+
+impl PendingError {
+    fn resolve(self, files: &FileDatabase) -> PreprocessError {
+        PreprocessError {
+            kind: self.kind,
+            location: resolve_location(&self.location, files),
+            backtrace: source_map::backtrace(&self.location, files),
+        }
+    }
+}
+
+fn resolve_location(location: &Location, files: &FileDatabase) -> Option<Box<SourceLocation>> {
+    location.file.map(|file| {
+        Box::new(SourceLocation {
+            path: files.get(file).path.clone(),
+            range: location.range,
+        })
+    })
+}
+
+/// A range of text in a named file.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SourceLocation {
+    /// The file's path.
+    pub path: String,
+    /// The range in the file.
+    pub range: TextRange,
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.path,
+            self.range.start.line_num + 1,
+            self.range.start.col_num + 1
+        )
+    }
+}
+
+/// An error that stopped preprocessing.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PreprocessError {
+    /// What went wrong.
+    pub kind: PreprocessErrorKind,
+    /// Where it went wrong. `None` for errors in the preprocessor's configuration.
+    pub location: Option<Box<SourceLocation>>,
+    /// The macro invocations, includes and imports that brought the code where it went wrong into
+    /// the output, innermost first.
+    pub backtrace: Vec<BacktraceFrame>,
+}
+
+impl Display for PreprocessError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{location}: {}", self.kind)?,
+            None => write!(f, "{}", self.kind)?,
+        }
+        for frame in &self.backtrace {
+            write!(f, "\n  {frame}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for PreprocessError {}
+
+/// The kinds of [`PreprocessError`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum PreprocessErrorKind {
+    /// A directive could not be parsed.
+    Directive(DirectiveParseErrorKind),
+    /// An included file could not be resolved.
+    Include {
+        /// The path as written in the directive.
+        path: String,
+        /// Why it could not be resolved.
+        error: ResolveError,
+    },
+    /// A module could not be resolved.
+    Import {
+        /// The module's path as written in the directive.
+        module: String,
+        /// Why it could not be resolved.
+        error: ResolveError,
+    },
+    /// A module imports itself through other modules. The cycle lists the modules' paths,
+    /// starting and ending with the same module, or starting with the preprocessed file's path if
+    /// the cycle goes through it.
+    ImportCycle(Vec<String>),
+    /// An item imported with `#import module::{item}` is not declared by the module.
+    ImportedItemNotFound {
+        /// The module's path as written in the directive.
+        module: String,
+        /// The item's name.
+        item: String,
+    },
+    /// A file includes itself, directly or through other files.
+    RecursiveInclude {
+        /// The path as written in the directive.
+        path: String,
+    },
+    /// An included anchor does not exist in the included file.
+    AnchorNotFound {
+        /// The anchor's name.
+        anchor: String,
+    },
+    /// `#elif`, `#else`, `#endif` or `#endanchor` has no matching opening directive.
+    UnmatchedDirective(&'static str),
+    /// `#elif` or `#else` comes after the `#else` of the same conditional.
+    DirectiveAfterElse(&'static str),
+    /// A conditional is still open at the end of a file.
+    UnterminatedConditional,
+    /// An `#if` or `#elif` condition could not be evaluated.
+    InvalidCondition(&'static str),
+    /// A built-in macro such as `__LINE__` was defined or undefined.
+    BuiltinMacroRedefined(String),
+    /// `##` is the first or last token of a macro's body, or of the content of a `__VA_OPT__`.
+    PasteAtBodyEdge,
+    /// `#` in the body of a function-like macro is not followed by a parameter.
+    StringifyWithoutParameter,
+    /// `##` formed something other than a valid WGSL identifier.
+    InvalidPaste(String),
+    /// `__VA_ARGS__` or `__VA_OPT__` is used outside of a variadic macro.
+    VariadicOutsideVariadicMacro,
+    /// `__VA_OPT__` is not followed by parenthesized tokens.
+    InvalidVaOpt,
+    /// A function-like macro was invoked with the wrong number of arguments.
+    MacroArgumentCount {
+        /// The number of named parameters.
+        expected: usize,
+        /// Whether the macro takes further variadic arguments.
+        variadic: bool,
+        /// The number of arguments.
+        found: usize,
+    },
+    /// A function-like macro invocation has no closing parenthesis.
+    UnterminatedMacroCall,
+    /// Macro invocations are nested more deeply than the limit set with
+    /// [`Preprocessor::set_max_expansion_depth`].
+    ExpansionDepthLimit(usize),
+    /// Preprocessing produced more code than the limit set with
+    /// [`Preprocessor::set_max_output_size`].
+    OutputSizeLimit(usize),
+    /// An `#error` directive was reached.
+    ErrorDirective(String),
+    /// A custom directive handler returned an error.
+    CustomDirective {
+        /// The directive's name.
+        name: String,
+        /// The handler's message.
+        message: String,
+    },
+    /// A directive has no built-in meaning or registered handler.
+    UnknownDirective(String),
+    /// A handler was registered for a built-in directive.
+    BuiltinDirectiveOverridden(String),
+    /// A handler was registered for a name that is not an identifier.
+    InvalidDirectiveName(String),
+}
+
+impl Display for PreprocessErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PreprocessErrorKind::Directive(kind) => write!(f, "{kind}"),
+            PreprocessErrorKind::Include { path, error } => {
+                write!(f, "could not include \"{path}\": {error}")
+            }
+            PreprocessErrorKind::Import { module, error } => {
+                write!(f, "could not import `{module}`: {error}")
+            }
+            PreprocessErrorKind::ImportCycle(cycle) => {
+                write!(f, "import cycle: {}", cycle.join(" -> "))
+            }
+            PreprocessErrorKind::ImportedItemNotFound { module, item } => {
+                write!(f, "`{item}` not found in module `{module}`")
+            }
+            PreprocessErrorKind::RecursiveInclude { path } => {
+                write!(f, "\"{path}\" includes itself")
+            }
+            PreprocessErrorKind::AnchorNotFound { anchor } => {
+                write!(f, "anchor `{anchor}` not found")
+            }
+            PreprocessErrorKind::UnmatchedDirective(name) => {
+                write!(f, "#{name} without a matching opening directive")
+            }
+            PreprocessErrorKind::DirectiveAfterElse(name) => write!(f, "#{name} after #else"),
+            PreprocessErrorKind::UnterminatedConditional => {
+                write!(f, "conditional is not closed by #endif")
+            }
+            PreprocessErrorKind::InvalidCondition(message) => write!(f, "{message}"),
+            PreprocessErrorKind::BuiltinMacroRedefined(name) => {
+                write!(f, "cannot redefine built-in macro `{name}`")
+            }
+            PreprocessErrorKind::PasteAtBodyEdge => {
+                write!(
+                    f,
+                    "`##` cannot be at either end of a macro's body or a `__VA_OPT__`"
+                )
+            }
+            PreprocessErrorKind::StringifyWithoutParameter => {
+                write!(f, "`#` must be followed by a macro parameter")
+            }
+            PreprocessErrorKind::InvalidPaste(text) => {
+                write!(
+                    f,
+                    "pasting formed `{text}`, which is not a valid identifier"
+                )
+            }
+            PreprocessErrorKind::VariadicOutsideVariadicMacro => {
+                write!(
+                    f,
+                    "`__VA_ARGS__` and `__VA_OPT__` are only allowed in variadic macros"
+                )
+            }
+            PreprocessErrorKind::InvalidVaOpt => {
+                write!(f, "`__VA_OPT__` must be followed by parenthesized tokens")
+            }
+            PreprocessErrorKind::MacroArgumentCount {
+                expected,
+                variadic,
+                found,
+            } => {
+                let at_least = if *variadic { "at least " } else { "" };
+                write!(
+                    f,
+                    "macro takes {at_least}{expected} arguments but {found} were given"
+                )
+            }
+            PreprocessErrorKind::UnterminatedMacroCall => {
+                write!(f, "macro invocation has no closing parenthesis")
+            }
+            PreprocessErrorKind::ExpansionDepthLimit(max_depth) => {
+                write!(
+                    f,
+                    "macro expansion is nested more than {max_depth} levels deep"
+                )
+            }
+            PreprocessErrorKind::OutputSizeLimit(max_output_size) => {
+                write!(
+                    f,
+                    "preprocessing produced more than {max_output_size} bytes"
+                )
+            }
+            PreprocessErrorKind::ErrorDirective(message) => write!(f, "#error: {message}"),
+            PreprocessErrorKind::CustomDirective { name, message } => {
+                write!(f, "#{name}: {message}")
+            }
+            PreprocessErrorKind::UnknownDirective(name) => write!(f, "unknown directive #{name}"),
+            PreprocessErrorKind::BuiltinDirectiveOverridden(name) => {
+                write!(
+                    f,
+                    "cannot register a handler for the built-in directive `{name}`"
+                )
+            }
+            PreprocessErrorKind::InvalidDirectiveName(name) => {
+                write!(f, "`{name}` is not a valid directive name")
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use super::source_map::Span;
+use super::FileId;
+use crate::utils::LocatedStr;
+use crate::wgsl::{scan_declarations, Declaration, DeclarationKind};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+// An imported module's preprocessed code.
+pub(crate) struct Module {
+    pub(crate) file: FileId,
+    pub(crate) path: String,
+    pub(crate) code: String,
+    // Relative to `code`.
+    pub(crate) spans: Vec<Span>,
+}
+
+// An `#import` that was run.
+pub(crate) struct ModuleImport {
+    // The importing module, or `None` for the preprocessed file and the files it includes.
+    pub(crate) importer: Option<FileId>,
+    // The index of the imported module.
+    pub(crate) module: usize,
+    // The names of the items imported with `#import module::{item}`, or `None` for the whole
+    // module.
+    pub(crate) items: Option<Vec<String>>,
+}
+
+impl Module {
+    // Returns whether the module declares an item named `name`.
+    pub(crate) fn declares(&self, name: &str) -> bool {
+        scan_declarations(LocatedStr::new(&self.code))
+            .iter()
+            .any(|declaration| declaration.name.is_some_and(|n| n.inner_str == name))
+    }
+}
+
+// Joins the code of `modules`, in order, and returns it with its spans.
+//
+// Modules that the preprocessed file imports as a whole, directly or through other modules imported
+// as a whole, are kept as they are. Other modules only keep their imported items, the declarations
+// those depend on, their global directives, and the `const_assert`s that only check kept
+// declarations. Dependencies are found by name in every module.
+pub(crate) fn link(
+    modules: &[Module],
+    imports: &[ModuleImport],
+    line_preserving: bool,
+) -> (String, Vec<Span>) {
+    // Importers finish after the modules they import, so one pass from the end is enough.
+    let mut whole_files = BTreeSet::new();
+    let mut whole = vec![false; modules.len()];
+    let mut items = vec![BTreeSet::new(); modules.len()];
+    for (module_idx, module) in modules.iter().enumerate().rev() {
+        for import in imports.iter().filter(|import| import.module == module_idx) {
+            match &import.items {
+                Some(names) => items[module_idx].extend(names.iter().map(String::as_str)),
+                None => {
+                    whole[module_idx] |= import
+                        .importer
+                        .is_none_or(|file| whole_files.contains(&file));
+                }
+            }
+        }
+        if whole[module_idx] {
+            whole_files.insert(module.file);
+        }
+    }
+
+    let declarations: Vec<Vec<Declaration<'_>>> = modules
+        .iter()
+        .map(|module| scan_declarations(LocatedStr::new(&module.code)))
+        .collect();
+    let mut by_name = BTreeMap::new();
+    for (module_idx, module_declarations) in declarations.iter().enumerate() {
+        for (idx, declaration) in module_declarations.iter().enumerate() {
+            if let Some(name) = declaration.name {
+                by_name.entry(name.inner_str).or_insert((module_idx, idx));
+            }
+        }
+    }
+
+    let mut kept: Vec<Vec<bool>> = declarations.iter().map(|d| vec![false; d.len()]).collect();
+    let mut pending = Vec::new();
+    for (module_idx, module_declarations) in declarations.iter().enumerate() {
+        for (idx, declaration) in module_declarations.iter().enumerate() {
+            let wanted = whole[module_idx]
+                || declaration.kind == DeclarationKind::GlobalDirective
+                || declaration
+                    .name
+                    .is_some_and(|name| items[module_idx].contains(name.inner_str));
+            if wanted {
+                pending.push((module_idx, idx));
+            }
+        }
+    }
+    while let Some((module_idx, idx)) = pending.pop() {
+        if core::mem::replace(&mut kept[module_idx][idx], true) {
+            continue;
+        }
+        for reference in &declarations[module_idx][idx].references {
+            if let Some(&dependency) = by_name.get(reference.inner_str) {
+                pending.push(dependency);
+            }
+        }
+    }
+    for (module_idx, module_declarations) in declarations.iter().enumerate() {
+        for (idx, declaration) in module_declarations.iter().enumerate() {
+            if declaration.kind == DeclarationKind::ConstAssert && !whole[module_idx] {
+                kept[module_idx][idx] = declaration.references.iter().all(|reference| {
+                    by_name
+                        .get(reference.inner_str)
+                        .is_none_or(|&(m, d)| kept[m][d])
+                });
+            }
+        }
+    }
+
+    let mut code = String::new();
+    let mut spans = Vec::new();
+    for (module_idx, module) in modules.iter().enumerate() {
+        if !whole[module_idx] && !kept[module_idx].contains(&true) {
+            continue;
+        }
+        if line_preserving {
+            code.push_str(&format!("// yawgsl: begin import {}\n", module.path));
+        }
+        match whole[module_idx] {
+            true => {
+                push_range(&mut code, &mut spans, module, 0..module.code.len());
+            }
+            false => {
+                let kept_declarations = declarations[module_idx]
+                    .iter()
+                    .zip(&kept[module_idx])
+                    .filter(|(_, kept)| **kept);
+                for (declaration, _) in kept_declarations {
+                    let start = declaration.text.start_location.byte_num as usize;
+                    push_range(
+                        &mut code,
+                        &mut spans,
+                        module,
+                        start..start + declaration.text.inner_str.len(),
+                    );
+                    code.push('\n');
+                }
+            }
+        }
+        if line_preserving {
+            code.push_str(&format!("// yawgsl: end import {}\n", module.path));
+        }
+    }
+    (code, spans)
+}
+
+// Appends `range` of the module's code, with the spans inside of it.
+fn push_range(
+    code: &mut String,
+    spans: &mut Vec<Span>,
+    module: &Module,
+    range: core::ops::Range<usize>,
+) {
+    let offset = code.len();
+    spans.extend(
+        module
+            .spans
+            .iter()
+            .filter(|(output_range, _)| {
+                output_range.start >= range.start && output_range.end <= range.end
+            })
+            .map(|(output_range, location)| {
+                let start = output_range.start - range.start + offset;
+                (start..start + output_range.len(), location.clone())
+            }),
+    );
+    code.push_str(&module.code[range]);
+}
@@ -0,0 +1,158 @@
+use super::{PreprocessError, PreprocessOutput};
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Display;
+#[cfg(feature = "std")]
+use std::error::Error;
+
+/// Defines whose values vary between the permutations of a shader.
+///
+/// ```
+/// use yet_another_wgsl_preprocessor::preprocessor::PermutationMatrix;
+///
+/// let mut matrix = PermutationMatrix::new();
+/// matrix.toggle("SHADOWS").values("LIGHTS", ["4", "8", "16"]);
+/// let keys: Vec<String> = matrix.permutations().iter().map(|p| p.key()).collect();
+/// assert_eq!(keys.len(), 6);
+/// assert_eq!(keys[0], "SHADOWS=on,LIGHTS=4");
+/// assert_eq!(keys[5], "SHADOWS=off,LIGHTS=16");
+/// ```
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct PermutationMatrix {
+    axes: Vec<Axis>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Axis {
+    name: String,
+    values: Vec<String>,
+    // Whether the values are `on` and `off`, for a define that is defined as nothing or not at all.
+    toggle: bool,
+}
+
+impl PermutationMatrix {
+    /// Creates a matrix without defines, which has one empty permutation.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a define that is defined as nothing (`on`) or not defined (`off`).
+    pub fn toggle(&mut self, name: impl Into<String>) -> &mut Self {
+        self.axes.push(Axis {
+            name: name.into(),
+            values: alloc::vec!["on".to_owned(), "off".to_owned()],
+            toggle: true,
+        });
+        self
+    }
+
+    /// Adds a define that is defined as each of `values`.
+    pub fn values(
+        &mut self,
+        name: impl Into<String>,
+        values: impl IntoIterator<Item = impl Into<String>>,
+    ) -> &mut Self {
+        self.axes.push(Axis {
+            name: name.into(),
+            values: values.into_iter().map(Into::into).collect(),
+            toggle: false,
+        });
+        self
+    }
+
+    /// Returns every combination of the defines' values. The define added last varies fastest.
+    pub fn permutations(&self) -> Vec<Permutation> {
+        let mut permutations = alloc::vec![Permutation::default()];
+        for (axis_idx, axis) in self.axes.iter().enumerate() {
+            permutations = permutations
+                .iter()
+                .flat_map(|permutation| {
+                    axis.values.iter().map(move |value| {
+                        let mut permutation = permutation.clone();
+                        permutation.choices.push((axis.name.clone(), value.clone()));
+                        if axis.toggle {
+                            permutation.toggles.push(axis_idx);
+                        }
+                        permutation
+                    })
+                })
+                .collect();
+        }
+        permutations
+    }
+}
+
+/// One combination of the values of the defines of a [`PermutationMatrix`].
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Permutation {
+    /// Each define's name and value, in the order they were added to the matrix. Toggles have the
+    /// values `on` and `off`.
+    pub choices: Vec<(String, String)>,
+    // The indices of the choices that are toggles.
+    toggles: Vec<usize>,
+}
+
+impl Permutation {
+    /// Returns a key that identifies the permutation in its matrix, like `SHADOWS=on,LIGHTS=4`.
+    pub fn key(&self) -> String {
+        let choices: Vec<String> = self
+            .choices
+            .iter()
+            .map(|(name, value)| alloc::format!("{name}={value}"))
+            .collect();
+        choices.join(",")
+    }
+
+    /// Returns the value chosen for the define `name`.
+    pub fn choice(&self, name: &str) -> Option<&str> {
+        self.choices
+            .iter()
+            .find(|(choice_name, _)| choice_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the macros that the permutation defines, with `None` for the toggles that are off
+    /// and so are undefined.
+    pub fn defines(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.choices.iter().enumerate().map(|(idx, (name, value))| {
+            match self.toggles.contains(&idx) {
+                true => (name.as_str(), (value == "on").then_some("")),
+                false => (name.as_str(), Some(value.as_str())),
+            }
+        })
+    }
+}
+
+/// The result of [`Preprocessor::preprocess_permutations`](super::Preprocessor::preprocess_permutations).
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct PermutationOutputs {
+    /// The distinct outputs. Permutations whose preprocessed code is the same share one.
+    pub outputs: Vec<PreprocessOutput>,
+    /// Each preprocessed permutation, with the index of its output in `outputs`.
+    pub permutations: Vec<(Permutation, usize)>,
+}
+
+/// An error in preprocessing one permutation.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PermutationError {
+    /// The permutation that failed.
+    pub permutation: Permutation,
+    /// Why it failed.
+    pub error: Box<PreprocessError>,
+}
+
+impl Display for PermutationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "in permutation {}: {}",
+            self.permutation.key(),
+            self.error
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for PermutationError {}
@@ -0,0 +1,380 @@
+use super::expand::{Expansion, IncludeSite, Location};
+use super::{resolve_location, FileDatabase, FileId, SourceLocation};
+use crate::utils::TextRange;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Display;
+use core::ops::Range;
+
+/// A step in the chain of locations that produced some code.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum BacktraceFrame {
+    /// The code comes from the expansion of a macro.
+    MacroExpansion {
+        /// The invoked macro's name.
+        macro_name: String,
+        /// Where the macro was invoked. `None` for invocations in macros defined through the API.
+        location: Option<Box<SourceLocation>>,
+    },
+    /// The code is in a file included by an `#include` directive.
+    Include {
+        /// Where the directive is.
+        location: Option<Box<SourceLocation>>,
+    },
+    /// The code is in a module imported by an `#import` directive.
+    Import {
+        /// Where the directive is.
+        location: Option<Box<SourceLocation>>,
+    },
+}
+
+impl Display for BacktraceFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BacktraceFrame::MacroExpansion {
+                macro_name,
+                location: Some(location),
+            } => write!(f, "in expansion of macro `{macro_name}` at {location}"),
+            BacktraceFrame::MacroExpansion {
+                macro_name,
+                location: None,
+            } => write!(f, "in expansion of macro `{macro_name}`"),
+            BacktraceFrame::Include {
+                location: Some(location),
+            } => write!(f, "included from {location}"),
+            BacktraceFrame::Include { location: None } => write!(f, "included"),
+            BacktraceFrame::Import {
+                location: Some(location),
+            } => write!(f, "imported from {location}"),
+            BacktraceFrame::Import { location: None } => write!(f, "imported"),
+        }
+    }
+}
+
+/// Where a token of the preprocessed code came from.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Origin {
+    /// Where the token was written, which is in a macro's body for tokens produced by macros.
+    /// `None` for tokens of macros defined through the API.
+    pub location: Option<Box<SourceLocation>>,
+    /// The macro invocations, includes and imports that brought the token into the output,
+    /// innermost first.
+    pub backtrace: Vec<BacktraceFrame>,
+}
+
+// Returns the macro invocations, includes and imports that brought `location` into the output.
+pub(crate) fn backtrace(location: &Location, files: &FileDatabase) -> Vec<BacktraceFrame> {
+    walk_backtrace(&LocationFrames { files }, location)
+}
+
+// The include sites and macro invocations that locations refer to, which `Location`s hold
+// directly and `MapLocation`s hold as indices into their `SourceMap`. Include sites are the
+// locations of `#include` and `#import` directives.
+trait Frames<'a> {
+    type Location: 'a;
+    // Refers to the location of an `#include` or `#import` directive.
+    type Site: Copy;
+
+    fn included_from(&self, location: &'a Self::Location) -> Option<Self::Site>;
+    fn site(&self, site: Self::Site) -> &'a Self::Location;
+    fn is_import(&self, site: Self::Site) -> bool;
+    fn same_site(&self, a: Self::Site, b: Self::Site) -> bool;
+    // The invoked macro's name and where it was invoked, for locations in a macro's expansion.
+    fn expansion(&self, location: &'a Self::Location) -> Option<(&'a str, &'a Self::Location)>;
+    fn resolve(&self, location: &'a Self::Location) -> Option<Box<SourceLocation>>;
+}
+
+// Walks from `location` to the preprocessed file, innermost frame first. The includes that a
+// macro's body and its invocation share are only listed after the invocation.
+fn walk_backtrace<'a, F: Frames<'a>>(frames: &F, location: &'a F::Location) -> Vec<BacktraceFrame> {
+    let mut backtrace = Vec::new();
+    let mut maybe_location = Some(location);
+    while let Some(location) = maybe_location {
+        let invocation_sites = frames
+            .expansion(location)
+            .and_then(|(_, invocation)| frames.included_from(invocation));
+        let mut maybe_site = frames.included_from(location);
+        while let Some(site) = maybe_site {
+            if include_chain_contains(frames, invocation_sites, site) {
+                break;
+            }
+            let site_location = frames.site(site);
+            let location = frames.resolve(site_location);
+            backtrace.push(match frames.is_import(site) {
+                true => BacktraceFrame::Import { location },
+                false => BacktraceFrame::Include { location },
+            });
+            maybe_site = frames.included_from(site_location);
+        }
+        maybe_location = frames.expansion(location).map(|(macro_name, invocation)| {
+            backtrace.push(BacktraceFrame::MacroExpansion {
+                macro_name: macro_name.into(),
+                location: frames.resolve(invocation),
+            });
+            invocation
+        });
+    }
+    backtrace
+}
+
+fn include_chain_contains<'a, F: Frames<'a>>(
+    frames: &F,
+    mut maybe_site: Option<F::Site>,
+    site: F::Site,
+) -> bool {
+    while let Some(chain_site) = maybe_site {
+        if frames.same_site(chain_site, site) {
+            return true;
+        }
+        maybe_site = frames.included_from(frames.site(chain_site));
+    }
+    false
+}
+
+struct LocationFrames<'f> {
+    files: &'f FileDatabase,
+}
+
+impl<'a> Frames<'a> for LocationFrames<'_> {
+    type Location = Location;
+    type Site = &'a Rc<IncludeSite>;
+
+    fn included_from(&self, location: &'a Location) -> Option<&'a Rc<IncludeSite>> {
+        location.included_from.as_ref()
+    }
+
+    fn site(&self, site: &'a Rc<IncludeSite>) -> &'a Location {
+        &site.location
+    }
+
+    fn is_import(&self, site: &'a Rc<IncludeSite>) -> bool {
+        site.import
+    }
+
+    fn same_site(&self, a: &'a Rc<IncludeSite>, b: &'a Rc<IncludeSite>) -> bool {
+        Rc::ptr_eq(a, b)
+    }
+
+    fn expansion(&self, location: &'a Location) -> Option<(&'a str, &'a Location)> {
+        let expansion = location.expansion.as_deref()?;
+        Some((&expansion.macro_name, &expansion.invocation))
+    }
+
+    fn resolve(&self, location: &'a Location) -> Option<Box<SourceLocation>> {
+        resolve_location(location, self.files)
+    }
+}
+
+struct MapFrames<'a> {
+    map: &'a SourceMap,
+    files: &'a FileDatabase,
+}
+
+impl<'a> Frames<'a> for MapFrames<'a> {
+    type Location = MapLocation;
+    type Site = usize;
+
+    fn included_from(&self, location: &'a MapLocation) -> Option<usize> {
+        location.included_from
+    }
+
+    fn site(&self, site: usize) -> &'a MapLocation {
+        &self.map.include_sites[site].0
+    }
+
+    fn is_import(&self, site: usize) -> bool {
+        self.map.include_sites[site].1
+    }
+
+    fn same_site(&self, a: usize, b: usize) -> bool {
+        a == b
+    }
+
+    fn expansion(&self, location: &'a MapLocation) -> Option<(&'a str, &'a MapLocation)> {
+        let (macro_name, invocation) = &self.map.expansions[location.expansion?];
+        Some((macro_name, invocation))
+    }
+
+    fn resolve(&self, location: &'a MapLocation) -> Option<Box<SourceLocation>> {
+        location.file.map(|file| {
+            Box::new(SourceLocation {
+                path: self.files.get(file).path.clone(),
+                range: location.range,
+            })
+        })
+    }
+}
+
+/// Where each token of the preprocessed code came from.
+///
+/// Only tokens that went through macro expansion are mapped, so whitespace and the text that
+/// directives produce have no origin.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct SourceMap {
+    // The tokens' byte ranges in the output, in order, and their locations.
+    spans: Vec<Span>,
+    // The macro invocations that locations refer to by index.
+    expansions: Vec<(String, MapLocation)>,
+    // The `#include` and `#import` directives that locations refer to by index, and whether each
+    // one is an `#import`.
+    include_sites: Vec<(MapLocation, bool)>,
+}
+
+// A `Location` that refers to the frames of its `SourceMap`, so that they are shared.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct MapLocation {
+    file: Option<FileId>,
+    range: TextRange,
+    included_from: Option<usize>,
+    expansion: Option<usize>,
+}
+
+impl SourceMap {
+    /// Returns where the token that contains byte `offset` of the code came from.
+    ///
+    /// `files` are the files of the same [`PreprocessOutput`](super::PreprocessOutput).
+    pub fn origin_at(&self, offset: usize, files: &FileDatabase) -> Option<Origin> {
+        let idx = self
+            .spans
+            .partition_point(|(output_range, _)| output_range.end <= offset);
+        let (output_range, location) = self.spans.get(idx)?;
+        if !output_range.contains(&offset) {
+            return None;
+        }
+
+        let frames = MapFrames { map: self, files };
+        Some(Origin {
+            location: frames.resolve(location),
+            backtrace: walk_backtrace(&frames, location),
+        })
+    }
+
+    pub(crate) fn span_count(&self) -> usize {
+        self.spans.len()
+    }
+
+    // Moves the spans after the first `span_count` out of the map, for output that moves.
+    pub(crate) fn take_spans(&mut self, span_count: usize) -> Vec<Span> {
+        self.spans.split_off(span_count)
+    }
+
+    // Adds back spans taken with `take_spans`, which must come after the map's spans.
+    pub(crate) fn extend_spans(&mut self, spans: Vec<Span>) {
+        self.spans.extend(spans);
+    }
+}
+
+// A token's byte range in the output and its location.
+pub(crate) type Span = (Range<usize>, MapLocation);
+
+// Moves spans `offset` bytes forward in the output.
+pub(crate) fn shift_spans(spans: &mut [Span], offset: usize) {
+    for (output_range, _) in spans {
+        *output_range = output_range.start + offset..output_range.end + offset;
+    }
+}
+
+// Replaces ranges of `code`, which must be sorted and not overlap. Spans move with the code
+// around them. Spans in a replaced range are dropped, except that with `rename`, the span that
+// starts the range covers its replacement.
+pub(crate) fn replace_ranges(
+    code: &str,
+    spans: Vec<Span>,
+    replacements: &[(Range<usize>, String)],
+    rename: bool,
+) -> (String, Vec<Span>) {
+    let mut output = String::with_capacity(code.len());
+    let mut new_ranges = Vec::with_capacity(replacements.len());
+    let mut copied = 0;
+    for (range, replacement) in replacements {
+        output.push_str(&code[copied..range.start]);
+        new_ranges.push(output.len()..output.len() + replacement.len());
+        output.push_str(replacement);
+        copied = range.end;
+    }
+    output.push_str(&code[copied..]);
+
+    let mut next = 0;
+    let spans = spans
+        .into_iter()
+        .filter_map(|(range, location)| {
+            while next < replacements.len() && replacements[next].0.end <= range.start {
+                next += 1;
+            }
+            if let Some((replaced, _)) = replacements.get(next) {
+                if replaced.start <= range.start {
+                    return (rename && replaced.start == range.start)
+                        .then(|| (new_ranges[next].clone(), location));
+                }
+            }
+            let start = match next {
+                0 => range.start,
+                _ => range.start - replacements[next - 1].0.end + new_ranges[next - 1].end,
+            };
+            Some((start..start + range.len(), location))
+        })
+        .collect();
+    (output, spans)
+}
+
+// Builds a `SourceMap`, sharing the frames of locations that share them.
+#[derive(Default)]
+pub(crate) struct SourceMapBuilder {
+    pub(crate) map: SourceMap,
+    // The frames' indices by address. The frames are kept alive so that addresses are not reused.
+    expansion_ids: BTreeMap<*const Expansion, (Rc<Expansion>, usize)>,
+    include_site_ids: BTreeMap<*const IncludeSite, (Rc<IncludeSite>, usize)>,
+}
+
+impl SourceMapBuilder {
+    // Records that the token at `output_range` of the output comes from `location`. Tokens must be
+    // recorded in output order.
+    pub(crate) fn record(&mut self, output_range: Range<usize>, location: &Location) {
+        let location = self.map_location(location);
+        self.map.spans.push((output_range, location));
+    }
+
+    fn map_location(&mut self, location: &Location) -> MapLocation {
+        MapLocation {
+            file: location.file,
+            range: location.range,
+            included_from: location
+                .included_from
+                .as_ref()
+                .map(|site| self.include_site_id(site)),
+            expansion: location
+                .expansion
+                .as_ref()
+                .map(|expansion| self.expansion_id(expansion)),
+        }
+    }
+
+    fn include_site_id(&mut self, site: &Rc<IncludeSite>) -> usize {
+        if let Some((_, id)) = self.include_site_ids.get(&Rc::as_ptr(site)) {
+            return *id;
+        }
+        let location = self.map_location(&site.location);
+        self.map.include_sites.push((location, site.import));
+        let id = self.map.include_sites.len() - 1;
+        self.include_site_ids
+            .insert(Rc::as_ptr(site), (site.clone(), id));
+        id
+    }
+
+    fn expansion_id(&mut self, expansion: &Rc<Expansion>) -> usize {
+        if let Some((_, id)) = self.expansion_ids.get(&Rc::as_ptr(expansion)) {
+            return *id;
+        }
+        let invocation = self.map_location(&expansion.invocation);
+        self.map
+            .expansions
+            .push((expansion.macro_name.clone(), invocation));
+        let id = self.map.expansions.len() - 1;
+        self.expansion_ids
+            .insert(Rc::as_ptr(expansion), (expansion.clone(), id));
+        id
+    }
+}
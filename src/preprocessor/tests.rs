@@ -0,0 +1,1457 @@
+use super::*;
+use alloc::format;
+use alloc::string::ToString;
+
+fn preprocess(files: &[(&str, &str)]) -> Result<String, PreprocessError> {
+    let mut resolver = MemoryResolver::new();
+    for (path, source) in files {
+        resolver.add_file(*path, *source);
+    }
+    Preprocessor::new(resolver)
+        .preprocess(files[0].0)
+        .map(|output| output.code)
+}
+
+mod conditionals {
+    use super::*;
+
+    #[test]
+    fn test_branches() {
+        let source = "// #define A 2;
+// #if A == 1;
+one
+// #elif A == 2 && defined(A);
+two
+// #else;
+other
+// #endif;
+";
+        assert_eq!(preprocess(&[("main.wgsl", source)]).unwrap(), "two\n");
+    }
+
+    #[test]
+    fn test_nested_inactive() {
+        let source = "// #ifdef MISSING;
+// #if 1 / 0;
+a
+// #endif;
+// #else;
+b
+// #endif;
+";
+        assert_eq!(preprocess(&[("main.wgsl", source)]).unwrap(), "b\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = preprocess(&[("main.wgsl", "// #endif;\n")]).unwrap_err();
+        assert_eq!(error.kind, PreprocessErrorKind::UnmatchedDirective("endif"));
+
+        let error = preprocess(&[("main.wgsl", "// #if 1;\n")]).unwrap_err();
+        assert_eq!(error.kind, PreprocessErrorKind::UnterminatedConditional);
+
+        let source = "// #if 1;\n// #else;\n// #else;\n// #endif;\n";
+        let error = preprocess(&[("main.wgsl", source)]).unwrap_err();
+        assert_eq!(error.kind, PreprocessErrorKind::DirectiveAfterElse("else"));
+        assert_eq!(error.to_string(), "main.wgsl:3:4: #else after #else");
+
+        let source = "// #if (A && B;\nfn main() {}\n// #endif;\n";
+        let error = preprocess(&[("main.wgsl", source)]).unwrap_err();
+        assert_eq!(error.to_string(), "main.wgsl:1:8: unclosed `(`");
+    }
+
+    #[test]
+    fn test_integer_overflow() {
+        let error = preprocess(&[("main.wgsl", "// #if 9223372036854775807 + 1;\n// #endif;\n")])
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "main.wgsl:1:28: integer overflow in #if expression"
+        );
+        for condition in [
+            "-9223372036854775807 - 2",
+            "4611686018427387904 * 2",
+            "-(-9223372036854775807 - 1)",
+            "(-9223372036854775807 - 1) / -1",
+            "3 << 62",
+            "1 << 64",
+            "1 >> -1",
+        ] {
+            let source = format!("// #if {condition};\n// #endif;\n");
+            let error = preprocess(&[("main.wgsl", &source)]).unwrap_err();
+            assert_eq!(
+                error.kind,
+                PreprocessErrorKind::InvalidCondition("integer overflow in #if expression"),
+                "{condition}"
+            );
+        }
+
+        let source = "// #if 0 && 1 << 64 || 1 << 62 == 4611686018427387904;\nyes\n// #endif;\n";
+        assert_eq!(preprocess(&[("main.wgsl", source)]).unwrap(), "yes\n");
+    }
+}
+
+mod macros {
+    use super::*;
+
+    #[test]
+    fn test_expansion() {
+        let source = "// #define SIZE 64u;
+// #define MUL(a, b) (a * b);
+var<workgroup> data: array<f32, SIZE>;
+let x = MUL(f(1, 2), SIZE);
+let MUL = 1;
+";
+        assert_eq!(
+            preprocess(&[("main.wgsl", source)]).unwrap(),
+            "var<workgroup> data: array<f32, 64u>;\nlet x = (f(1, 2) * 64u);\nlet MUL = 1;\n"
+        );
+    }
+
+    #[test]
+    fn test_api_defines() {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file("main.wgsl", "// #ifdef FAST;\nN\n// #endif;\n");
+        let mut preprocessor = Preprocessor::new(resolver);
+        preprocessor.define("FAST", "").unwrap();
+        preprocessor.define("N", "4 + 4").unwrap();
+        assert_eq!(
+            preprocessor.preprocess("main.wgsl").unwrap().code,
+            "4 + 4\n"
+        );
+
+        preprocessor.undefine("FAST");
+        assert_eq!(preprocessor.preprocess("main.wgsl").unwrap().code, "");
+
+        let error = preprocessor.define("fn", "1").unwrap_err();
+        assert!(matches!(
+            error.kind,
+            PreprocessErrorKind::Directive(DirectiveParseErrorKind::InvalidMacroName(_))
+        ));
+    }
+
+    #[test]
+    fn test_argument_count() {
+        let source = "// #define F(a, b) a;\nF(1)\n";
+        let error = preprocess(&[("main.wgsl", source)]).unwrap_err();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::MacroArgumentCount {
+                expected: 2,
+                variadic: false,
+                found: 1
+            }
+        );
+    }
+}
+
+mod includes {
+    use super::*;
+
+    #[test]
+    fn test_include_and_anchor() {
+        let files = [
+            (
+                "shaders/main.wgsl",
+                "// #include \"lib/util.wgsl\" b;\nmain\n",
+            ),
+            (
+                "shaders/lib/util.wgsl",
+                "a\n// #anchor b;\nb\n// #endanchor;\nc\n",
+            ),
+        ];
+        assert_eq!(preprocess(&files).unwrap(), "b\nmain\n");
+
+        let files = [
+            ("main.wgsl", "// #include \"util.wgsl\" missing;\n"),
+            ("util.wgsl", "a\n"),
+        ];
+        let error = preprocess(&files).unwrap_err();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::AnchorNotFound {
+                anchor: "missing".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_pragma_once_and_recursion() {
+        let files = [
+            (
+                "main.wgsl",
+                "// #include \"a.wgsl\";\n// #include \"a.wgsl\";\n",
+            ),
+            ("a.wgsl", "// #pragma once;\na\n"),
+        ];
+        assert_eq!(preprocess(&files).unwrap(), "a\n");
+
+        let files = [("main.wgsl", "// #include \"main.wgsl\";\n")];
+        let error = preprocess(&files).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            PreprocessErrorKind::RecursiveInclude { .. }
+        ));
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let error = preprocess(&[("main.wgsl", "\n// #include \"nope.wgsl\";\n")]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "main.wgsl:2:4: could not include \"nope.wgsl\": file not found"
+        );
+    }
+}
+
+mod custom_directives {
+    use super::*;
+    use crate::macros::CustomDirective;
+
+    fn bindings(
+        directive: &CustomDirective<'_>,
+        context: &DirectiveContext<'_>,
+    ) -> Result<String, CustomDirectiveError> {
+        match directive.tokens.as_slice() {
+            [MacroTokenResult::AlphanumStringToken(group)] => Ok(format!(
+                "// bindings for {} from {}",
+                group.inner_str, context.path
+            )),
+            [] => Err("expected a group name".into()),
+            [_, extra, ..] => {
+                Err(CustomDirectiveError::new("too many arguments").with_range(extra.range()))
+            }
+            [other] => {
+                Err(CustomDirectiveError::new("expected a group name").with_range(other.range()))
+            }
+        }
+    }
+
+    fn run(source: &str, pass_through: bool) -> Result<String, PreprocessError> {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file("main.wgsl", source);
+        let mut preprocessor = Preprocessor::new(resolver);
+        preprocessor
+            .register_directive("bindings", bindings)
+            .unwrap()
+            .pass_through_unknown_directives(pass_through);
+        preprocessor
+            .preprocess("main.wgsl")
+            .map(|output| output.code)
+    }
+
+    #[test]
+    fn test_handler() {
+        assert_eq!(
+            run("a\n// #bindings material;\nb\n", false).unwrap(),
+            "a\n// bindings for material from main.wgsl\nb\n"
+        );
+
+        let error = run("// #bindings a b;\n", false).unwrap_err();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::CustomDirective {
+                name: "bindings".to_string(),
+                message: "too many arguments".to_string()
+            }
+        );
+        assert_eq!(error.location.unwrap().range.start.col_num, 15);
+    }
+
+    #[test]
+    fn test_inactive_directives_are_not_handled() {
+        let source = "// #ifdef MISSING;\n// #bindings;\n// #endif;\n";
+        assert_eq!(run(source, false).unwrap(), "");
+    }
+
+    #[test]
+    fn test_unknown_directives() {
+        let error = run("// #unknown thing;\n", false).unwrap_err();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::UnknownDirective("unknown".to_string())
+        );
+        assert_eq!(
+            run("a\n// #unknown thing;\nb\n", true).unwrap(),
+            "a\n// #unknown thing;\nb\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let mut preprocessor = Preprocessor::new(MemoryResolver::new());
+        let error = preprocessor
+            .register_directive("define", bindings)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::BuiltinDirectiveOverridden("define".to_string())
+        );
+        assert_eq!(
+            error.to_string(),
+            "cannot register a handler for the built-in directive `define`"
+        );
+        for name in ["", "foo bar", "_", "1st"] {
+            let error = preprocessor
+                .register_directive(name, bindings)
+                .err()
+                .unwrap();
+            assert_eq!(
+                error.kind,
+                PreprocessErrorKind::InvalidDirectiveName(name.to_string())
+            );
+        }
+    }
+}
+
+mod diagnostics {
+    use super::*;
+
+    #[test]
+    fn test_error_directive() {
+        let source = "a\n// #error \"unsupported\";\nb\n";
+        let error = preprocess(&[("main.wgsl", source)]).unwrap_err();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::ErrorDirective("unsupported".to_string())
+        );
+        assert_eq!(error.to_string(), "main.wgsl:2:4: #error: unsupported");
+    }
+
+    #[test]
+    fn test_warning_directive() {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file(
+            "main.wgsl",
+            "a\n// #warning \"slow path\";\n// #if 0;\n// #warning \"skipped\";\n// #endif;\nb\n",
+        );
+        let output = Preprocessor::new(resolver).preprocess("main.wgsl").unwrap();
+        assert_eq!(output.code, "a\nb\n");
+        let warnings: Vec<_> = output
+            .diagnostics
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(warnings, ["main.wgsl:2:4: warning: #warning: slow path"]);
+    }
+}
+
+mod output_modes {
+    use super::*;
+
+    const MAIN: &str = "// #include \"util.wgsl\";
+// #define ADD(a, b) a + b;
+// #ifdef MISSING;
+disabled
+// #endif;
+let x = ADD(1,
+    2);
+let y = 3;";
+    const UTIL: &str = "// #include \"inner.wgsl\";\nfn util() {}\n";
+    const INNER: &str = "fn inner() {}\n";
+
+    fn run(mode: OutputMode) -> String {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file("main.wgsl", MAIN)
+            .add_file("util.wgsl", UTIL)
+            .add_file("inner.wgsl", INNER);
+        let mut preprocessor = Preprocessor::new(resolver);
+        preprocessor.set_output_mode(mode);
+        preprocessor.preprocess("main.wgsl").unwrap().code
+    }
+
+    #[test]
+    fn test_compact() {
+        assert_eq!(
+            run(OutputMode::Compact),
+            "fn inner() {}\nfn util() {}\nlet x = 1 + 2\n;\nlet y = 3;"
+        );
+    }
+
+    #[test]
+    fn test_line_preserving() {
+        let code = run(OutputMode::LinePreserving);
+        let lines: Vec<_> = code.lines().collect();
+        assert_eq!(lines[5], "let x = 1 + 2");
+        assert_eq!(lines[7], "let y = 3;");
+        assert!(lines[..5].iter().all(|line| line.is_empty()));
+        assert_eq!(
+            lines[8..],
+            [
+                "// yawgsl: begin include \"util.wgsl\"",
+                "// yawgsl: begin include \"inner.wgsl\"",
+                "fn inner() {}",
+                "// yawgsl: end include \"inner.wgsl\"",
+                "",
+                "fn util() {}",
+                "// yawgsl: end include \"util.wgsl\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include_inside_function() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file(
+                "main.wgsl",
+                "fn f() -> f32 {\n    var x = 1.0;\n    // #include \"body.wgsl\";\n    \
+                 return x;\n}\n// #include \"inner.wgsl\";\n",
+            )
+            .add_file("body.wgsl", "    x = x * 2.0;\n")
+            .add_file("inner.wgsl", INNER);
+        let mut preprocessor = Preprocessor::new(resolver);
+        let compact = preprocessor.preprocess("main.wgsl").unwrap();
+        assert!(compact.diagnostics.is_empty());
+        let line_preserving = preprocessor
+            .set_output_mode(OutputMode::LinePreserving)
+            .preprocess("main.wgsl")
+            .unwrap();
+
+        // The same code, other than the lines that only line-preserving mode adds.
+        let code_lines = |code: &str| -> Vec<String> {
+            code.lines()
+                .filter(|line| !line.is_empty() && !line.starts_with("// yawgsl:"))
+                .map(str::to_owned)
+                .collect()
+        };
+        assert_eq!(code_lines(&line_preserving.code), code_lines(&compact.code));
+        assert!(line_preserving.code.starts_with(
+            "fn f() -> f32 {\n    var x = 1.0;\n// yawgsl: begin include \"body.wgsl\"\n"
+        ));
+        assert_eq!(
+            line_preserving.diagnostics.warnings()[0].to_string(),
+            "main.wgsl:3:8: warning: \"body.wgsl\" is included inside braces, so it shifts the \
+             lines after it"
+        );
+        assert_eq!(line_preserving.diagnostics.warnings().len(), 1);
+    }
+}
+
+mod builtin_macros {
+    use super::*;
+
+    #[test]
+    fn test_file_and_line() {
+        let files = [
+            (
+                "shaders/main.wgsl",
+                "// #include \"lib.wgsl\";\n__LINE__ __FILE__\n",
+            ),
+            (
+                "shaders/lib.wgsl",
+                "// #define HERE __FILE__:__LINE__;\n\n// a\nHERE\n",
+            ),
+        ];
+        assert_eq!(
+            preprocess(&files).unwrap(),
+            "\n// a\n\"shaders/lib.wgsl\":4\n2 \"shaders/main.wgsl\"\n"
+        );
+    }
+
+    #[test]
+    fn test_counter() {
+        let source = "// #define HELPER(f) f __COUNTER__;
+// #if __COUNTER__ == 0 && defined(__COUNTER__);
+HELPER(a) HELPER(b)
+// #endif;
+__COUNTER__
+";
+        assert_eq!(
+            preprocess(&[("main.wgsl", source)]).unwrap(),
+            "a 1 b 2\n3\n"
+        );
+    }
+
+    #[test]
+    fn test_version_and_redefinition() {
+        let source = "// #if __YAWGSL_VERSION__ > 0;\nyes\n// #endif;\n";
+        assert_eq!(preprocess(&[("main.wgsl", source)]).unwrap(), "yes\n");
+
+        let error = preprocess(&[("main.wgsl", "// #undef __LINE__;\n")]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "main.wgsl:1:11: cannot redefine built-in macro `__LINE__`"
+        );
+        let mut preprocessor = Preprocessor::new(MemoryResolver::new());
+        assert!(preprocessor.define("__FILE__", "\"x\"").is_err());
+    }
+}
+
+mod paste_and_stringify {
+    use super::*;
+
+    #[test]
+    fn test_helper_family() {
+        let source = "// #define SUM(T) fn sum_##T(v: array<T, 2>) -> T {
+//     return v[0] + v[1];
+// };
+SUM(f32)
+SUM(vec3f)
+";
+        assert_eq!(
+            preprocess(&[("main.wgsl", source)]).unwrap(),
+            "fn sum_f32(v: array<f32, 2>) -> f32 { return v[0] + v[1]; }\n\
+             fn sum_vec3f(v: array<vec3f, 2>) -> vec3f { return v[0] + v[1]; }\n"
+        );
+    }
+
+    #[test]
+    fn test_paste_edge_cases() {
+        let source = "// #define CAT(a, b) a ## b;
+// #define UNIQUE(name) name ## _ ## __COUNTER__;
+// #define SUFFIX(x) x##_suffix;
+CAT(x, 1) CAT(, y) CAT(z, ) UNIQUE(helper) UNIQUE(helper) SUFFIX(a b)
+";
+        assert_eq!(
+            preprocess(&[("main.wgsl", source)]).unwrap(),
+            "x1 y z helper_0 helper_1 a b_suffix\n"
+        );
+    }
+
+    #[test]
+    fn test_stringify() {
+        let source = "// #define STR(x) #x;\nSTR( a  +\n /* c */ b )\n";
+        assert_eq!(
+            preprocess(&[("main.wgsl", source)]).unwrap(),
+            "\"a + b\"\n\n"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let test_cases = [
+            (
+                "// #define CAT(a, b) a ## b;\nCAT(x, +)\n",
+                PreprocessErrorKind::InvalidPaste("x+".to_string()),
+                "main.wgsl:1:24",
+            ),
+            (
+                "// #define CAT(a, b) a ## b;\nCAT(f, n)\n",
+                PreprocessErrorKind::InvalidPaste("fn".to_string()),
+                "main.wgsl:1:24",
+            ),
+            (
+                "// #define A ## b;\n",
+                PreprocessErrorKind::PasteAtBodyEdge,
+                "main.wgsl:1:14",
+            ),
+            (
+                "// #define S(x) #y;\n",
+                PreprocessErrorKind::StringifyWithoutParameter,
+                "main.wgsl:1:17",
+            ),
+        ];
+        for (source, kind, location) in test_cases {
+            let error = preprocess(&[("main.wgsl", source)]).unwrap_err();
+            assert_eq!(error.kind, kind, "{source}");
+            assert_eq!(error.location.unwrap().to_string(), location, "{source}");
+        }
+    }
+}
+
+mod variadic_macros {
+    use super::*;
+
+    #[test]
+    fn test_va_args() {
+        let source = "// #define FIELDS(...) struct S { __VA_ARGS__ };
+// #define SELECT(f, c, ...) select(f, c __VA_OPT__(, __VA_ARGS__));
+// #define COUNT(...) #__VA_ARGS__;
+FIELDS(a: f32, b: vec2<f32>)
+SELECT(x, y) SELECT(x, y, z, w) SELECT(x, y, )
+COUNT() COUNT(1, (2, 3))
+";
+        assert_eq!(
+            preprocess(&[("main.wgsl", source)]).unwrap(),
+            "struct S { a: f32, b: vec2<f32> }\n\
+             select(x, y ) select(x, y , z, w) select(x, y )\n\
+             \"\" \"1, (2, 3)\"\n"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let test_cases = [
+            (
+                "// #define F(a, b, ...) a;\nF(1)\n",
+                PreprocessErrorKind::MacroArgumentCount {
+                    expected: 2,
+                    variadic: true,
+                    found: 1,
+                },
+            ),
+            (
+                "// #define F(a) __VA_ARGS__;\n",
+                PreprocessErrorKind::VariadicOutsideVariadicMacro,
+            ),
+            (
+                "// #define F(...) __VA_OPT__ x;\n",
+                PreprocessErrorKind::InvalidVaOpt,
+            ),
+            // `##` inside of `__VA_OPT__` has nothing to paste onto.
+            (
+                "// #define F(a, ...) a __VA_OPT__(## x);\nF(p, 1)\n",
+                PreprocessErrorKind::PasteAtBodyEdge,
+            ),
+            (
+                "// #define F(a, ...) __VA_OPT__(x ##) a;\nF(p, 1)\n",
+                PreprocessErrorKind::PasteAtBodyEdge,
+            ),
+        ];
+        for (source, kind) in test_cases {
+            let error = preprocess(&[("main.wgsl", source)]).unwrap_err();
+            assert_eq!(error.kind, kind, "{source}");
+        }
+        let source = "// #define F(a, b, ...) a;\nF(1)\n";
+        let error = preprocess(&[("main.wgsl", source)]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "main.wgsl:2:1: macro takes at least 2 arguments but 1 were given"
+        );
+        // Like in C, `F()` passes one empty argument.
+        let source = "// #define F(a, ...) [a];\nF()\n";
+        assert_eq!(preprocess(&[("main.wgsl", source)]).unwrap(), "[]\n");
+    }
+}
+
+mod recursive_expansion {
+    use super::*;
+
+    fn preprocess_with_limits(
+        source: &str,
+        max_depth: usize,
+        max_output_size: usize,
+    ) -> Result<String, PreprocessError> {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file("main.wgsl", source);
+        Preprocessor::new(resolver)
+            .set_max_expansion_depth(max_depth)
+            .set_max_output_size(max_output_size)
+            .preprocess("main.wgsl")
+            .map(|output| output.code)
+    }
+
+    #[test]
+    fn test_rescanning() {
+        let source = "// #define A B + 1;
+// #define B C * 2;
+// #define C x;
+// #define SQ(x) ((x) * (x));
+// #define CUBE(x) (SQ(x) * (x));
+// #define WHERE line __LINE__;
+// #define HERE WHERE;
+A
+CUBE(A)
+HERE
+";
+        assert_eq!(
+            preprocess(&[("main.wgsl", source)]).unwrap(),
+            "x * 2 + 1\n(((x * 2 + 1) * (x * 2 + 1)) * (x * 2 + 1))\nline 10\n"
+        );
+    }
+
+    #[test]
+    fn test_painting() {
+        let source = "// #define f f + 1;
+// #define a b;
+// #define b a;
+// #define g(x) x;
+// #define h g(h);
+// #define F(x) x(x);
+// #define p(x) x * q;
+// #define q(x) p(x);
+f a b h F(F)
+p(2)(9)
+";
+        assert_eq!(
+            preprocess(&[("main.wgsl", source)]).unwrap(),
+            "f + 1 a b h F(F)\n2 * 9 * q\n"
+        );
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let source = "// #define A B;\n// #define B C;\n// #define C x;\nA\n";
+        assert_eq!(preprocess_with_limits(source, 3, 1000).unwrap(), "x\n");
+        let error = preprocess_with_limits(source, 2, 1000).unwrap_err();
+        assert_eq!(error.kind, PreprocessErrorKind::ExpansionDepthLimit(2));
+        assert_eq!(
+            error.to_string(),
+            "main.wgsl:2:14: macro expansion is nested more than 2 levels deep\n  \
+             in expansion of macro `B` at main.wgsl:1:14\n  \
+             in expansion of macro `A` at main.wgsl:4:1"
+        );
+
+        let source = "// #define F(x) x;\nF(F(F(1)))\n";
+        assert_eq!(preprocess_with_limits(source, 3, 1000).unwrap(), "1\n");
+        let error = preprocess_with_limits(source, 2, 1000).unwrap_err();
+        assert_eq!(error.kind, PreprocessErrorKind::ExpansionDepthLimit(2));
+    }
+
+    #[test]
+    fn test_output_size_limit() {
+        let mut source = String::from("// #define A0 x;\n");
+        for i in 1..64 {
+            source += &format!("// #define A{i} A{} A{};\n", i - 1, i - 1);
+        }
+        source += "A63\n";
+        let error = preprocess_with_limits(&source, 256, 1000).unwrap_err();
+        assert_eq!(error.kind, PreprocessErrorKind::OutputSizeLimit(1000));
+        assert_eq!(error.backtrace.len(), 63);
+
+        // Expansions that end up empty count too.
+        let source = source.replace("A0 x", "A0");
+        let error = preprocess_with_limits(&source, 256, 1000).unwrap_err();
+        assert_eq!(error.kind, PreprocessErrorKind::OutputSizeLimit(1000));
+    }
+}
+
+mod backtraces {
+    use super::*;
+
+    fn files() -> MemoryResolver {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file("lib/paste.wgsl", "// #define PASTE(a, b) a ## b;\n")
+            .add_file(
+                "lib/util.wgsl",
+                "// #include \"paste.wgsl\";\n// #define WRAP(x) PASTE(x, 1);\n",
+            );
+        resolver
+    }
+
+    #[test]
+    fn test_error_backtrace() {
+        let mut resolver = files();
+        resolver.add_file("main.wgsl", "// #include \"lib/util.wgsl\";\nWRAP(+)\n");
+        let error = Preprocessor::new(resolver)
+            .preprocess("main.wgsl")
+            .unwrap_err();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::InvalidPaste("+1".to_owned())
+        );
+        assert_eq!(
+            error.to_string(),
+            "lib/paste.wgsl:1:26: pasting formed `+1`, which is not a valid identifier\n  \
+             included from lib/util.wgsl:1:4\n  \
+             in expansion of macro `PASTE` at lib/util.wgsl:2:20\n  \
+             included from main.wgsl:1:4\n  \
+             in expansion of macro `WRAP` at main.wgsl:2:1"
+        );
+    }
+
+    #[test]
+    fn test_warning_backtrace() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file("main.wgsl", "// #include \"a.wgsl\";\n")
+            .add_file("a.wgsl", "// #warning \"deprecated\";\n");
+        let output = Preprocessor::new(resolver).preprocess("main.wgsl").unwrap();
+        assert_eq!(
+            output.diagnostics.warnings()[0].to_string(),
+            "a.wgsl:1:4: warning: #warning: deprecated\n  included from main.wgsl:1:4"
+        );
+    }
+
+    #[test]
+    fn test_origins() {
+        let mut resolver = files();
+        resolver.add_file(
+            "main.wgsl",
+            "// #include \"lib/util.wgsl\";\nlet x = WRAP(f);\n",
+        );
+        let output = Preprocessor::new(resolver).preprocess("main.wgsl").unwrap();
+        assert_eq!(output.code, "let x = f1;\n");
+
+        let origin = output.origin_at(0).unwrap();
+        assert_eq!(origin.location.unwrap().to_string(), "main.wgsl:2:1");
+        assert!(origin.backtrace.is_empty());
+        assert_eq!(output.origin_at(3), None);
+
+        let origin = output.origin_at(9).unwrap();
+        assert_eq!(origin.location.unwrap().to_string(), "lib/paste.wgsl:1:26");
+        let backtrace: Vec<_> = origin.backtrace.iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            backtrace,
+            [
+                "included from lib/util.wgsl:1:4",
+                "in expansion of macro `PASTE` at lib/util.wgsl:2:20",
+                "included from main.wgsl:1:4",
+                "in expansion of macro `WRAP` at main.wgsl:2:9",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_origins_in_line_preserving_mode() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file("main.wgsl", "// #include \"a.wgsl\";\nmain\n")
+            .add_file("a.wgsl", "helper\n");
+        let output = Preprocessor::new(resolver)
+            .set_output_mode(OutputMode::LinePreserving)
+            .preprocess("main.wgsl")
+            .unwrap();
+        let offset = output.code.find("helper").unwrap();
+        let origin = output.origin_at(offset).unwrap();
+        assert_eq!(origin.location.unwrap().to_string(), "a.wgsl:1:1");
+        assert_eq!(
+            origin.backtrace,
+            [BacktraceFrame::Include {
+                location: Some(Box::new(SourceLocation {
+                    path: "main.wgsl".to_owned(),
+                    range: TextRange::new((0, 3, 3).into(), (0, 21, 21).into()),
+                })),
+            }]
+        );
+        let offset = output.code.find("main").unwrap();
+        let origin = output.origin_at(offset).unwrap();
+        assert_eq!(origin.location.unwrap().to_string(), "main.wgsl:2:1");
+    }
+}
+
+mod modules {
+    use super::*;
+
+    fn preprocess_modules(files: &[(&str, &str)]) -> Result<String, PreprocessError> {
+        let mut resolver = MemoryResolver::new();
+        for (path, source) in files {
+            resolver.add_file(*path, *source);
+        }
+        Preprocessor::new(resolver)
+            .set_module_root("shaders")
+            .preprocess(files[0].0)
+            .map(|output| output.code)
+    }
+
+    #[test]
+    fn test_dependency_order() {
+        let files = [
+            (
+                "main.wgsl",
+                "// #import app::scene;\n// #import lighting::pbr;\nfn main() { scene(); }\n",
+            ),
+            (
+                "shaders/app/scene.wgsl",
+                "// #import lighting::pbr;\nfn scene() -> f32 { return pbr(); }\n",
+            ),
+            (
+                "shaders/lighting/pbr.wgsl",
+                "// #import lighting::common;\nfn pbr() -> f32 { return PI; }\n",
+            ),
+            ("shaders/lighting/common.wgsl", "const PI = 3.14;"),
+        ];
+        assert_eq!(
+            preprocess_modules(&files).unwrap(),
+            "const PI = 3.14;\n\
+             fn pbr() -> f32 { return PI; }\n\
+             fn scene() -> f32 { return pbr(); }\n\
+             fn main() { scene(); }\n"
+        );
+    }
+
+    #[test]
+    fn test_line_preserving() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file("main.wgsl", "// #import util;\nfn main() {}\n")
+            .add_file("util.wgsl", "fn util() {}\n");
+        let output = Preprocessor::new(resolver)
+            .set_output_mode(OutputMode::LinePreserving)
+            .preprocess("main.wgsl")
+            .unwrap();
+        assert_eq!(
+            output.code,
+            "\nfn main() {}\n\
+             // yawgsl: begin import util\n\
+             fn util() {}\n\
+             // yawgsl: end import util\n"
+        );
+        let origin = output.origin_at(output.code.find("util()").unwrap());
+        assert_eq!(
+            origin.unwrap().location.unwrap().to_string(),
+            "util.wgsl:1:4"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let files = [
+            ("main.wgsl", "// #import a;\n"),
+            ("shaders/a.wgsl", "// #import b;\n"),
+            ("shaders/b.wgsl", "fn b() {}\n// #import a;\n"),
+        ];
+        let error = preprocess_modules(&files).unwrap_err();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::ImportCycle(vec!["a".to_owned(), "b".to_owned(), "a".to_owned()])
+        );
+        assert_eq!(
+            error.to_string(),
+            "shaders/b.wgsl:2:4: import cycle: a -> b -> a\n  \
+             imported from shaders/a.wgsl:1:4\n  \
+             imported from main.wgsl:1:4"
+        );
+
+        let error =
+            preprocess_modules(&[("main.wgsl", "// #import missing::module;\n")]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "main.wgsl:1:4: could not import `missing::module`: file not found"
+        );
+    }
+
+    #[test]
+    fn test_error_in_imported_module() {
+        let files = [
+            ("main.wgsl", "// #import a;\n"),
+            ("shaders/a.wgsl", "// #include \"b.wgsl\";\n"),
+            ("shaders/b.wgsl", "// #bogus;\n"),
+        ];
+        let error = preprocess_modules(&files).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "shaders/b.wgsl:1:4: unknown directive #bogus\n  \
+             included from shaders/a.wgsl:1:4\n  \
+             imported from main.wgsl:1:4"
+        );
+        assert!(matches!(
+            error.backtrace.as_slice(),
+            [
+                BacktraceFrame::Include { .. },
+                BacktraceFrame::Import { .. }
+            ]
+        ));
+    }
+
+    const LIGHTING: &str = "enable f16;
+// #import math;
+struct Light { color: vec3f }
+fn fresnel_schlick(c: f32) -> f32 { return pow(1.0 - c, 5.0); }
+fn pbr_direct(light: Light) -> vec3f { return light.color * fresnel_schlick(PI); }
+fn unused() -> f32 { return TAU; }
+const_assert PI > 3.0;
+const_assert TAU > 6.0;
+";
+
+    #[test]
+    fn test_selective_import() {
+        let files = [
+            (
+                "main.wgsl",
+                "// #import lighting::{pbr_direct};\nfn main() { pbr_direct(); }\n",
+            ),
+            ("shaders/lighting.wgsl", LIGHTING),
+            ("shaders/math.wgsl", "const PI = 3.14;\nconst TAU = 6.28;\n"),
+        ];
+        assert_eq!(
+            preprocess_modules(&files).unwrap(),
+            "const PI = 3.14;\n\
+             enable f16;\n\
+             struct Light { color: vec3f }\n\
+             fn fresnel_schlick(c: f32) -> f32 { return pow(1.0 - c, 5.0); }\n\
+             fn pbr_direct(light: Light) -> vec3f { return light.color * fresnel_schlick(PI); }\n\
+             const_assert PI > 3.0;\n\
+             fn main() { pbr_direct(); }\n"
+        );
+
+        // Importing the whole module too keeps all of it.
+        let files = [
+            (
+                "main.wgsl",
+                "// #import lighting::{fresnel_schlick};\n// #import app;\n",
+            ),
+            ("shaders/app.wgsl", "// #import lighting;\n"),
+            ("shaders/lighting.wgsl", LIGHTING),
+            ("shaders/math.wgsl", "const PI = 3.14;\nconst TAU = 6.28;\n"),
+        ];
+        let code = preprocess_modules(&files).unwrap();
+        assert!(code.starts_with("const PI = 3.14;\nconst TAU = 6.28;\nenable f16;\n"));
+        assert!(code.contains("fn unused()") && code.contains("const_assert TAU"));
+    }
+
+    #[test]
+    fn test_mangle_names() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file(
+                "main.wgsl",
+                "// #import color;\n// #import tone::{saturate};\n\
+                 fn main() -> f32 { return saturate(color::saturate(1.0)); }\n",
+            )
+            .add_file(
+                "color.wgsl",
+                "struct Pixel { saturate: f32 }\n\
+                 fn saturate(x: f32) -> f32 { return Pixel(x).saturate; }\n",
+            )
+            .add_file(
+                "tone.wgsl",
+                "fn saturate(x: f32) -> f32 { return clamp(x, 0.0, 1.0); }\n",
+            );
+        let output = Preprocessor::new(resolver)
+            .set_mangle_names(true)
+            .preprocess("main.wgsl")
+            .unwrap();
+        assert_eq!(
+            output.code,
+            "struct color__Pixel { saturate: f32 }\n\
+             fn color__saturate(x: f32) -> f32 { return color__Pixel(x).saturate; }\n\
+             fn tone__saturate(x: f32) -> f32 { return clamp(x, 0.0, 1.0); }\n\
+             fn main() -> f32 { return tone__saturate(color__saturate(1.0)); }\n"
+        );
+        assert_eq!(
+            output
+                .demangle_table
+                .get("color__Pixel")
+                .map(String::as_str),
+            Some("color::Pixel")
+        );
+        assert_eq!(
+            output.demangle("error: `tone__saturate` takes 1 argument"),
+            "error: `tone::saturate` takes 1 argument"
+        );
+
+        let offset = output.code.find("color__saturate(1.0)").unwrap();
+        let origin = output.origin_at(offset).unwrap();
+        assert_eq!(origin.location.unwrap().to_string(), "main.wgsl:3:36");
+        let origin = output
+            .origin_at(output.code.find("(1.0)").unwrap())
+            .unwrap();
+        assert_eq!(origin.location.unwrap().to_string(), "main.wgsl:3:51");
+    }
+
+    #[test]
+    fn test_mangle_names_keeps_own_declarations() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file(
+                "main.wgsl",
+                "// #import tone;\n\
+                 fn saturate(x: f32) -> f32 { return x; }\n\
+                 fn main() -> f32 { return saturate(tone::saturate(1.0)); }\n",
+            )
+            .add_file(
+                "tone.wgsl",
+                "fn saturate(x: f32) -> f32 { return clamp(x, 0.0, 1.0); }\n",
+            );
+        let output = Preprocessor::new(resolver)
+            .set_mangle_names(true)
+            .preprocess("main.wgsl")
+            .unwrap();
+        assert_eq!(
+            output.code,
+            "fn tone__saturate(x: f32) -> f32 { return clamp(x, 0.0, 1.0); }\n\
+             fn saturate(x: f32) -> f32 { return x; }\n\
+             fn main() -> f32 { return saturate(tone__saturate(1.0)); }\n"
+        );
+    }
+
+    #[test]
+    fn test_mangle_names_keeps_enumerants() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file(
+                "main.wgsl",
+                "// #import clip;\n\
+                 @group(0) @binding(0) var<storage, read_write> points: array<vec4f, SIZE>;\n\
+                 @vertex @workgroup_size(SIZE)\n\
+                 fn main(@builtin(vertex_index) i: u32) -> @builtin(position) vec4f {\n\
+                 \x20   return vec4f(position, f32(vertex_index + i));\n}\n",
+            )
+            .add_file(
+                "clip.wgsl",
+                "const position = 0.0;\nconst vertex_index = 1u;\nconst storage = 2;\n\
+                 const read_write = 3;\nconst SIZE = 64;\n",
+            );
+        let output = Preprocessor::new(resolver)
+            .set_mangle_names(true)
+            .preprocess("main.wgsl")
+            .unwrap();
+        assert!(output.code.ends_with(
+            "@group(0) @binding(0) var<storage, read_write> points: array<vec4f, clip__SIZE>;\n\
+             @vertex @workgroup_size(clip__SIZE)\n\
+             fn main(@builtin(vertex_index) i: u32) -> @builtin(position) vec4f {\n\
+             \x20   return vec4f(clip__position, f32(clip__vertex_index + i));\n}\n"
+        ));
+    }
+
+    #[test]
+    fn test_selective_import_errors() {
+        let files = [
+            (
+                "main.wgsl",
+                "\n// #import lighting::{pbr_direct, pbr_indirect};\n",
+            ),
+            ("shaders/lighting.wgsl", LIGHTING),
+            ("shaders/math.wgsl", ""),
+        ];
+        let error = preprocess_modules(&files).unwrap_err();
+        assert_eq!(
+            error.kind,
+            PreprocessErrorKind::ImportedItemNotFound {
+                module: "lighting".to_owned(),
+                item: "pbr_indirect".to_owned(),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "main.wgsl:2:35: `pbr_indirect` not found in module `lighting`"
+        );
+    }
+}
+
+mod dead_code {
+    use super::*;
+
+    const SHADER: &str = "struct Light { color: vec3f }
+alias Color = vec3f;
+const SCALE = 2.0;
+const UNUSED = 1.0;
+@group(0) @binding(0) var<uniform> light: Light;
+fn scale(c: Color) -> Color { return c * SCALE; }
+fn debug() -> f32 { return UNUSED; }
+@fragment
+fn main() -> @location(0) vec4f { return vec4f(scale(light.color), 1.0); }
+";
+
+    fn preprocess_with(configure: impl FnOnce(&mut Preprocessor)) -> PreprocessOutput {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file("main.wgsl", SHADER);
+        let mut preprocessor = Preprocessor::new(resolver);
+        preprocessor.set_eliminate_dead_code(true);
+        configure(&mut preprocessor);
+        preprocessor.preprocess("main.wgsl").unwrap()
+    }
+
+    #[test]
+    fn test_unused_declarations() {
+        let output = preprocess_with(|_| {});
+        assert_eq!(
+            output.code,
+            "struct Light { color: vec3f }
+alias Color = vec3f;
+const SCALE = 2.0;
+@group(0) @binding(0) var<uniform> light: Light;
+fn scale(c: Color) -> Color { return c * SCALE; }
+@fragment
+fn main() -> @location(0) vec4f { return vec4f(scale(light.color), 1.0); }
+"
+        );
+        let origin = output.origin_at(output.code.find("fn main").unwrap());
+        assert_eq!(
+            origin.unwrap().location.unwrap().to_string(),
+            "main.wgsl:9:1"
+        );
+
+        let output = preprocess_with(|preprocessor| {
+            preprocessor.keep_symbol("debug");
+        });
+        assert!(output.code.contains("const UNUSED") && output.code.contains("fn debug"));
+    }
+
+    #[test]
+    fn test_line_preserving() {
+        let output = preprocess_with(|preprocessor| {
+            preprocessor.set_output_mode(OutputMode::LinePreserving);
+        });
+        let lines: Vec<&str> = output.code.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!((lines[3], lines[6]), ("", ""));
+        assert!(lines[8].starts_with("fn main"));
+    }
+
+    #[test]
+    fn test_entry_points() {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file(
+            "main.wgsl",
+            "fn shade() -> f32 { return 1.0; }\n\
+             fn position() -> vec4f { return vec4f(); }\n\
+             @vertex fn vs() -> @builtin(position) vec4f { return position(); }\n\
+             @fragment fn fs() -> @location(0) f32 { return shade(); }\n",
+        );
+        let output = Preprocessor::new(resolver).preprocess("main.wgsl").unwrap();
+        assert_eq!(output.entry_points(), ["vs", "fs"]);
+        assert_eq!(output.entry_point("main"), None);
+
+        let fragment = output.entry_point("fs").unwrap();
+        assert_eq!(
+            fragment.code,
+            "fn shade() -> f32 { return 1.0; }\n\
+             @fragment fn fs() -> @location(0) f32 { return shade(); }\n"
+        );
+        let origin = fragment.origin_at(fragment.code.find("fs()").unwrap());
+        assert_eq!(
+            origin.unwrap().location.unwrap().to_string(),
+            "main.wgsl:4:14"
+        );
+        let vertex = output.entry_point("vs").unwrap();
+        assert!(vertex.code.starts_with("fn position()") && !vertex.code.contains("fs"));
+    }
+
+    #[test]
+    fn test_mangled_names() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file(
+                "main.wgsl",
+                "// #import util;\n@compute @workgroup_size(1)\nfn main() { _ = util::a(); }\n",
+            )
+            .add_file(
+                "util.wgsl",
+                "fn a() -> i32 { return 1; }\nfn b() {}\nfn c() {}\n",
+            );
+        let output = Preprocessor::new(resolver)
+            .set_mangle_names(true)
+            .set_eliminate_dead_code(true)
+            .keep_symbol("util::c")
+            .preprocess("main.wgsl")
+            .unwrap();
+        assert_eq!(
+            output.code,
+            "fn util__a() -> i32 { return 1; }\n\
+             fn util__c() {}\n\
+             @compute @workgroup_size(1)\n\
+             fn main() { _ = util__a(); }\n"
+        );
+    }
+}
+
+mod permutations {
+    use super::*;
+
+    #[test]
+    fn test_permutations() {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file(
+            "main.wgsl",
+            "// #ifdef SHADOWS;\nconst SHADOWS = true;\n// #endif;\n\
+             const LIGHT_COUNT = min(LIGHTS, 8);\n",
+        );
+        let mut preprocessor = Preprocessor::new(resolver);
+        preprocessor.define("SHADOWS", "").unwrap();
+        let mut matrix = PermutationMatrix::new();
+        matrix.toggle("SHADOWS").values("LIGHTS", ["4", "8", "16"]);
+
+        let permutations = preprocessor
+            .preprocess_permutations("main.wgsl", &matrix, |permutation| {
+                permutation.choice("LIGHTS") != Some("16")
+            })
+            .unwrap();
+        let keys: Vec<(String, usize)> = permutations
+            .permutations
+            .iter()
+            .map(|(permutation, output_idx)| (permutation.key(), *output_idx))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ("SHADOWS=on,LIGHTS=4".to_owned(), 0),
+                ("SHADOWS=on,LIGHTS=8".to_owned(), 1),
+                ("SHADOWS=off,LIGHTS=4".to_owned(), 2),
+                ("SHADOWS=off,LIGHTS=8".to_owned(), 3),
+            ]
+        );
+        assert_eq!(
+            permutations.outputs[2].code,
+            "const LIGHT_COUNT = min(4, 8);\n"
+        );
+
+        // Permutations with the same code share an output.
+        let mut matrix = PermutationMatrix::new();
+        matrix.values("UNUSED", ["a", "b"]).toggle("SHADOWS");
+        let permutations = preprocessor
+            .preprocess_permutations("main.wgsl", &matrix, |_| true)
+            .unwrap();
+        let output_indices: Vec<usize> = permutations.permutations.iter().map(|p| p.1).collect();
+        assert_eq!(output_indices, [0, 1, 0, 1]);
+        assert_eq!(permutations.outputs.len(), 2);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file("main.wgsl", "// #if N > 1;\n// #endif;\n");
+        let mut matrix = PermutationMatrix::new();
+        matrix.values("N", ["2", "(", "3"]);
+        let error = Preprocessor::new(resolver)
+            .preprocess_permutations("main.wgsl", &matrix, |_| true)
+            .unwrap_err();
+        assert_eq!(error.permutation.key(), "N=(");
+        assert!(error.to_string().starts_with("in permutation N=(: "));
+    }
+}
+
+mod used_defines {
+    use super::*;
+
+    #[test]
+    fn test_used_defines() {
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file(
+            "main.wgsl",
+            "// #ifdef SHADOWS;\n// #if QUALITY > 1 || defined(HDR);\nconst A = FILTER;\n// #endif;\n\
+             // #else;\nconst B = FALLBACK;\n// #endif;\n",
+        );
+        let mut preprocessor = Preprocessor::new(resolver);
+        preprocessor.define("SHADOWS", "").unwrap();
+        preprocessor.define("QUALITY", "2").unwrap();
+        preprocessor.define("UNUSED", "1").unwrap();
+        let output = preprocessor.preprocess("main.wgsl").unwrap();
+        assert_eq!(output.code, "const A = FILTER;\n");
+
+        let defines = ["SHADOWS", "QUALITY", "HDR", "FILTER", "FALLBACK", "UNUSED"];
+        let used: Vec<&str> = defines
+            .into_iter()
+            .filter(|name| output.used_defines.contains(*name))
+            .collect();
+        // `FALLBACK` is in a disabled branch.
+        assert_eq!(used, ["SHADOWS", "QUALITY", "HDR", "FILTER"]);
+    }
+}
+
+mod cache {
+    use super::*;
+    use core::cell::RefCell;
+
+    // Lets the tests change files after the resolver was given to the preprocessor.
+    struct SharedResolver<'a>(&'a RefCell<MemoryResolver>);
+
+    impl IncludeResolver for SharedResolver<'_> {
+        fn resolve(
+            &self,
+            path: &str,
+            including_path: Option<&str>,
+        ) -> Result<ResolvedFile, ResolveError> {
+            self.0.borrow().resolve(path, including_path)
+        }
+    }
+
+    #[test]
+    fn test_recomputes_affected_outputs() {
+        let files = RefCell::new(MemoryResolver::new());
+        files
+            .borrow_mut()
+            .add_file("common.wgsl", "const PI = 3.14159;\n")
+            .add_file("other.wgsl", "const E = 2.71828;\n")
+            .add_file(
+                "a.wgsl",
+                "// #include \"common.wgsl\";\nconst A = SCALE * PI;\n",
+            )
+            .add_file(
+                "b.wgsl",
+                "// #include \"other.wgsl\";\nconst B = SCALE * E;\n",
+            );
+        let mut preprocessor = Preprocessor::new(SharedResolver(&files));
+        preprocessor.define("SCALE", "2.0").unwrap();
+        let mut cache = PreprocessCache::new();
+
+        let a = preprocessor
+            .preprocess_cached("a.wgsl", &mut cache)
+            .unwrap();
+        let b = preprocessor
+            .preprocess_cached("b.wgsl", &mut cache)
+            .unwrap();
+        assert_eq!(*a, preprocessor.preprocess("a.wgsl").unwrap());
+        assert_eq!(cache.len(), 2);
+        let a_again = preprocessor
+            .preprocess_cached("a.wgsl", &mut cache)
+            .unwrap();
+        assert!(Arc::ptr_eq(&a, &a_again));
+
+        files
+            .borrow_mut()
+            .add_file("common.wgsl", "const PI = 3.0;\n");
+        let new_a = preprocessor
+            .preprocess_cached("a.wgsl", &mut cache)
+            .unwrap();
+        let new_b = preprocessor
+            .preprocess_cached("b.wgsl", &mut cache)
+            .unwrap();
+        assert_eq!(new_a.code, "const PI = 3.0;\nconst A = 2.0 * PI;\n");
+        assert!(Arc::ptr_eq(&b, &new_b));
+
+        // Other defines are another output.
+        preprocessor.define("SCALE", "4.0").unwrap();
+        let scaled = preprocessor
+            .preprocess_cached("a.wgsl", &mut cache)
+            .unwrap();
+        assert_eq!(scaled.code, "const PI = 3.0;\nconst A = 4.0 * PI;\n");
+        assert_eq!(cache.len(), 3);
+
+        assert_eq!(cache.invalidate("common.wgsl"), ["a.wgsl"]);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_errors_are_not_cached() {
+        let files = RefCell::new(MemoryResolver::new());
+        files
+            .borrow_mut()
+            .add_file("main.wgsl", "// #include \"missing.wgsl\";\n");
+        let preprocessor = Preprocessor::new(SharedResolver(&files));
+        let mut cache = PreprocessCache::new();
+        assert!(preprocessor
+            .preprocess_cached("main.wgsl", &mut cache)
+            .is_err());
+        assert!(cache.is_empty());
+
+        files.borrow_mut().add_file(
+            "missing.wgsl",
+            "// #ifdef X;\n// #define;\n// #endif;\nconst C = 1;\n",
+        );
+        let output = preprocessor
+            .preprocess_cached("main.wgsl", &mut cache)
+            .unwrap();
+        assert_eq!(output.code, "const C = 1;\n");
+    }
+
+    #[test]
+    fn test_unchanged_files_are_not_tokenized_again() {
+        let source = "// #ifdef FAST;\nconst C = 1;\n// #endif;\n";
+        let mut resolver = MemoryResolver::new();
+        resolver.add_file("main.wgsl", source);
+        let mut preprocessor = Preprocessor::new(resolver);
+        let mut cache = PreprocessCache::new();
+        let slow = preprocessor
+            .preprocess_cached("main.wgsl", &mut cache)
+            .unwrap();
+        let syntax_settings = preprocessor.syntax_settings;
+        let scanned = cache.directives.scan(&Arc::from(source), syntax_settings);
+
+        preprocessor.define("FAST", "").unwrap();
+        let fast = preprocessor
+            .preprocess_cached("main.wgsl", &mut cache)
+            .unwrap();
+        assert_eq!(slow.code, "");
+        assert_eq!(fast.code, "const C = 1;\n");
+        let rescanned = cache.directives.scan(&Arc::from(source), syntax_settings);
+        assert!(Arc::ptr_eq(&scanned, &rescanned));
+    }
+}
+
+mod depfiles {
+    use super::*;
+
+    #[test]
+    fn test_depfile() {
+        let mut resolver = MemoryResolver::new();
+        resolver
+            .add_file(
+                "main.wgsl",
+                "// #include \"lib/a b.wgsl\";\n// #import util;\n",
+            )
+            .add_file("lib/a b.wgsl", "// #include \"c.wgsl\";\n")
+            .add_file("lib/c.wgsl", "const C = 1;\n")
+            .add_file("util.wgsl", "const U = 2;\n");
+        let output = Preprocessor::new(resolver).preprocess("main.wgsl").unwrap();
+        assert_eq!(
+            output.depfile("out/main.wgsl"),
+            "out/main.wgsl: \\\n  main.wgsl \\\n  lib/a\\ b.wgsl \\\n  lib/c.wgsl \\\n  util.wgsl\n"
+        );
+    }
+}
@@ -1,13 +1,35 @@
+//! Shared text utilities: located strings and source locations.
+
 use core::ops::Range;
 
+/// A string slice that remembers where in the original source it starts.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct LocatedStr<'a> {
+    /// The text itself.
     pub inner_str: &'a str,
+    /// The location of the first char of `inner_str` in the original source.
     pub start_location: TextLocation,
 }
 
 impl<'a> LocatedStr<'a> {
-    // (absolute location, relative byte location, which needle)
+    /// Returns the location just past the last char of the string.
+    pub fn end_location(&self) -> TextLocation {
+        let mut output = self.start_location;
+        for c in self.inner_str.chars() {
+            if c == '\n' {
+                output.line_num += 1;
+                output.col_num = 0;
+            } else {
+                output.col_num += 1;
+            }
+        }
+        output.byte_num += self.inner_str.len() as u64;
+        output
+    }
+
+    /// Searches for the first match of any needle candidate.
+    ///
+    /// Returns (absolute location, relative byte location, which needle).
     pub fn find_any_substr(
         &self,
         needle_candidates: &[&str],
@@ -38,6 +60,9 @@ impl<'a> LocatedStr<'a> {
         None
     }
 
+    /// Finds the first char for which `needle` returns true.
+    ///
+    /// Returns (absolute location, relative byte location).
     pub fn find_with_fn(
         &self,
         mut needle: impl FnMut(char) -> bool,
@@ -59,6 +84,9 @@ impl<'a> LocatedStr<'a> {
         None
     }
 
+    /// Slices the string by byte range, keeping track of the new start location.
+    ///
+    /// Panics under the same conditions as indexing a `str`.
     pub fn get_unchecked(self, index: Range<usize>) -> LocatedStr<'a> {
         let before_split = &self.inner_str[..index.start];
         let split = &self.inner_str[index.clone()];
@@ -89,6 +117,7 @@ impl<'a> LocatedStr<'a> {
         }
     }
 
+    /// Creates a `LocatedStr` that starts at the beginning of the source.
     pub fn new(inner: &'a str) -> LocatedStr<'a> {
         LocatedStr {
             inner_str: inner,
@@ -96,6 +125,7 @@ impl<'a> LocatedStr<'a> {
         }
     }
 
+    /// Creates a `LocatedStr` that starts at the given location.
     pub fn new_with_loc(
         inner_str: &'a str,
         line_num: u64,
@@ -112,6 +142,12 @@ impl<'a> LocatedStr<'a> {
         }
     }
 
+    /// Returns the range of source text covered by the string.
+    pub fn range(&self) -> TextRange {
+        TextRange::new(self.start_location, self.end_location())
+    }
+
+    /// Removes leading whitespace, keeping track of the new start location.
     pub fn trim_start(mut self) -> LocatedStr<'a> {
         for (current_char_idx, current_char) in self.inner_str.char_indices() {
             if current_char.is_whitespace() {
@@ -149,14 +185,21 @@ impl<'a> From<(&'a str, TextLocation)> for LocatedStr<'a> {
     }
 }
 
+/// A location in source text.
+///
+/// All fields are zero-based. Columns count chars, not bytes.
 #[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct TextLocation {
+    /// The line number.
     pub line_num: u64,
+    /// The column (in chars) within the line.
     pub col_num: u64,
+    /// The byte offset from the start of the source.
     pub byte_num: u64,
 }
 
 impl TextLocation {
+    /// Creates a new `TextLocation`.
     pub fn new(line_num: u64, col_num: u64, byte_num: u64) -> Self {
         TextLocation {
             line_num,
@@ -182,6 +225,98 @@ impl From<&(u64, u64, u64)> for TextLocation {
     }
 }
 
+/// A half-open span of source text between two [`TextLocation`]s.
+///
+/// `start` is the location of the first char in the span and `end` is the location just past the
+/// last one. Comparisons between ranges are done by byte offset, so both ranges are expected to
+/// come from the same source.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TextRange {
+    /// The location of the first char in the range.
+    pub start: TextLocation,
+    /// The location just past the last char in the range.
+    pub end: TextLocation,
+}
+
+impl TextRange {
+    /// Creates a new `TextRange`.
+    pub fn new(start: TextLocation, end: TextLocation) -> Self {
+        TextRange { start, end }
+    }
+
+    /// Creates an empty range at `location`.
+    pub fn empty_at(location: TextLocation) -> Self {
+        TextRange {
+            start: location,
+            end: location,
+        }
+    }
+
+    /// Returns the byte range of the span within its source.
+    pub fn byte_range(&self) -> Range<usize> {
+        self.start.byte_num as usize..self.end.byte_num as usize
+    }
+
+    /// Returns true if `location` is inside the range.
+    pub fn contains(&self, location: TextLocation) -> bool {
+        self.start.byte_num <= location.byte_num && location.byte_num < self.end.byte_num
+    }
+
+    /// Returns true if all of `other` is inside the range.
+    pub fn contains_range(&self, other: TextRange) -> bool {
+        self.start.byte_num <= other.start.byte_num && other.end.byte_num <= self.end.byte_num
+    }
+
+    /// Returns the overlapping part of the two ranges, if there is any.
+    ///
+    /// Ranges that merely touch do not intersect.
+    pub fn intersection(self, other: TextRange) -> Option<TextRange> {
+        let start = if self.start.byte_num >= other.start.byte_num {
+            self.start
+        } else {
+            other.start
+        };
+        let end = if self.end.byte_num <= other.end.byte_num {
+            self.end
+        } else {
+            other.end
+        };
+
+        if start.byte_num < end.byte_num {
+            Some(TextRange { start, end })
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the range covers no text.
+    pub fn is_empty(&self) -> bool {
+        self.start.byte_num >= self.end.byte_num
+    }
+
+    /// Returns the smallest range that covers both ranges and everything between them.
+    pub fn merge(self, other: TextRange) -> TextRange {
+        TextRange {
+            start: if self.start.byte_num <= other.start.byte_num {
+                self.start
+            } else {
+                other.start
+            },
+            end: if self.end.byte_num >= other.end.byte_num {
+                self.end
+            } else {
+                other.end
+            },
+        }
+    }
+}
+
+impl From<(TextLocation, TextLocation)> for TextRange {
+    fn from((start, end): (TextLocation, TextLocation)) -> Self {
+        TextRange { start, end }
+    }
+}
+
 /// Searches `haystack` for the first match of any needle candidate in `needle_candidates`
 ///
 /// Returns (match location index, matched needle candidate index in candidate list).
@@ -201,7 +336,7 @@ pub(crate) fn find_any_substring(
         }
     }
 
-    return None;
+    None
 }
 
 #[cfg(test)]
@@ -211,6 +346,18 @@ mod tests {
     mod located_str {
         use super::*;
 
+        #[test]
+        fn test_end_location_and_range() {
+            let ls = LocatedStr::new_with_loc("ab\ncd€", 2, 4, 10);
+            assert_eq!(ls.end_location(), TextLocation::new(3, 3, 18));
+            assert_eq!(
+                ls.range(),
+                TextRange::new(TextLocation::new(2, 4, 10), TextLocation::new(3, 3, 18))
+            );
+            let empty = LocatedStr::new_with_loc("", 1, 1, 1);
+            assert!(empty.range().is_empty());
+        }
+
         #[test]
         fn test_find_any_substr() {
             let test_cases: &[(_, _, &[_], _)] =
@@ -227,6 +374,7 @@ mod tests {
             }
         }
 
+        #[allow(clippy::type_complexity)]
         #[test]
         fn test_find_with_fn() {
             let test_cases: &[(&str, &dyn Fn(char) -> bool, (u64, u64, u64))] = &[
@@ -318,6 +466,49 @@ mod tests {
         }
     }
 
+    mod text_range {
+        use super::*;
+
+        fn r(start: u64, end: u64) -> TextRange {
+            TextRange::new(
+                TextLocation::new(0, start, start),
+                TextLocation::new(0, end, end),
+            )
+        }
+
+        #[test]
+        fn test_contains() {
+            let range = r(2, 5);
+            assert!(range.contains(TextLocation::new(0, 2, 2)));
+            assert!(range.contains(TextLocation::new(0, 4, 4)));
+            assert!(!range.contains(TextLocation::new(0, 5, 5)));
+            assert!(!range.contains(TextLocation::new(0, 1, 1)));
+            assert!(range.contains_range(r(3, 5)));
+            assert!(!range.contains_range(r(3, 6)));
+        }
+
+        #[test]
+        fn test_intersection() {
+            let test_cases = [
+                ((r(0, 5), r(3, 8)), Some(r(3, 5))),
+                ((r(3, 8), r(0, 5)), Some(r(3, 5))),
+                ((r(0, 8), r(2, 4)), Some(r(2, 4))),
+                ((r(0, 3), r(3, 5)), None),
+                ((r(0, 2), r(4, 5)), None),
+            ];
+            for ((a, b), expected) in test_cases {
+                assert_eq!(a.intersection(b), expected);
+            }
+        }
+
+        #[test]
+        fn test_merge() {
+            assert_eq!(r(0, 2).merge(r(4, 5)), r(0, 5));
+            assert_eq!(r(4, 5).merge(r(0, 2)), r(0, 5));
+            assert_eq!(r(1, 9).merge(r(2, 3)), r(1, 9));
+        }
+    }
+
     #[test]
     fn test_find_any_substring() {
         let test_cases: &[((_, &[_]), _)] = &[
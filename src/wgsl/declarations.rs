@@ -0,0 +1,256 @@
+use super::{Token, TokenIter, TokenKind};
+use crate::utils::LocatedStr;
+use alloc::vec::Vec;
+
+/// The kinds of [`Declaration`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DeclarationKind {
+    /// `fn name(...) { ... }`
+    Function,
+    /// `struct Name { ... }`
+    Struct,
+    /// `const name = ...;`
+    Const,
+    /// `override name ...;`
+    Override,
+    /// `var<...> name ...;`
+    Var,
+    /// `alias Name = ...;`
+    Alias,
+    /// `const_assert ...;`
+    ConstAssert,
+    /// `enable ...;`, `requires ...;` or `diagnostic(...);`
+    GlobalDirective,
+    /// Anything else, such as a macro invocation that was left unexpanded.
+    Other,
+}
+
+/// A module-scope declaration of WGSL code, found by [`scan_declarations`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Declaration<'a> {
+    /// What is declared.
+    pub kind: DeclarationKind,
+    /// The declared name. `None` for declarations without one, such as `const_assert`.
+    pub name: Option<LocatedStr<'a>>,
+    /// The names of the declaration's attributes, like `compute` in `@compute`.
+    pub attributes: Vec<LocatedStr<'a>>,
+    /// The declaration's text, from its first attribute or keyword to its closing `}` or `;`.
+    pub text: LocatedStr<'a>,
+    /// The identifiers used by the declaration, except its name and the names after `.`.
+    ///
+    /// These include keywords, types and local names, so only the ones that are declared at module
+    /// scope are dependencies.
+    pub references: Vec<LocatedStr<'a>>,
+}
+
+impl<'a> Declaration<'a> {
+    /// Returns whether the declaration is a `@vertex`, `@fragment` or `@compute` function.
+    pub fn is_entry_point(&self) -> bool {
+        self.kind == DeclarationKind::Function
+            && self
+                .attributes
+                .iter()
+                .any(|attribute| matches!(attribute.inner_str, "vertex" | "fragment" | "compute"))
+    }
+}
+
+/// Splits WGSL code into its module-scope declarations.
+///
+/// Only tokens and brackets are looked at, so this also works on code that would not validate.
+/// A declaration ends at the first `;` outside of brackets, or at the `}` that closes its outermost
+/// braces. Stray `;`s and closing brackets between declarations are skipped.
+pub fn scan_declarations(source: LocatedStr<'_>) -> Vec<Declaration<'_>> {
+    let tokens: Vec<Token<'_>> = TokenIter::new(source)
+        .filter(|token| !token.is_trivia())
+        .collect();
+    let mut declarations = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i].kind == TokenKind::Symbol
+            && matches!(tokens[i].text.inner_str, ";" | ")" | "]" | "}")
+        {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let end = declaration_end(&tokens, start);
+
+        // Skip attributes, like `@compute` and `@workgroup_size(64)`.
+        let mut keyword_idx = start;
+        let mut attributes = Vec::new();
+        while keyword_idx < end && is_symbol(&tokens[keyword_idx], "@") {
+            attributes.extend(tokens.get(keyword_idx + 1).map(|token| token.text));
+            keyword_idx += 2;
+            if keyword_idx < end && is_symbol(&tokens[keyword_idx], "(") {
+                keyword_idx = closing_bracket(&tokens, keyword_idx).map_or(end, |close| close + 1);
+            }
+        }
+        let kind = match tokens.get(keyword_idx).map(|t| t.text.inner_str) {
+            Some("fn") => DeclarationKind::Function,
+            Some("struct") => DeclarationKind::Struct,
+            Some("const") => DeclarationKind::Const,
+            Some("override") => DeclarationKind::Override,
+            Some("var") => DeclarationKind::Var,
+            Some("alias") => DeclarationKind::Alias,
+            Some("const_assert") => DeclarationKind::ConstAssert,
+            Some("enable" | "requires" | "diagnostic") => DeclarationKind::GlobalDirective,
+            _ => DeclarationKind::Other,
+        };
+        let maybe_name_idx = match kind {
+            DeclarationKind::ConstAssert
+            | DeclarationKind::GlobalDirective
+            | DeclarationKind::Other => None,
+            // `var<storage, read> name`
+            DeclarationKind::Var
+                if tokens
+                    .get(keyword_idx + 1)
+                    .is_some_and(|t| is_symbol(t, "<")) =>
+            {
+                let mut depth = 0usize;
+                (keyword_idx + 1..end)
+                    .find(|&idx| {
+                        if is_symbol(&tokens[idx], "<") {
+                            depth += 1;
+                        } else if is_symbol(&tokens[idx], ">") {
+                            depth -= 1;
+                        }
+                        depth == 0
+                    })
+                    .map(|close| close + 1)
+            }
+            _ => Some(keyword_idx + 1),
+        }
+        .filter(|&idx| idx <= end && tokens[idx].kind == TokenKind::Ident);
+
+        let references = (start..=end)
+            .filter(|&idx| tokens[idx].kind == TokenKind::Ident && Some(idx) != maybe_name_idx)
+            .filter(|&idx| idx == 0 || !is_symbol(&tokens[idx - 1], "."))
+            .map(|idx| tokens[idx].text)
+            .collect();
+        let byte_start =
+            tokens[start].text.start_location.byte_num - source.start_location.byte_num;
+        let byte_end = tokens[end].text.end_location().byte_num - source.start_location.byte_num;
+        declarations.push(Declaration {
+            kind,
+            name: maybe_name_idx.map(|idx| tokens[idx].text),
+            attributes,
+            text: source.get_unchecked(byte_start as usize..byte_end as usize),
+            references,
+        });
+        i = end + 1;
+    }
+    declarations
+}
+
+fn is_symbol(token: &Token<'_>, symbol: &str) -> bool {
+    token.kind == TokenKind::Symbol && token.text.inner_str == symbol
+}
+
+// Returns the index of the last token of the declaration starting at `start`.
+fn declaration_end(tokens: &[Token<'_>], start: usize) -> usize {
+    let mut depth = 0usize;
+    for (idx, token) in tokens.iter().enumerate().skip(start) {
+        if token.kind != TokenKind::Symbol {
+            continue;
+        }
+        match token.text.inner_str {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" if depth > 0 => {
+                depth -= 1;
+                if depth == 0 && token.text.inner_str == "}" {
+                    return idx;
+                }
+            }
+            ";" if depth == 0 => return idx,
+            _ => {}
+        }
+    }
+    tokens.len() - 1
+}
+
+// Returns the index of the bracket that closes the one at `open`.
+fn closing_bracket(tokens: &[Token<'_>], open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for (idx, token) in tokens.iter().enumerate().skip(open) {
+        if token.kind != TokenKind::Symbol {
+            continue;
+        }
+        match token.text.inner_str {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_declarations() {
+        let source = "enable f16;
+struct Light { color: vec3f, range: f32 };
+const PI = 3.14159;
+@group(0) @binding(0) var<storage, read> lights: array<Light>;
+// A helper.
+fn attenuation(light: Light, d: f32) -> f32 {
+    if d > light.range { return 0.0; }
+    return PI / (d * d);
+}
+const_assert PI > 3.0;
+@compute @workgroup_size(64)
+fn main() { _ = attenuation(lights[0], 1.0); }
+";
+        let declarations = scan_declarations(LocatedStr::new(source));
+        let summary: Vec<_> = declarations
+            .iter()
+            .map(|d| (d.kind, d.name.map(|name| name.inner_str)))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (DeclarationKind::GlobalDirective, None),
+                (DeclarationKind::Struct, Some("Light")),
+                (DeclarationKind::Const, Some("PI")),
+                (DeclarationKind::Var, Some("lights")),
+                (DeclarationKind::Function, Some("attenuation")),
+                (DeclarationKind::ConstAssert, None),
+                (DeclarationKind::Function, Some("main")),
+            ]
+        );
+
+        let attenuation = &declarations[4];
+        assert!(attenuation.text.inner_str.starts_with("fn attenuation("));
+        assert!(attenuation
+            .text
+            .inner_str
+            .ends_with("return PI / (d * d);\n}"));
+        let references: Vec<_> = attenuation.references.iter().map(|r| r.inner_str).collect();
+        assert!(references.contains(&"Light") && references.contains(&"PI"));
+        // `range` is a member, not a reference to a declaration.
+        assert!(!references.contains(&"range") && !references.contains(&"attenuation"));
+        assert_eq!(declarations[6].text.start_location.line_num, 10);
+        let attributes: Vec<_> = declarations[6]
+            .attributes
+            .iter()
+            .map(|a| a.inner_str)
+            .collect();
+        assert_eq!(attributes, ["compute", "workgroup_size"]);
+        assert!(declarations[6].is_entry_point() && !declarations[4].is_entry_point());
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        let declarations = scan_declarations(LocatedStr::new("}; const A = 1;\nfn f() {"));
+        assert_eq!(declarations.len(), 2);
+        assert_eq!(declarations[0].text.inner_str, "const A = 1;");
+        assert_eq!(declarations[1].text.inner_str, "fn f() {");
+    }
+}
@@ -0,0 +1,163 @@
+use crate::parsing::{ident::ident_len, literals::number_len};
+use crate::utils::LocatedStr;
+
+/// The kinds of [`Token`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TokenKind {
+    /// An identifier or keyword.
+    Ident,
+    /// A numeric literal, including any suffix. Not validated.
+    Number,
+    /// A single char that is not part of any other token.
+    Symbol,
+    /// A run of whitespace.
+    Whitespace,
+    /// A line or (possibly nested) block comment.
+    Comment,
+}
+
+/// A token of WGSL code.
+///
+/// Whitespace and comments are tokens too, so concatenating the tokens of a source gives back the
+/// source.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Token<'a> {
+    /// What kind of token this is.
+    pub kind: TokenKind,
+    /// The token's text.
+    pub text: LocatedStr<'a>,
+}
+
+impl<'a> Token<'a> {
+    /// Returns true for whitespace and comments.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.kind, TokenKind::Whitespace | TokenKind::Comment)
+    }
+}
+
+/// Iterates over the tokens of WGSL code.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TokenIter<'a> {
+    source_remaining: LocatedStr<'a>,
+}
+
+impl<'a> TokenIter<'a> {
+    /// Creates an iterator over the tokens of `source`.
+    pub fn new(source: LocatedStr<'a>) -> Self {
+        TokenIter {
+            source_remaining: source,
+        }
+    }
+}
+
+impl<'a> Iterator for TokenIter<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let source = self.source_remaining.inner_str;
+        let first_char = source.chars().next()?;
+
+        let (kind, len) = if first_char.is_whitespace() {
+            (
+                TokenKind::Whitespace,
+                source
+                    .find(|c: char| !c.is_whitespace())
+                    .unwrap_or(source.len()),
+            )
+        } else if source.starts_with("//") {
+            (
+                TokenKind::Comment,
+                source.find('\n').unwrap_or(source.len()),
+            )
+        } else if source.starts_with("/*") {
+            (TokenKind::Comment, block_comment_len(source))
+        } else if first_char.is_ascii_digit()
+            || (first_char == '.' && source[1..].starts_with(|c: char| c.is_ascii_digit()))
+        {
+            (TokenKind::Number, number_len(source))
+        } else {
+            match ident_len(source) {
+                0 => (TokenKind::Symbol, first_char.len_utf8()),
+                len => (TokenKind::Ident, len),
+            }
+        };
+
+        let output = Token {
+            kind,
+            text: self.source_remaining.get_unchecked(0..len),
+        };
+        self.source_remaining = self.source_remaining.get_unchecked(len..source.len());
+        Some(output)
+    }
+}
+
+// Block comments nest in WGSL. An unterminated comment runs to the end of the source.
+fn block_comment_len(source: &str) -> usize {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < source.len() {
+        if source[i..].starts_with("/*") {
+            depth += 1;
+            i += 2;
+        } else if source[i..].starts_with("*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += source[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    source.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds_and_text(source: &str) -> Vec<(TokenKind, &str)> {
+        TokenIter::new(LocatedStr::new(source))
+            .map(|token| (token.kind, token.text.inner_str))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "@vertex\nfn main(@builtin(vertex_index) i: u32) -> vec4f {\n\
+                      \t/* nested /* comment */ here */ return vec4f(1.5e-3, 0x1Fu, .5f, 1);\n\
+                      } // trailing\n";
+        let joined: String = TokenIter::new(LocatedStr::new(source))
+            .map(|token| token.text.inner_str)
+            .collect();
+        assert_eq!(joined, source);
+    }
+
+    #[test]
+    fn test_token_kinds() {
+        use TokenKind::*;
+
+        assert_eq!(
+            kinds_and_text("let _x=a_b+1.5e-3;// c\n_"),
+            [
+                (Ident, "let"),
+                (Whitespace, " "),
+                (Ident, "_x"),
+                (Symbol, "="),
+                (Ident, "a_b"),
+                (Symbol, "+"),
+                (Number, "1.5e-3"),
+                (Symbol, ";"),
+                (Comment, "// c"),
+                (Whitespace, "\n"),
+                (Symbol, "_"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locations() {
+        let tokens: Vec<_> = TokenIter::new(LocatedStr::new("a\n  b")).collect();
+        assert_eq!(tokens[2].text, LocatedStr::new_with_loc("b", 1, 2, 4));
+    }
+}
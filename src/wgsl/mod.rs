@@ -0,0 +1,9 @@
+//! Tools for working with WGSL code outside of directives.
+
+#[cfg(feature = "alloc")]
+mod declarations;
+mod lexer;
+
+#[cfg(feature = "alloc")]
+pub use declarations::{scan_declarations, Declaration, DeclarationKind};
+pub use lexer::{Token, TokenIter, TokenKind};